@@ -0,0 +1,94 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use rust_simd::{dotp::*, helpers::*};
+
+// Elementwise products over arrays that fit in L2 and that spill to main memory
+const NBLOCKS: [usize; 2] = [64, 16384];
+
+fn dotp_f32(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dotp_f32");
+
+    let mut kernels: Vec<(&str, DotpFn<f32>)> = vec![
+        ("naive", dotp_naive_f32),
+        ("portable", dotp_simd_f32_portable),
+        ("portable_aligned", dotp_simd_f32_portable_aligned),
+    ];
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    {
+        kernels.push(("avx2", x86::dotp_simd_f32));
+        kernels.push(("avx2_aligned", x86::dotp_simd_f32_aligned));
+        kernels.push(("avx2_par", x86::dotp_simd_f32_par));
+    }
+
+    for nblocks in NBLOCKS.iter() {
+        let (x, y, mut z) = data_f32(*nblocks);
+        group.throughput(Throughput::Bytes((3 * 4 * x.len()) as u64));
+
+        for (name, kernel) in kernels.iter() {
+            group.bench_with_input(BenchmarkId::new(*name, nblocks), nblocks, |b, _| {
+                b.iter(|| kernel(&x, &y, &mut z))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn dotp_f64(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dotp_f64");
+
+    let mut kernels: Vec<(&str, DotpFn<f64>)> = vec![
+        ("portable", dotp_simd_f64_portable),
+        ("portable_aligned", dotp_simd_f64_portable_aligned),
+    ];
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    {
+        kernels.push(("naive", x86::dotp_naive_f64));
+        kernels.push(("avx2", x86::dotp_simd_f64));
+        kernels.push(("avx2_aligned", x86::dotp_simd_f64_aligned));
+        kernels.push(("avx2_par", x86::dotp_simd_f64_par));
+    }
+
+    for nblocks in NBLOCKS.iter() {
+        let (x, y, mut z) = data_f64(*nblocks);
+        group.throughput(Throughput::Bytes((3 * 8 * x.len()) as u64));
+
+        for (name, kernel) in kernels.iter() {
+            group.bench_with_input(BenchmarkId::new(*name, nblocks), nblocks, |b, _| {
+                b.iter(|| kernel(&x, &y, &mut z))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+// Sweep the lane width of the generic portable SIMD kernel
+fn fma_lanes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fma_lanes");
+
+    let nblocks = NBLOCKS[0];
+    let (x32, y32, mut z32) = data_f32(nblocks);
+    let (x64, y64, mut z64) = data_f64(nblocks);
+
+    for lanes in [2, 4, 8, 16].iter() {
+        let kernel = fma_simd_lanes::<f32>(*lanes, false).unwrap();
+        group.bench_with_input(BenchmarkId::new("f32", lanes), lanes, |b, _| {
+            b.iter(|| kernel(&x32, &y32, &mut z32))
+        });
+
+        let kernel = fma_simd_lanes::<f64>(*lanes, false).unwrap();
+        group.bench_with_input(BenchmarkId::new("f64", lanes), lanes, |b, _| {
+            b.iter(|| kernel(&x64, &y64, &mut z64))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().noise_threshold(0.05);
+    targets = dotp_f32, dotp_f64, fma_lanes
+}
+criterion_main!(benches);
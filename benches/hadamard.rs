@@ -0,0 +1,48 @@
+use std::sync::RwLock;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use rust_simd::{hadamard::*, helpers::*, kernel::Laplace};
+
+fn hadamard(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hadamard");
+
+    let mut kernels: Vec<(&str, HadamardFn)> = vec![("naive", hadamard_product_naive)];
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    {
+        kernels.push(("avx2", x86::hadamard_product_simd));
+        kernels.push(("avx2_aligned", |order, sibling_set, kernel_data| {
+            x86::hadamard_product_simd_aligned(order, sibling_set, kernel_data).unwrap()
+        }));
+        kernels.push(("avx2_stream", x86::hadamard_product_simd_stream));
+    }
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    {
+        kernels.push(("neon", aarch64::hadamard_product_simd_neon));
+        kernels.push(("neon_stream", aarch64::hadamard_product_simd_neon_stream));
+    }
+
+    for expansion_order in [5, 7, 9].iter() {
+        let sibling_set = sibling_set_data(*expansion_order, DEFAULT_SEED);
+        let kernel_data = RwLock::new(m2l_kernel_data(&Laplace, *expansion_order, 1.).remove(0));
+
+        for (name, kernel) in kernels.iter() {
+            group.bench_with_input(
+                BenchmarkId::new(*name, expansion_order),
+                expansion_order,
+                |b, &expansion_order| {
+                    b.iter(|| kernel(expansion_order, &sibling_set, &kernel_data))
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().noise_threshold(0.05);
+    targets = hadamard
+}
+criterion_main!(benches);
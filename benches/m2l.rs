@@ -0,0 +1,87 @@
+use std::sync::RwLock;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use rust_simd::{
+    aligned::AllocPolicy, hadamard::hadamard_product_naive, helpers::*, kernel::Laplace, m2l::*,
+    prefetch::PrefetchPolicy,
+};
+
+const EXPANSION_ORDER: usize = 5;
+
+// A complete octree, deep enough for a full block of 4096 keys in the x86 parent level driver
+const DEPTH: u64 = 4;
+
+fn m2l(c: &mut Criterion) {
+    let mut group = c.benchmark_group("m2l");
+    group.sample_size(10);
+
+    let leaves = full_octree_keys(DEPTH);
+    let domain = unit_domain();
+    let alloc = AllocPolicy::default();
+    let kernel_data =
+        RwLock::new(m2l_kernel_data(&Laplace, EXPANSION_ORDER, 1. / (1 << DEPTH) as f64).remove(0));
+    let kernel_data = &kernel_data;
+
+    group.bench_function("naive_par", |b| {
+        b.iter(|| m2l_naive_par(EXPANSION_ORDER, &leaves, alloc))
+    });
+
+    group.bench_function("parent_naive", |b| {
+        b.iter(|| m2l_parent_par_naive(EXPANSION_ORDER, &leaves, kernel_data, alloc))
+    });
+
+    group.bench_function("periodic", |b| {
+        b.iter(|| m2l_parent_par_periodic(EXPANSION_ORDER, &leaves, &domain, kernel_data, alloc))
+    });
+
+    group.bench_function("pipelined", |b| {
+        b.iter(|| {
+            m2l_parent_par_pipelined(
+                EXPANSION_ORDER,
+                &leaves,
+                kernel_data,
+                alloc,
+                hadamard_product_naive,
+                512,
+            )
+        })
+    });
+
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    group.bench_function("parent_avx2", |b| {
+        b.iter(|| {
+            x86::m2l_parent_par_simd(
+                EXPANSION_ORDER,
+                &leaves,
+                kernel_data,
+                alloc,
+                PrefetchPolicy::default(),
+                x86::DEFAULT_BLOCK_SIZE,
+            )
+        })
+    });
+
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    group.bench_function("parent_neon", |b| {
+        b.iter(|| {
+            aarch64::m2l_parent_par_simd(
+                EXPANSION_ORDER,
+                &leaves,
+                kernel_data,
+                alloc,
+                PrefetchPolicy::default(),
+                aarch64::DEFAULT_BLOCK_SIZE,
+            )
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().noise_threshold(0.05);
+    targets = m2l
+}
+criterion_main!(benches);
@@ -0,0 +1,421 @@
+//! Fixed length heap buffers aligned to `ALIGN` bytes, 64 by default so that buffers start on a
+//! cache line and every SIMD vector in them can be loaded with an aligned load.
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::Arc,
+};
+
+use rayon::prelude::*;
+
+pub const CACHE_LINE: usize = 64;
+
+// Size of a transparent huge page on x86_64 and on aarch64 with 4 KiB base pages
+pub const HUGE_PAGE: usize = 2 << 20;
+
+// Whether `ptr` is a multiple of `align` bytes
+pub fn is_aligned<T>(ptr: *const T, align: usize) -> bool {
+    ptr as usize % align == 0
+}
+
+// Restricted to `Copy` elements, so that dropping the buffer only has to free it.
+pub struct AlignedVec<T: Copy, const ALIGN: usize = CACHE_LINE> {
+    ptr: NonNull<T>,
+    len: usize,
+    // Set if the buffer is part of a block shared with other buffers, which frees it instead
+    block: Option<Arc<Block>>,
+}
+
+unsafe impl<T: Copy + Send, const ALIGN: usize> Send for AlignedVec<T, ALIGN> {}
+unsafe impl<T: Copy + Sync, const ALIGN: usize> Sync for AlignedVec<T, ALIGN> {}
+
+impl<T: Copy, const ALIGN: usize> AlignedVec<T, ALIGN> {
+    // Panics if ALIGN isn't a power of two.
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(
+            len * std::mem::size_of::<T>(),
+            ALIGN.max(std::mem::align_of::<T>()),
+        )
+        .unwrap()
+    }
+
+    // Allocate room for `len` elements, left uninitialised.
+    fn allocate(len: usize) -> NonNull<T> {
+        let layout = Self::layout(len);
+
+        if layout.size() == 0 {
+            // Dangling, but aligned and never dereferenced
+            return NonNull::new(layout.align() as *mut T).unwrap();
+        }
+
+        let ptr = unsafe { alloc::alloc(layout) } as *mut T;
+        NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+    }
+
+    pub fn from_elem(value: T, len: usize) -> Self {
+        let ptr = Self::allocate(len);
+        for i in 0..len {
+            unsafe { ptr.as_ptr().add(i).write(value) };
+        }
+        Self {
+            ptr,
+            len,
+            block: None,
+        }
+    }
+
+    // Room for `len` elements, for kernels that write every element before reading any of them,
+    // e.g. with non-temporal stores that would otherwise follow a pass zeroing the buffer. The
+    // buffer is aligned and freed on drop as any other.
+    //
+    // # Safety
+    // Every element must be written through `as_mut_ptr` before the buffer is used as a slice in any
+    // way, including writing an element by index, which borrows the whole buffer as a `&mut [T]`
+    // of uninitialised values, as do `Clone`, `PartialEq` and `Debug`. Dropping the buffer reads
+    // nothing, so it may be dropped before it's initialised.
+    pub(crate) unsafe fn uninit(len: usize) -> Self {
+        Self {
+            ptr: Self::allocate(len),
+            len,
+            block: None,
+        }
+    }
+
+    // Pointers to the first element, which unlike those of the slice don't borrow the elements, so
+    // may be used to write those of an `uninit` buffer.
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr.as_ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    pub fn from_slice(values: &[T]) -> Self {
+        let ptr = Self::allocate(values.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(values.as_ptr(), ptr.as_ptr(), values.len());
+        }
+        Self {
+            ptr,
+            len: values.len(),
+            block: None,
+        }
+    }
+}
+
+impl<T: Copy, const ALIGN: usize> Drop for AlignedVec<T, ALIGN> {
+    fn drop(&mut self) {
+        if self.block.is_some() {
+            return;
+        }
+
+        let layout = Self::layout(self.len);
+        if layout.size() != 0 {
+            unsafe { alloc::dealloc(self.ptr.as_ptr() as *mut u8, layout) };
+        }
+    }
+}
+
+impl<T: Copy, const ALIGN: usize> Deref for AlignedVec<T, ALIGN> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy, const ALIGN: usize> DerefMut for AlignedVec<T, ALIGN> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy, const ALIGN: usize> Clone for AlignedVec<T, ALIGN> {
+    fn clone(&self) -> Self {
+        Self::from_slice(self)
+    }
+}
+
+impl<T: Copy, const ALIGN: usize> Default for AlignedVec<T, ALIGN> {
+    fn default() -> Self {
+        Self::from_slice(&[])
+    }
+}
+
+impl<T: Copy + fmt::Debug, const ALIGN: usize> fmt::Debug for AlignedVec<T, ALIGN> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: Copy + PartialEq, const ALIGN: usize> PartialEq for AlignedVec<T, ALIGN> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Copy, const ALIGN: usize> From<&[T]> for AlignedVec<T, ALIGN> {
+    fn from(values: &[T]) -> Self {
+        Self::from_slice(values)
+    }
+}
+
+impl<T: Copy, const ALIGN: usize> From<Vec<T>> for AlignedVec<T, ALIGN> {
+    fn from(values: Vec<T>) -> Self {
+        Self::from_slice(&values)
+    }
+}
+
+impl<T: Copy, const ALIGN: usize> FromIterator<T> for AlignedVec<T, ALIGN> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::from(iter.into_iter().collect::<Vec<T>>())
+    }
+}
+
+impl<'a, T: Copy, const ALIGN: usize> IntoIterator for &'a AlignedVec<T, ALIGN> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: Copy, const ALIGN: usize> IntoIterator for &'a mut AlignedVec<T, ALIGN> {
+    type Item = &'a mut T;
+    type IntoIter = std::slice::IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+// How large buffers are allocated. Huge pages cut the TLB misses of scattered accesses over a
+// working set of hundreds of MB. Linux places a page on the NUMA node of the thread that first
+// writes to it, so first touch initialisation by the rayon workers keeps each buffer local to
+// the socket that later processes it, rather than putting all of them on the main thread's node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocPolicy {
+    pub huge_pages: bool,
+    pub first_touch: bool,
+}
+
+impl fmt::Display for AllocPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.huge_pages, self.first_touch) {
+            (false, false) => write!(f, "default"),
+            (true, false) => write!(f, "huge-pages"),
+            (false, true) => write!(f, "first-touch"),
+            (true, true) => write!(f, "huge-pages+first-touch"),
+        }
+    }
+}
+
+// A single allocation split between many buffers, freed when the last of them is dropped.
+struct Block {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+unsafe impl Send for Block {}
+unsafe impl Sync for Block {}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+        }
+    }
+}
+
+// Only advice, the kernel silently uses base pages if transparent huge pages are disabled.
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(ptr: *mut u8, size: usize) {
+    unsafe { libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_HUGEPAGE) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_huge_pages(_ptr: *mut u8, _size: usize) {}
+
+fn round_up(x: usize, multiple: usize) -> usize {
+    x.div_ceil(multiple) * multiple
+}
+
+// `count` buffers of `len` elements set to `value`, allocated according to `policy`.
+//
+// With the default policy each buffer is a separate allocation, initialised on the calling thread.
+// Otherwise the buffers are laid out in order in one block, each starting on an ALIGN byte
+// boundary. With huge pages the block is 2 MiB aligned and advised for transparent huge pages,
+// and with first touch each run of `chunk` consecutive buffers is initialised by one rayon worker,
+// matching drivers that process the buffers in chunks of the same size. A huge page is placed by
+// the first write to any of it, so first touch is then only as fine grained as 2 MiB.
+pub fn alloc_buffers<T: Copy + Send + Sync, const ALIGN: usize>(
+    value: T,
+    len: usize,
+    count: usize,
+    chunk: usize,
+    policy: AllocPolicy,
+) -> Vec<AlignedVec<T, ALIGN>> {
+    if policy == AllocPolicy::default() {
+        return (0..count)
+            .map(|_| AlignedVec::from_elem(value, len))
+            .collect();
+    }
+
+    let align = ALIGN.max(std::mem::align_of::<T>());
+    let stride = round_up(len * std::mem::size_of::<T>(), align);
+
+    let layout = if policy.huge_pages {
+        Layout::from_size_align(round_up(stride * count, HUGE_PAGE), HUGE_PAGE.max(align))
+    } else {
+        Layout::from_size_align(stride * count, align)
+    }
+    .unwrap();
+
+    let ptr = if layout.size() == 0 {
+        // Dangling, but aligned and never dereferenced
+        NonNull::new(layout.align() as *mut u8).unwrap()
+    } else {
+        // Left untouched, so that pages are only backed by memory on the first write below
+        let ptr = unsafe { alloc::alloc(layout) };
+        NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+    };
+
+    if policy.huge_pages && layout.size() != 0 {
+        advise_huge_pages(ptr.as_ptr(), layout.size());
+    }
+
+    let block = Arc::new(Block { ptr, layout });
+
+    let init = |i: usize| {
+        let ptr = unsafe { block.ptr.as_ptr().add(i * stride) } as *mut T;
+        for j in 0..len {
+            unsafe { ptr.add(j).write(value) };
+        }
+        AlignedVec {
+            ptr: NonNull::new(ptr).unwrap(),
+            len,
+            block: Some(Arc::clone(&block)),
+        }
+    };
+
+    if policy.first_touch {
+        let chunk = chunk.max(1);
+        let nchunks = count.div_ceil(chunk);
+        (0..nchunks)
+            .into_par_iter()
+            .flat_map_iter(|c| (c * chunk..((c + 1) * chunk).min(count)).map(init))
+            .collect()
+    } else {
+        (0..count).map(init).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICIES: [AllocPolicy; 4] = [
+        AllocPolicy {
+            huge_pages: false,
+            first_touch: false,
+        },
+        AllocPolicy {
+            huge_pages: true,
+            first_touch: false,
+        },
+        AllocPolicy {
+            huge_pages: false,
+            first_touch: true,
+        },
+        AllocPolicy {
+            huge_pages: true,
+            first_touch: true,
+        },
+    ];
+
+    #[test]
+    fn buffers_are_aligned() {
+        for len in [0, 1, 3, 8, 1000] {
+            let v = AlignedVec::<f64>::from_elem(1., len);
+            assert!(is_aligned(v.as_ptr(), CACHE_LINE));
+            assert_eq!(v.len(), len);
+            assert!(v.iter().all(|&x| x == 1.));
+
+            let v = AlignedVec::<u8, 4096>::from_slice(&vec![7; len]);
+            assert!(is_aligned(v.as_ptr(), 4096));
+            assert_eq!(&*v, &vec![7; len][..]);
+
+            let w = v.clone();
+            assert!(is_aligned(w.as_ptr(), 4096));
+            assert_eq!(v, w);
+        }
+    }
+
+    #[test]
+    fn uninit_buffers_are_aligned() {
+        for len in [0, 1, 3, 8, 1000] {
+            let mut v = unsafe { AlignedVec::<f64>::uninit(len) };
+            assert!(is_aligned(v.as_ptr(), CACHE_LINE));
+            assert_eq!(v.len(), len);
+
+            for i in 0..len {
+                unsafe { v.as_mut_ptr().add(i).write(i as f64) };
+            }
+            assert!(v.iter().enumerate().all(|(i, &x)| x == i as f64));
+        }
+    }
+
+    #[test]
+    fn alloc_buffers_follow_policy() {
+        for policy in POLICIES {
+            for (len, count) in [(0, 0), (0, 5), (3, 1), (3, 13), (100, 20)] {
+                let mut buffers = alloc_buffers::<f64, CACHE_LINE>(2., len, count, 8, policy);
+                assert_eq!(buffers.len(), count);
+
+                for b in buffers.iter() {
+                    assert_eq!(b.len(), len);
+                    assert!(is_aligned(b.as_ptr(), CACHE_LINE));
+                    assert!(b.iter().all(|&x| x == 2.));
+                    assert_eq!(b.block.is_some(), policy != AllocPolicy::default());
+                }
+
+                if policy.huge_pages && count > 0 {
+                    assert!(is_aligned(buffers[0].as_ptr(), HUGE_PAGE));
+                }
+
+                // Buffers are laid out in order and don't overlap
+                for (i, b) in buffers.iter_mut().enumerate() {
+                    b.iter_mut().for_each(|x| *x = i as f64);
+                }
+                for (i, b) in buffers.iter().enumerate() {
+                    assert!(b.iter().all(|&x| x == i as f64));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shared_block_is_freed_with_its_last_buffer() {
+        for policy in &POLICIES[1..] {
+            let mut buffers = alloc_buffers::<f64, CACHE_LINE>(1., 10, 4, 2, *policy);
+            let block = Arc::downgrade(buffers[0].block.as_ref().unwrap());
+            assert_eq!(block.strong_count(), 4);
+
+            // A clone owns its own allocation
+            let copy = buffers[3].clone();
+            assert!(copy.block.is_none());
+
+            buffers.truncate(1);
+            assert_eq!(block.strong_count(), 1);
+            assert!(buffers[0].iter().all(|&x| x == 1.));
+
+            drop(buffers);
+            assert!(block.upgrade().is_none());
+            assert!(copy.iter().all(|&x| x == 1.));
+        }
+    }
+}
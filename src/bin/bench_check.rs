@@ -0,0 +1,107 @@
+//! Compare the latest Criterion results against a saved baseline, failing if any benchmark slowed
+//! down by more than a threshold.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use serde_json::Value;
+
+#[derive(Parser)]
+#[command(
+    name = "bench-check",
+    about = "Flag benchmarks that regressed against a saved Criterion baseline"
+)]
+struct Cli {
+    /// Baseline saved with `cargo bench -- --save-baseline <name>`
+    #[arg(long, default_value = "main")]
+    baseline: String,
+
+    /// Relative slowdown of the median that counts as a regression
+    #[arg(long, default_value_t = 0.05)]
+    threshold: f64,
+
+    #[arg(long, default_value = "target/criterion")]
+    criterion_dir: PathBuf,
+}
+
+// Median time in ns from a Criterion estimates file.
+fn median(path: &Path) -> Option<f64> {
+    let estimates: Value = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    estimates["median"]["point_estimate"].as_f64()
+}
+
+// Directories holding a `new` result, i.e. one per benchmark.
+fn benchmarks(dir: &Path, found: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if path.file_name().is_some_and(|n| n == "new") {
+                found.push(dir.to_path_buf());
+            } else {
+                benchmarks(&path, found);
+            }
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let mut dirs = Vec::new();
+    benchmarks(&cli.criterion_dir, &mut dirs);
+    dirs.sort();
+
+    if dirs.is_empty() {
+        eprintln!("No Criterion results in {:?}, run `cargo bench` first", cli.criterion_dir);
+        std::process::exit(1);
+    }
+
+    let mut regressions = 0;
+
+    for dir in dirs.iter() {
+        let name = dir.strip_prefix(&cli.criterion_dir).unwrap_or(dir).display();
+
+        let (Some(new), Some(base)) = (
+            median(&dir.join("new").join("estimates.json")),
+            median(&dir.join(&cli.baseline).join("estimates.json")),
+        ) else {
+            println!("{:<50} no baseline '{}'", name, cli.baseline);
+            continue;
+        };
+
+        let change = new / base - 1.;
+        let flag = if change > cli.threshold {
+            regressions += 1;
+            "REGRESSED"
+        } else if change < -cli.threshold {
+            "improved"
+        } else {
+            ""
+        };
+
+        println!(
+            "{:<50} {:>12.3}us -> {:>12.3}us {:>+7.1}% {}",
+            name,
+            base * 1e-3,
+            new * 1e-3,
+            100. * change,
+            flag
+        );
+    }
+
+    if regressions > 0 {
+        eprintln!(
+            "{} benchmarks regressed by more than {:.1}% against '{}'",
+            regressions,
+            100. * cli.threshold,
+            cli.baseline
+        );
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,1543 @@
+//! Unified benchmark driver for the dotp, Hadamard, P2P and M2L experiments.
+use std::{
+    collections::HashSet,
+    hint::black_box,
+    path::PathBuf,
+    sync::{OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use num::complex::Complex64;
+
+use bempp_traits::tree::Tree;
+use bempp_tree::types::{domain::Domain, morton::MortonKey, single_node::SingleNodeTree};
+
+use rust_simd::{
+    aligned::{AlignedVec, AllocPolicy},
+    blas1,
+    cachesim::*,
+    distributions,
+    dotp::*,
+    hadamard::*,
+    helpers::*,
+    kernel::{Helmholtz, Laplace, ModifiedHelmholtz},
+    m2l::*,
+    operators::C2ECache,
+    p2p::*,
+    perf::PerfCounters,
+    prefetch::PrefetchPolicy,
+    periodic::{
+        apply_lattice_sum, is_cubic, lattice_sum_operator, root_multipole, Boundary,
+        MIN_PERIODIC_LEVEL,
+    },
+    results::{summarise, write_results, BenchResult, Format, HostInfo},
+    roofline::*,
+    stream::*,
+    svd::*,
+    timing::PhaseTimes,
+    tune::{candidate_block_sizes, tune, TunedBlock, Tuning, DEFAULT_TUNING_FILE},
+};
+
+#[derive(Parser)]
+#[command(name = "m2l-bench", about = "Benchmarks for SIMD M2L-type operations")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    params: Params,
+}
+
+#[derive(Args, Clone, Debug)]
+struct Params {
+    /// Number of points in the tree, or number of elements for dotp and blas1
+    #[arg(long, global = true, default_value_t = 1000000)]
+    npoints: usize,
+
+    #[arg(long, global = true, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    /// Seed of all random inputs, so that runs are reproducible
+    #[arg(long, global = true, default_value_t = DEFAULT_SEED)]
+    seed: u64,
+
+    /// Keys of the M2L benchmarks, from a tree over the points or a synthetic octree at `depth`
+    #[arg(long, global = true, value_enum, default_value_t = Octree::Points)]
+    octree: Octree,
+
+    /// Fraction of sibling sets kept in a sparse synthetic octree
+    #[arg(long, global = true, default_value_t = 0.5)]
+    occupancy: f64,
+
+    #[arg(long, global = true, default_value_t = 150)]
+    ncrit: u64,
+
+    #[arg(long, global = true, default_value_t = 5)]
+    depth: u64,
+
+    #[arg(long, global = true, default_value_t = 9)]
+    expansion_order: usize,
+
+    #[arg(long, global = true, value_enum, default_value_t = Backend::Naive)]
+    backend: Backend,
+
+    /// Kernel whose spectra the Hadamard products and M2L drivers use, random data if not given
+    #[arg(long, global = true, value_enum)]
+    kernel: Option<GreenKernel>,
+
+    /// Wavenumber of the Yukawa and Helmholtz kernels
+    #[arg(long, global = true, default_value_t = 1.0)]
+    wavenumber: f64,
+
+    /// Size of the rayon thread pool, defaults to the number of cores
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+
+    /// Advise transparent huge pages for the M2L expansion buffers
+    #[arg(long, global = true)]
+    huge_pages: bool,
+
+    /// Initialise the M2L expansion buffers in parallel, so that pages are placed on the NUMA
+    /// node of the worker that processes them
+    #[arg(long, global = true)]
+    first_touch: bool,
+
+    #[arg(long, global = true, default_value_t = 1)]
+    repetitions: usize,
+
+    /// Append results to this file
+    #[arg(long, global = true)]
+    output: Option<PathBuf>,
+
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Jsonl)]
+    format: OutputFormat,
+
+    /// Measure the memory bandwidth and compare achieved performance to the roofline
+    #[arg(long, global = true)]
+    roofline: bool,
+
+    /// Block sizes tuned by `tune`, used by the blocked M2L drivers unless given explicitly
+    #[arg(long, global = true, default_value = DEFAULT_TUNING_FILE)]
+    tuning_file: PathBuf,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Elementwise z = x * y
+    Dotp {
+        #[arg(long, value_enum, default_value_t = Precision::F64)]
+        precision: Precision,
+
+        #[arg(long)]
+        parallel: bool,
+
+        /// Use the generic portable SIMD FMA kernel with this many lanes (2, 4, 8 or 16)
+        #[arg(long)]
+        lanes: Option<usize>,
+
+        /// Use the aligned load and store kernels
+        #[arg(long, conflicts_with = "lanes")]
+        aligned: bool,
+
+        /// Start the arrays this many elements past a cache line boundary
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+    },
+    /// BLAS level-1 dot product, axpy, scal or Euclidean norm over npoints elements
+    Blas1 {
+        #[arg(long, value_enum, default_value_t = Blas1Op::Dot)]
+        op: Blas1Op,
+
+        #[arg(long)]
+        complex: bool,
+
+        /// Conjugate x in the complex dot product
+        #[arg(long)]
+        conjugate: bool,
+
+        /// Summation of the reductions, dot and nrm2
+        #[arg(long, value_enum, default_value_t = Summation::Naive)]
+        summation: Summation,
+    },
+    /// Hadamard product of a single sibling set with the kernel data
+    Hadamard {
+        /// Use the aligned load and store kernel
+        #[arg(long)]
+        aligned: bool,
+
+        /// Write the result with non-temporal stores
+        #[arg(long, conflicts_with = "aligned")]
+        stream: bool,
+    },
+    /// Near field evaluation over the leaves of the tree
+    P2p {
+        /// Compare against the scalar reference
+        #[arg(long)]
+        check: bool,
+    },
+    M2lNaive,
+    M2lNaivePar,
+    M2lParent {
+        /// Prefetch the buffers of the sibling set this many sets ahead, 0 disables prefetching
+        #[arg(long, default_value_t = 0)]
+        prefetch_distance: usize,
+
+        /// Cache lines prefetched from the start of each buffer
+        #[arg(long, default_value_t = 8)]
+        prefetch_lines: usize,
+
+        /// Keys per block of the SIMD drivers, a multiple of 8, defaults to the tuned size
+        #[arg(long)]
+        block_size: Option<usize>,
+    },
+    M2lPeriodic {
+        /// Periodic images of the domain summed in each direction for the far field above the root
+        #[arg(long, default_value_t = 3)]
+        nimages: i64,
+    },
+    /// Parent level M2L packing the next block of sibling sets while the current one is computed
+    M2lPipelined {
+        /// Keys per block, a multiple of 8, defaults to the tuned size
+        #[arg(long)]
+        block_size: Option<usize>,
+    },
+    /// Time a blocked M2L driver over candidate block sizes derived from the cache sizes, saving
+    /// the fastest to the tuning file
+    Tune {
+        #[arg(long, value_enum, default_value_t = BlockedDriver::Parent)]
+        driver: BlockedDriver,
+    },
+    M2lSvd {
+        /// Truncation rank of the compressed operators
+        #[arg(long, default_value_t = 50)]
+        rank: usize,
+    },
+    /// STREAM copy, scale, add and triad, sweeping the working set through the cache hierarchy
+    Stream {
+        #[arg(long)]
+        parallel: bool,
+
+        /// Largest working set of the sweep, in MiB
+        #[arg(long, default_value_t = 512)]
+        max_working_set: usize,
+
+        /// Minimum time spent on each working set size, in seconds
+        #[arg(long, default_value_t = 0.1)]
+        min_time: f64,
+    },
+    /// Replay the expansion buffer accesses of an M2L driver through a model cache hierarchy
+    CacheSim {
+        #[arg(long, value_enum, default_value_t = SimDriver::Parent)]
+        driver: SimDriver,
+
+        /// Keys per block for the blocked driver, a multiple of 8
+        #[arg(long, default_value_t = 4096)]
+        block_size: usize,
+
+        /// Order of traversal and buffer layout, only the naive driver accepts hash order
+        #[arg(long, value_enum, default_value_t = KeyOrder::Morton)]
+        key_order: KeyOrder,
+    },
+}
+
+impl Command {
+    // The command with the block sizes of the blocked M2L drivers filled in, so that the size used
+    // is recorded with the results.
+    fn with_block_sizes(&self, params: &Params) -> Command {
+        match self {
+            Command::M2lParent {
+                prefetch_distance,
+                prefetch_lines,
+                block_size,
+            } => Command::M2lParent {
+                prefetch_distance: *prefetch_distance,
+                prefetch_lines: *prefetch_lines,
+                block_size: blocked_backend(params)
+                    .then(|| block_size_for(params, BlockedDriver::Parent, *block_size)),
+            },
+            Command::M2lPipelined { block_size } => Command::M2lPipelined {
+                block_size: Some(block_size_for(params, BlockedDriver::Pipelined, *block_size)),
+            },
+            command => command.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Dotp { .. } => "dotp",
+            Command::Blas1 { op, .. } => match op {
+                Blas1Op::Dot => "dot",
+                Blas1Op::Axpy => "axpy",
+                Blas1Op::Scal => "scal",
+                Blas1Op::Nrm2 => "nrm2",
+            },
+            Command::Hadamard { .. } => "hadamard",
+            Command::P2p { .. } => "p2p",
+            Command::M2lNaive => "m2l-naive",
+            Command::M2lNaivePar => "m2l-naive-par",
+            Command::M2lParent { .. } => "m2l-parent",
+            Command::M2lPeriodic { .. } => "m2l-periodic",
+            Command::M2lPipelined { .. } => "m2l-pipelined",
+            Command::Tune { .. } => "tune",
+            Command::M2lSvd { .. } => "m2l-svd",
+            Command::Stream { .. } => "stream",
+            Command::CacheSim { .. } => "cache-sim",
+        }
+    }
+
+    // Kernel specific options, recorded alongside the results
+    fn variant(&self) -> String {
+        match self {
+            Command::Dotp {
+                precision,
+                parallel,
+                lanes,
+                aligned,
+                offset,
+            } => {
+                let mut variant = format!("{:?}", precision).to_lowercase();
+                if let Some(lanes) = lanes {
+                    variant = format!("{} x{}", variant, lanes);
+                }
+                if *parallel {
+                    variant = format!("{} parallel", variant);
+                }
+                if *aligned {
+                    variant = format!("{} aligned", variant);
+                }
+                if *offset > 0 {
+                    variant = format!("{} offset {}", variant, offset);
+                }
+                variant
+            }
+            Command::Hadamard { aligned: true, .. } => "aligned".to_string(),
+            Command::Hadamard { stream: true, .. } => "stream".to_string(),
+            Command::Blas1 {
+                op,
+                complex,
+                conjugate,
+                summation,
+            } => {
+                let mut variant = if *complex { "c64" } else { "f64" }.to_string();
+                if *op == Blas1Op::Dot && *complex && *conjugate {
+                    variant = format!("{} conjugate", variant);
+                }
+                if matches!(op, Blas1Op::Dot | Blas1Op::Nrm2) {
+                    variant = format!("{} {:?}", variant, summation).to_lowercase();
+                }
+                variant
+            }
+            Command::M2lParent {
+                prefetch_distance,
+                prefetch_lines,
+                block_size,
+            } => {
+                let mut variant = Vec::new();
+                if let Some(block_size) = block_size {
+                    variant.push(format!("block {}", block_size));
+                }
+                if *prefetch_distance > 0 {
+                    variant.push(format!(
+                        "prefetch {} lines {}",
+                        prefetch_distance, prefetch_lines
+                    ));
+                }
+                variant.join(" ")
+            }
+            Command::M2lPipelined {
+                block_size: Some(block_size),
+            } => format!("block {}", block_size),
+            Command::Tune { driver } => format!("{:?}", driver).to_lowercase(),
+            Command::M2lPeriodic { nimages } => format!("images {}", nimages),
+            Command::M2lSvd { rank } => format!("rank {}", rank),
+            Command::Stream { parallel, .. } => {
+                if *parallel {
+                    "triad parallel".to_string()
+                } else {
+                    "triad".to_string()
+                }
+            }
+            Command::CacheSim {
+                driver,
+                block_size,
+                key_order,
+            } => {
+                let variant = format!("{:?} {:?}", driver, key_order).to_lowercase();
+                if *driver == SimDriver::Blocked {
+                    format!("{} block {}", variant, block_size)
+                } else {
+                    variant
+                }
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Jsonl,
+    Csv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Distribution {
+    Uniform,
+    SphereSurface,
+    Plummer,
+    GaussianMixture,
+}
+
+impl From<Distribution> for distributions::Distribution {
+    fn from(distribution: Distribution) -> Self {
+        match distribution {
+            Distribution::Uniform => distributions::Distribution::Uniform,
+            Distribution::SphereSurface => distributions::Distribution::SphereSurface,
+            Distribution::Plummer => distributions::Distribution::Plummer,
+            Distribution::GaussianMixture => distributions::Distribution::GaussianMixture,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Blas1Op {
+    Dot,
+    Axpy,
+    Scal,
+    Nrm2,
+}
+
+impl From<Blas1Op> for blas1::Blas1Op {
+    fn from(op: Blas1Op) -> Self {
+        match op {
+            Blas1Op::Dot => blas1::Blas1Op::Dot,
+            Blas1Op::Axpy => blas1::Blas1Op::Axpy,
+            Blas1Op::Scal => blas1::Blas1Op::Scal,
+            Blas1Op::Nrm2 => blas1::Blas1Op::Nrm2,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Summation {
+    Naive,
+    Kahan,
+    Pairwise,
+}
+
+impl From<Summation> for blas1::Summation {
+    fn from(summation: Summation) -> Self {
+        match summation {
+            Summation::Naive => blas1::Summation::Naive,
+            Summation::Kahan => blas1::Summation::Kahan,
+            Summation::Pairwise => blas1::Summation::Pairwise,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Octree {
+    Points,
+    Full,
+    Sparse,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    Naive,
+    Portable,
+    Avx2,
+    Neon,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum GreenKernel {
+    Laplace,
+    Yukawa,
+    Helmholtz,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SimDriver {
+    Naive,
+    Parent,
+    Blocked,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum BlockedDriver {
+    Parent,
+    Pipelined,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyOrder {
+    // Sorted Morton order, as the parent level drivers traverse
+    Morton,
+    // Iteration order of the key maps, as the naive drivers traverse
+    Hash,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Precision {
+    F32,
+    F64,
+}
+
+impl Params {
+    fn alloc(&self) -> AllocPolicy {
+        AllocPolicy {
+            huge_pages: self.huge_pages,
+            first_touch: self.first_touch,
+        }
+    }
+}
+
+fn unsupported(name: &str, params: &Params) -> String {
+    format!("{} has no {:?} backend in this build", name, params.backend)
+}
+
+// Hardware counters, enabled only around the timed regions of each kernel.
+static COUNTERS: OnceLock<PerfCounters> = OnceLock::new();
+
+fn counters() -> &'static PerfCounters {
+    COUNTERS.get_or_init(PerfCounters::open)
+}
+
+// Time `repetitions` calls of `f`.
+fn time<F: FnMut()>(repetitions: usize, mut f: F) -> Vec<Duration> {
+    (0..repetitions.max(1))
+        .map(|_| {
+            let s = Instant::now();
+            counters().measure(&mut f);
+            s.elapsed()
+        })
+        .collect()
+}
+
+// Wall times of each repetition, and for the instrumented drivers the mean time per phase.
+struct Timings {
+    times: Vec<Duration>,
+    phases: Vec<(String, f64)>,
+    // Analytic cost of a single repetition
+    cost: Option<Cost>,
+}
+
+impl From<Vec<Duration>> for Timings {
+    fn from(times: Vec<Duration>) -> Self {
+        Self {
+            times,
+            phases: Vec::new(),
+            cost: None,
+        }
+    }
+}
+
+impl Timings {
+    fn with_cost(mut self, cost: Cost) -> Self {
+        self.cost = Some(cost);
+        self
+    }
+}
+
+// Time `repetitions` calls of an instrumented driver, averaging the phase breakdown.
+fn time_phases<F: FnMut() -> PhaseTimes>(repetitions: usize, mut f: F) -> Timings {
+    let repetitions = repetitions.max(1);
+    let mut times = Vec::new();
+    let mut phases: Vec<(String, f64)> = Vec::new();
+
+    for _ in 0..repetitions {
+        let s = Instant::now();
+        let breakdown = counters().measure(&mut f).breakdown();
+        times.push(s.elapsed());
+
+        if phases.is_empty() {
+            phases = breakdown.iter().map(|(name, _)| (name.clone(), 0.)).collect();
+        }
+        for (p, (_, t)) in phases.iter_mut().zip(breakdown.iter()) {
+            p.1 += t / (repetitions as f64);
+        }
+    }
+
+    Timings {
+        times,
+        phases,
+        cost: None,
+    }
+}
+
+fn tree(params: &Params) -> SingleNodeTree {
+    let points = distributions::points(params.distribution.into(), params.npoints, params.seed);
+
+    let global_idxs: Vec<usize> = (0..params.npoints).collect();
+
+    SingleNodeTree::new(
+        &points,
+        false,
+        Some(params.ncrit),
+        Some(params.depth),
+        &global_idxs,
+    )
+}
+
+// Leaves of the M2L benchmarks and their domain. Synthetic octrees skip building a tree from
+// points, so they scale cheaply to deeper trees.
+fn leaves(params: &Params) -> (HashSet<MortonKey>, Domain) {
+    match params.octree {
+        Octree::Points => {
+            let tree = tree(params);
+            (tree.get_all_leaves_set().clone(), tree.get_domain().clone())
+        }
+        Octree::Full => (full_octree_keys(params.depth), unit_domain()),
+        Octree::Sparse => (
+            sparse_octree_keys(params.depth, params.occupancy, params.seed),
+            unit_domain(),
+        ),
+    }
+}
+
+// Width of the leaf boxes, taking the leaves to be at `depth`.
+fn box_width(params: &Params, domain: &Domain) -> f64 {
+    domain.diameter[0] / 2f64.powi(params.depth as i32)
+}
+
+// The 16 kernels of the Hadamard products, the spectra of the chosen kernel between boxes of
+// `box_width`, else random data. A complex kernel has a buffer for each of the real and imaginary
+// parts of its spectrum, which the drivers are run with in turn.
+fn kernel_data(params: &Params, box_width: f64) -> Vec<RwLock<AlignedVec<Complex64>>> {
+    let expansion_order = params.expansion_order;
+
+    let data = match params.kernel {
+        None => vec![kernel_like_data_transpose(expansion_order, params.seed.wrapping_add(1))],
+        Some(GreenKernel::Laplace) => m2l_kernel_data(&Laplace, expansion_order, box_width),
+        Some(GreenKernel::Yukawa) => m2l_kernel_data(
+            &ModifiedHelmholtz::new(params.wavenumber),
+            expansion_order,
+            box_width,
+        ),
+        Some(GreenKernel::Helmholtz) => m2l_kernel_data(
+            &Helmholtz::new(params.wavenumber),
+            expansion_order,
+            box_width,
+        ),
+    };
+
+    data.into_iter().map(RwLock::new).collect()
+}
+
+// Run an instrumented driver with the kernels of each part of the spectrum, summing their times.
+fn each_part<F: FnMut(&RwLock<AlignedVec<Complex64>>) -> PhaseTimes>(
+    kernel_data: &[RwLock<AlignedVec<Complex64>>],
+    mut f: F,
+) -> PhaseTimes {
+    let times = PhaseTimes::new();
+    for k in kernel_data.iter() {
+        times.accumulate(&f(k));
+    }
+    times
+}
+
+// Total size of the interaction lists of all leaves.
+fn interaction_count(leaves: &HashSet<MortonKey>) -> usize {
+    leaves
+        .iter()
+        .map(|key| {
+            key.parent()
+                .neighbors()
+                .iter()
+                .flat_map(|pn| pn.children())
+                .filter(|pnc| !key.is_adjacent_same_level(pnc) && leaves.contains(pnc))
+                .count()
+        })
+        .sum()
+}
+
+// Number of pairwise near field interactions, and the number of targets.
+fn near_field_count(tree: &SingleNodeTree) -> (usize, usize) {
+    let npoints = |key| tree.get_points(key).map(|p| p.len()).unwrap_or(0);
+
+    let mut ninteractions = 0;
+    let mut ntargets = 0;
+
+    for leaf in tree.get_all_leaves_set().iter() {
+        let ntargets_leaf = npoints(leaf);
+        let nsources: usize = leaf.neighbors().iter().map(npoints).sum::<usize>() + ntargets_leaf;
+
+        ninteractions += ntargets_leaf * nsources;
+        ntargets += ntargets_leaf;
+    }
+
+    (ninteractions, ntargets)
+}
+
+fn dotp(
+    params: &Params,
+    precision: Precision,
+    parallel: bool,
+    lanes: Option<usize>,
+    aligned: bool,
+    offset: usize,
+) -> Result<Timings, String> {
+    let nblocks = (params.npoints / BLOCK_SIZE).max(1);
+
+    // The arrays start on a cache line, offsetting them splits vectors across cache lines
+    if offset >= nblocks * BLOCK_SIZE {
+        return Err(format!("--offset {} is past the end of the arrays", offset));
+    }
+    let elem_size = match precision {
+        Precision::F32 => 4,
+        Precision::F64 => 8,
+    };
+    if aligned && offset * elem_size % 32 != 0 {
+        return Err(format!(
+            "--offset {} misaligns the arrays for the aligned kernels",
+            offset
+        ));
+    }
+    let n = nblocks * BLOCK_SIZE - offset;
+
+    if let Some(lanes) = lanes {
+        if params.backend != Backend::Portable {
+            return Err("--lanes selects the portable SIMD kernel".to_string());
+        }
+
+        let unsupported = || format!("no generic kernel with {} lanes", lanes);
+
+        return match precision {
+            Precision::F32 => {
+                let kernel = fma_simd_lanes::<f32>(lanes, parallel).ok_or_else(unsupported)?;
+                let (x, y, mut z) = data_f32(nblocks);
+                let times = time(params.repetitions, || {
+                    kernel(&x[offset..], &y[offset..], &mut z[offset..]).unwrap()
+                });
+                Ok(Timings::from(times).with_cost(dotp_fma_cost(n, elem_size)))
+            }
+            Precision::F64 => {
+                let kernel = fma_simd_lanes::<f64>(lanes, parallel).ok_or_else(unsupported)?;
+                let (x, y, mut z) = data_f64(nblocks);
+                let times = time(params.repetitions, || {
+                    kernel(&x[offset..], &y[offset..], &mut z[offset..]).unwrap()
+                });
+                Ok(Timings::from(times).with_cost(dotp_fma_cost(n, elem_size)))
+            }
+        };
+    }
+
+    let kernel_f32: Option<DotpFn<f32>> = match (params.backend, parallel, aligned) {
+        (Backend::Naive, false, false) => Some(dotp_naive_f32),
+        (Backend::Portable, false, false) => Some(dotp_simd_f32_portable),
+        (Backend::Portable, false, true) => Some(dotp_simd_f32_portable_aligned),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, false, false) => Some(rust_simd::dotp::x86::dotp_simd_f32),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, true, false) => Some(rust_simd::dotp::x86::dotp_simd_f32_par),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, false, true) => Some(rust_simd::dotp::x86::dotp_simd_f32_aligned),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, true, true) => Some(rust_simd::dotp::x86::dotp_simd_f32_par_aligned),
+        _ => None,
+    };
+
+    let kernel_f64: Option<DotpFn<f64>> = match (params.backend, parallel, aligned) {
+        (Backend::Portable, false, false) => Some(dotp_simd_f64_portable),
+        (Backend::Portable, false, true) => Some(dotp_simd_f64_portable_aligned),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Naive, false, false) => Some(rust_simd::dotp::x86::dotp_naive_f64),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, false, false) => Some(rust_simd::dotp::x86::dotp_simd_f64),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, true, false) => Some(rust_simd::dotp::x86::dotp_simd_f64_par),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, false, true) => Some(rust_simd::dotp::x86::dotp_simd_f64_aligned),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, true, true) => Some(rust_simd::dotp::x86::dotp_simd_f64_par_aligned),
+        _ => None,
+    };
+
+    // The intrinsic kernels accumulate with an FMA, the others only multiply
+    let cost = |elem_size| match params.backend {
+        Backend::Avx2 => dotp_fma_cost(n, elem_size),
+        _ => dotp_mul_cost(n, elem_size),
+    };
+
+    match precision {
+        Precision::F32 => {
+            let kernel = kernel_f32.ok_or_else(|| unsupported("dotp", params))?;
+            let (x, y, mut z) = data_f32(nblocks);
+            let times = time(params.repetitions, || {
+                kernel(&x[offset..], &y[offset..], &mut z[offset..]).unwrap()
+            });
+            Ok(Timings::from(times).with_cost(cost(elem_size)))
+        }
+        Precision::F64 => {
+            let kernel = kernel_f64.ok_or_else(|| unsupported("dotp", params))?;
+            let (x, y, mut z) = data_f64(nblocks);
+            let times = time(params.repetitions, || {
+                kernel(&x[offset..], &y[offset..], &mut z[offset..]).unwrap()
+            });
+            Ok(Timings::from(times).with_cost(cost(elem_size)))
+        }
+    }
+}
+
+fn blas1_bench(
+    params: &Params,
+    op: Blas1Op,
+    complex: bool,
+    conjugate: bool,
+    summation: Summation,
+) -> Result<Timings, String> {
+    let backend = match params.backend {
+        Backend::Naive => blas1::Blas1Backend::Scalar,
+        Backend::Portable => blas1::Blas1Backend::Portable,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Backend::Avx2 => blas1::Blas1Backend::Avx2,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Backend::Neon => blas1::Blas1Backend::Neon,
+        #[allow(unreachable_patterns)]
+        _ => return Err(unsupported("blas1", params)),
+    };
+
+    let n = params.npoints;
+    let summation = summation.into();
+
+    // Unit modulus, so that repeated scaling neither underflows nor overflows
+    let a = Complex64::new(0.6, 0.8);
+
+    let x = random_complex(n, params.seed);
+    let mut y = random_complex(n, params.seed.wrapping_add(1));
+
+    let times = if complex {
+        time(params.repetitions, || match op {
+            Blas1Op::Dot => {
+                black_box(blas1::dot_complex(backend, summation, conjugate, &x, &y).unwrap());
+            }
+            Blas1Op::Axpy => blas1::axpy_complex(backend, a, &x, &mut y).unwrap(),
+            Blas1Op::Scal => blas1::scal_complex(backend, a, &mut y),
+            Blas1Op::Nrm2 => {
+                black_box(blas1::nrm2_complex(backend, summation, &x));
+            }
+        })
+    } else {
+        let x: Vec<f64> = x.iter().map(|c| c.re).collect();
+        let mut y: Vec<f64> = y.iter().map(|c| c.re).collect();
+
+        time(params.repetitions, || match op {
+            Blas1Op::Dot => {
+                black_box(blas1::dot(backend, summation, &x, &y).unwrap());
+            }
+            Blas1Op::Axpy => blas1::axpy(backend, -1., &x, &mut y).unwrap(),
+            Blas1Op::Scal => blas1::scal(backend, -1., &mut y),
+            Blas1Op::Nrm2 => {
+                black_box(blas1::nrm2(backend, summation, &x));
+            }
+        })
+    };
+
+    Ok(Timings::from(times).with_cost(blas1_cost(op.into(), complex, n)))
+}
+
+fn hadamard(params: &Params, aligned: bool, stream: bool) -> Result<Timings, String> {
+    let expansion_order = params.expansion_order;
+
+    // NEON has no separate aligned loads, so only AVX2 has an aligned variant
+    let kernel: HadamardFn = match (params.backend, aligned, stream) {
+        (Backend::Naive, false, false) => hadamard_product_naive,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, false, false) => rust_simd::hadamard::x86::hadamard_product_simd,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, true, false) => |order, sibling_set, kernel_data| {
+            rust_simd::hadamard::x86::hadamard_product_simd_aligned(order, sibling_set, kernel_data)
+                .unwrap()
+        },
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (Backend::Avx2, false, true) => rust_simd::hadamard::x86::hadamard_product_simd_stream,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        (Backend::Neon, false, false) => rust_simd::hadamard::aarch64::hadamard_product_simd_neon,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        (Backend::Neon, false, true) => {
+            rust_simd::hadamard::aarch64::hadamard_product_simd_neon_stream
+        }
+        _ => return Err(unsupported("hadamard", params)),
+    };
+
+    let sibling_set = sibling_set_data(expansion_order, params.seed);
+    let kernel_data = kernel_data(params, box_width(params, &unit_domain()));
+
+    Ok(Timings::from(time(params.repetitions, || {
+        for k in kernel_data.iter() {
+            kernel(expansion_order, &sibling_set, k);
+        }
+    }))
+    .with_cost(hadamard_cost(expansion_order) * kernel_data.len() as f64))
+}
+
+// Check the accuracy of a P2P kernel against the scalar reference, relative to the largest value.
+fn check_p2p(
+    expected: &(Vec<f64>, Vec<[f64; 3]>),
+    found: &(Vec<f64>, Vec<[f64; 3]>),
+) -> Result<(), String> {
+    let max_pot = expected.0.iter().fold(0f64, |a, b| a.max(b.abs()));
+    let max_grad = expected
+        .1
+        .iter()
+        .flat_map(|g| g.iter())
+        .fold(0f64, |a, b| a.max(b.abs()));
+
+    let err_pot = expected
+        .0
+        .iter()
+        .zip(found.0.iter())
+        .fold(0f64, |a, (e, f)| a.max((e - f).abs()))
+        / max_pot;
+
+    let err_grad = expected
+        .1
+        .iter()
+        .zip(found.1.iter())
+        .flat_map(|(e, f)| e.iter().zip(f.iter()))
+        .fold(0f64, |a, (e, f)| a.max((e - f).abs()))
+        / max_grad;
+
+    if err_pot < 1e-12 && err_grad < 1e-12 {
+        Ok(())
+    } else {
+        Err(format!(
+            "p2p maximum errors {:e} in the potential and {:e} in the gradient exceed 1e-12",
+            err_pot, err_grad
+        ))
+    }
+}
+
+fn p2p(params: &Params, check: bool) -> Result<Timings, String> {
+    let kernel: P2PFn = match params.backend {
+        Backend::Naive => p2p_laplace_naive,
+        Backend::Portable => p2p_laplace_portable,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Backend::Avx2 => rust_simd::p2p::x86::p2p_laplace_avx2,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Backend::Neon => rust_simd::p2p::aarch64::p2p_laplace_neon,
+        _ => return Err(unsupported("p2p", params)),
+    };
+
+    let tree = tree(params);
+    let charges = vec![1f64; params.npoints];
+
+    let times = time(params.repetitions, || {
+        p2p_tree(&tree, &charges, kernel);
+    });
+
+    if check {
+        let expected = p2p_tree(&tree, &charges, p2p_laplace_naive);
+        let found = p2p_tree(&tree, &charges, kernel);
+        check_p2p(&expected, &found)?;
+    }
+
+    let (ninteractions, ntargets) = near_field_count(&tree);
+    Ok(Timings::from(times).with_cost(p2p_cost(ninteractions, ntargets)))
+}
+
+fn m2l_parent(
+    params: &Params,
+    prefetch: PrefetchPolicy,
+    block_size: Option<usize>,
+) -> Result<Timings, String> {
+    type Driver = fn(
+        usize,
+        &HashSet<MortonKey>,
+        &RwLock<AlignedVec<Complex64>>,
+        AllocPolicy,
+        PrefetchPolicy,
+        usize,
+    ) -> PhaseTimes;
+    let driver: Driver = match params.backend {
+        Backend::Naive if prefetch.distance == 0 => {
+            |order, leaves, kernel_data, alloc, _, _| {
+                m2l_parent_par_naive(order, leaves, kernel_data, alloc)
+            }
+        }
+        Backend::Naive => {
+            return Err("m2l-parent prefetching is only implemented by the SIMD backends".to_string())
+        }
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Backend::Avx2 => rust_simd::m2l::x86::m2l_parent_par_simd,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Backend::Neon => rust_simd::m2l::aarch64::m2l_parent_par_simd,
+        _ => return Err(unsupported("m2l-parent", params)),
+    };
+
+    if let Some(block_size) = block_size {
+        check_block_size("m2l-parent", block_size)?;
+    }
+
+    let (leaves, domain) = leaves(params);
+    let kernel_data = kernel_data(params, box_width(params, &domain));
+    let cost = m2l_parent_cost_for(params, &leaves, Boundary::Free) * kernel_data.len() as f64;
+
+    Ok(time_phases(params.repetitions, || {
+        each_part(&kernel_data, |k| {
+            driver(
+                params.expansion_order,
+                &leaves,
+                k,
+                params.alloc(),
+                prefetch,
+                block_size.unwrap_or_default(),
+            )
+        })
+    })
+    .with_cost(cost))
+}
+
+fn check_block_size(name: &str, block_size: usize) -> Result<(), String> {
+    if block_size == 0 || block_size % 8 != 0 {
+        Err(format!("{} block size must be a positive multiple of 8", name))
+    } else {
+        Ok(())
+    }
+}
+
+fn m2l_pipelined(params: &Params, block_size: usize) -> Result<Timings, String> {
+    check_block_size("m2l-pipelined", block_size)?;
+    let hadamard = pipelined_hadamard(params)?;
+    let (leaves, domain) = leaves(params);
+    let kernel_data = kernel_data(params, box_width(params, &domain));
+    let cost = m2l_parent_cost_for(params, &leaves, Boundary::Free) * kernel_data.len() as f64;
+
+    Ok(time_phases(params.repetitions, || {
+        each_part(&kernel_data, |k| {
+            m2l_parent_par_pipelined(
+                params.expansion_order,
+                &leaves,
+                k,
+                params.alloc(),
+                hadamard,
+                block_size,
+            )
+        })
+    })
+    .with_cost(cost))
+}
+
+fn pipelined_hadamard(params: &Params) -> Result<HadamardFn, String> {
+    match params.backend {
+        Backend::Naive => Ok(hadamard_product_naive),
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Backend::Avx2 => Ok(rust_simd::hadamard::x86::hadamard_product_simd),
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Backend::Neon => Ok(rust_simd::hadamard::aarch64::hadamard_product_simd_neon),
+        _ => Err(unsupported("m2l-pipelined", params)),
+    }
+}
+
+// Whether the parent level driver of the backend processes keys in blocks
+fn blocked_backend(params: &Params) -> bool {
+    match params.backend {
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Backend::Avx2 => true,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Backend::Neon => true,
+        _ => false,
+    }
+}
+
+fn default_block_size(params: &Params, driver: BlockedDriver) -> usize {
+    match (driver, params.backend) {
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        (BlockedDriver::Parent, Backend::Avx2) => rust_simd::m2l::x86::DEFAULT_BLOCK_SIZE,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        (BlockedDriver::Parent, Backend::Neon) => rust_simd::m2l::aarch64::DEFAULT_BLOCK_SIZE,
+        _ => DEFAULT_PIPELINED_BLOCK_SIZE,
+    }
+}
+
+// Driver and backend a block size is tuned for
+fn tuning_key(params: &Params, driver: BlockedDriver) -> String {
+    let name = match driver {
+        BlockedDriver::Parent => "m2l-parent",
+        BlockedDriver::Pipelined => "m2l-pipelined",
+    };
+    format!("{} {:?}", name, params.backend).to_lowercase()
+}
+
+// Block size given on the command line, else the one tuned for this configuration, else the
+// driver's default.
+fn block_size_for(params: &Params, driver: BlockedDriver, block_size: Option<usize>) -> usize {
+    block_size
+        .or_else(|| {
+            Tuning::load(&params.tuning_file).block_size(
+                &tuning_key(params, driver),
+                params.expansion_order,
+                rayon::current_num_threads(),
+            )
+        })
+        .unwrap_or_else(|| default_block_size(params, driver))
+}
+
+fn tune_bench(params: &Params, driver: BlockedDriver) -> Result<Timings, String> {
+    let (leaves, domain) = leaves(params);
+    let kernel_data = kernel_data(params, box_width(params, &domain));
+
+    type Run<'a> =
+        dyn Fn(&HashSet<MortonKey>, &RwLock<AlignedVec<Complex64>>, usize) -> PhaseTimes + 'a;
+    let run: Box<Run<'_>> = match driver {
+        BlockedDriver::Parent => match params.backend {
+            #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+            Backend::Avx2 => Box::new(|leaves, kernel_data, block_size| {
+                rust_simd::m2l::x86::m2l_parent_par_simd(
+                    params.expansion_order,
+                    leaves,
+                    kernel_data,
+                    params.alloc(),
+                    PrefetchPolicy::default(),
+                    block_size,
+                )
+            }),
+            #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+            Backend::Neon => Box::new(|leaves, kernel_data, block_size| {
+                rust_simd::m2l::aarch64::m2l_parent_par_simd(
+                    params.expansion_order,
+                    leaves,
+                    kernel_data,
+                    params.alloc(),
+                    PrefetchPolicy::default(),
+                    block_size,
+                )
+            }),
+            _ => return Err(unsupported("tune --driver parent", params)),
+        },
+        BlockedDriver::Pipelined => {
+            let hadamard = pipelined_hadamard(params)?;
+            Box::new(move |leaves, kernel_data, block_size| {
+                m2l_parent_par_pipelined(
+                    params.expansion_order,
+                    leaves,
+                    kernel_data,
+                    params.alloc(),
+                    hadamard,
+                    block_size,
+                )
+            })
+        }
+    };
+
+    let threads = rayon::current_num_threads();
+    let candidates =
+        candidate_block_sizes(params.expansion_order, &cache_sizes(), threads, leaves.len());
+
+    let (times, best) = tune(&candidates, params.repetitions, |block_size| {
+        let s = Instant::now();
+        each_part(&kernel_data, |k| run(&leaves, k, block_size));
+        s.elapsed()
+    });
+
+    println!("block size       min    median       max");
+    for (block_size, t) in times.iter() {
+        let (min, median, max) = summarise(t);
+        println!(
+            "{:>10} {:>9.6}s {:>9.6}s {:>9.6}s",
+            block_size, min, median, max
+        );
+    }
+
+    let best_times = times.into_iter().find(|(b, _)| *b == best).unwrap().1;
+    let (min, median, max) = summarise(&best_times);
+
+    let mut tuning = Tuning::load(&params.tuning_file);
+    tuning.insert(TunedBlock {
+        driver: tuning_key(params, driver),
+        expansion_order: params.expansion_order,
+        threads,
+        block_size: best,
+        seconds: median,
+        min_seconds: min,
+        max_seconds: max,
+    });
+    tuning
+        .save(&params.tuning_file)
+        .map_err(|e| format!("failed to write {}: {}", params.tuning_file.display(), e))?;
+    println!(
+        "tuned block size {} saved to {}",
+        best,
+        params.tuning_file.display()
+    );
+
+    let cost = m2l_parent_cost_for(params, &leaves, Boundary::Free) * kernel_data.len() as f64;
+    Ok(Timings::from(best_times).with_cost(cost))
+}
+
+// Model of the parent level drivers, the number of saves per parent is that of a parent in the
+// interior of the domain.
+fn m2l_parent_cost_for(params: &Params, leaves: &HashSet<MortonKey>, boundary: Boundary) -> Cost {
+    let nparents = leaves.len() / 8;
+    let nsaves: usize = scatter_displacements(boundary).iter().map(|s| s.len()).sum();
+    m2l_parent_cost(params.expansion_order, nparents, nsaves)
+}
+
+// Parent level M2L with periodic boundaries, followed by the far field of the images of the domain
+// applied to the root's expansion. The lattice sum and the upward pass giving the root's expansion
+// are computed outside the timed region.
+fn m2l_periodic(params: &Params, nimages: i64) -> Result<Timings, String> {
+    let (leaves, domain) = leaves(params);
+
+    // The halos are the periodic neighbours of the parents
+    if leaves.iter().any(|key| key.level() < MIN_PERIODIC_LEVEL + 1) {
+        return Err(format!(
+            "m2l-periodic needs leaves at depth {} or deeper",
+            MIN_PERIODIC_LEVEL + 1
+        ));
+    }
+    if nimages < 1 {
+        return Err("--nimages must be at least 1".to_string());
+    }
+    if !is_cubic(&domain) {
+        return Err(format!(
+            "m2l-periodic needs a cubic domain, the tree's is {:?}",
+            domain.diameter
+        ));
+    }
+
+    let kernel_data = kernel_data(params, box_width(params, &domain));
+    let cost = m2l_parent_cost_for(params, &leaves, Boundary::Periodic) * kernel_data.len() as f64;
+
+    let lattice_sum = lattice_sum_operator(&Laplace, params.expansion_order, &domain, nimages);
+
+    // The root's expansion, translated up the tree from random leaf expansions with no net charge
+    let c2e = C2ECache::new(Laplace, params.expansion_order, domain.diameter[0]);
+    let multipoles =
+        random_multipoles(params.expansion_order, &leaves, params.seed.wrapping_add(2));
+    let root_density = root_multipole(&c2e, &domain, &multipoles);
+
+    Ok(time_phases(params.repetitions, || {
+        let times = each_part(&kernel_data, |k| {
+            m2l_parent_par_periodic(params.expansion_order, &leaves, &domain, k, params.alloc())
+        });
+        black_box(apply_lattice_sum(&lattice_sum, &root_density));
+        times
+    })
+    .with_cost(cost))
+}
+
+fn m2l_svd_bench(params: &Params, rank: usize) -> Result<Timings, String> {
+    let (leaves, domain) = leaves(params);
+    let operator = svd_m2l_operator(
+        &Laplace,
+        params.expansion_order,
+        box_width(params, &domain),
+        rank,
+    )
+    .map_err(|e| e.to_string())?;
+
+    // The leaves and domain are checked by an untimed run
+    m2l_svd(&operator, params.expansion_order, &leaves, &domain).map_err(|e| e.to_string())?;
+
+    // The downward check potentials output by the M2L are converted to equivalent densities,
+    // with the operators precomputed outside the timed region
+    let c2e = C2ECache::new(Laplace, params.expansion_order, domain.diameter[0]);
+    c2e.get(params.depth);
+
+    let ncoeffs = 6 * (params.expansion_order - 1).pow(2) + 2;
+    let cost = m2l_svd_cost(
+        ncoeffs,
+        operator.k,
+        leaves.len(),
+        interaction_count(&leaves),
+    );
+
+    Ok(Timings::from(time(params.repetitions, || {
+        let locals = m2l_svd(&operator, params.expansion_order, &leaves, &domain).unwrap();
+        c2e.downward_equivalent(params.depth, &locals);
+    }))
+    .with_cost(cost))
+}
+
+// Print the bandwidth sweep, then time the triad over the largest working set so that it is
+// recorded like any other kernel.
+fn stream_bench(
+    params: &Params,
+    parallel: bool,
+    max_working_set: usize,
+    min_time: f64,
+) -> Result<Timings, String> {
+    let backend = match params.backend {
+        Backend::Naive => StreamBackend::Scalar,
+        Backend::Portable => StreamBackend::Portable,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Backend::Avx2 => StreamBackend::Avx2,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Backend::Neon => StreamBackend::Neon,
+        #[allow(unreachable_patterns)]
+        _ => return Err(unsupported("stream", params)),
+    };
+
+    let max_working_set = max_working_set << 20;
+    let level = |l: Option<usize>| l.map_or("mem".to_string(), |l| format!("L{}", l));
+
+    let results = stream_sweep(backend, parallel, max_working_set, min_time);
+    for r in results.iter() {
+        println!(
+            "  {:<6} {:>10} KiB {:>4} {:>10.3} GB/s",
+            r.kernel.name(),
+            r.working_set >> 10,
+            level(r.cache_level),
+            r.bandwidth
+        );
+    }
+
+    println!("best bandwidth per level:");
+    for (kernel, l, bandwidth) in bandwidth_per_level(&results) {
+        println!("  {:<6} {:>4} {:>10.3} GB/s", kernel.name(), level(l), bandwidth);
+    }
+
+    let kernel = StreamKernel::Triad;
+    let nblocks = (max_working_set / (kernel.arrays() * BLOCK_SIZE * 8)).max(1);
+    let (x, y, mut z) = data_f64(nblocks);
+    let times = time(params.repetitions, || {
+        stream(kernel, backend, parallel, &x, &y, &mut z)
+    });
+
+    Ok(Timings::from(times).with_cost(stream_cost(kernel, nblocks * BLOCK_SIZE)))
+}
+
+fn cache_sim(
+    params: &Params,
+    driver: SimDriver,
+    block_size: usize,
+    key_order: KeyOrder,
+) -> Result<Timings, String> {
+    let (leaves, _) = leaves(params);
+
+    let mut keys: Vec<MortonKey> = leaves.into_iter().collect();
+    match key_order {
+        KeyOrder::Morton => keys.sort(),
+        KeyOrder::Hash if driver != SimDriver::Naive => {
+            return Err("hash key order splits sibling sets, use the naive driver".to_string())
+        }
+        KeyOrder::Hash => (),
+    }
+
+    let trace = match driver {
+        SimDriver::Naive => trace_m2l_naive(params.expansion_order, &keys),
+        SimDriver::Parent => trace_m2l_parent(params.expansion_order, &keys),
+        SimDriver::Blocked => {
+            check_block_size("cache-sim", block_size)?;
+            trace_m2l_blocked(params.expansion_order, &keys, block_size)
+        }
+    };
+
+    let configs = CacheConfig::detect();
+    let mut hierarchy = Hierarchy::new(&configs);
+    let times = time(1, || hierarchy.replay(&trace));
+
+    println!("{} MiB traced", trace.bytes() >> 20);
+    print!("{}", hierarchy);
+
+    Ok(Timings::from(times))
+}
+
+fn run(command: &Command, params: &Params) -> Result<Timings, String> {
+    if params.octree == Octree::Sparse && params.depth == 0 {
+        return Err("--octree sparse needs --depth 1 or deeper".to_string());
+    }
+
+    let naive_only = |name: &str| {
+        if params.backend == Backend::Naive {
+            Ok(())
+        } else {
+            Err(unsupported(name, params))
+        }
+    };
+
+    match command {
+        Command::Dotp {
+            precision,
+            parallel,
+            lanes,
+            aligned,
+            offset,
+        } => dotp(params, *precision, *parallel, *lanes, *aligned, *offset),
+        Command::Blas1 {
+            op,
+            complex,
+            conjugate,
+            summation,
+        } => blas1_bench(params, *op, *complex, *conjugate, *summation),
+        Command::Hadamard { aligned, stream } => hadamard(params, *aligned, *stream),
+        Command::P2p { check } => p2p(params, *check),
+        Command::M2lNaive => {
+            naive_only("m2l-naive")?;
+            let (leaves, _) = leaves(params);
+            Ok(Timings::from(time(params.repetitions, || {
+                m2l_naive(params.expansion_order, &leaves)
+            })))
+        }
+        Command::M2lNaivePar => {
+            naive_only("m2l-naive-par")?;
+            let (leaves, _) = leaves(params);
+            let cost = m2l_naive_cost(params.expansion_order, interaction_count(&leaves));
+            Ok(Timings::from(time(params.repetitions, || {
+                m2l_naive_par(params.expansion_order, &leaves, params.alloc())
+            }))
+            .with_cost(cost))
+        }
+        Command::M2lParent {
+            prefetch_distance,
+            prefetch_lines,
+            block_size,
+        } => {
+            let prefetch = PrefetchPolicy {
+                distance: *prefetch_distance,
+                lines: *prefetch_lines,
+            };
+            m2l_parent(params, prefetch, *block_size)
+        }
+        Command::M2lPipelined { block_size } => {
+            m2l_pipelined(params, block_size.unwrap_or(DEFAULT_PIPELINED_BLOCK_SIZE))
+        }
+        Command::Tune { driver } => tune_bench(params, *driver),
+        Command::M2lPeriodic { nimages } => {
+            naive_only("m2l-periodic")?;
+            m2l_periodic(params, *nimages)
+        }
+        Command::M2lSvd { rank } => {
+            naive_only("m2l-svd")?;
+            m2l_svd_bench(params, *rank)
+        }
+        Command::Stream {
+            parallel,
+            max_working_set,
+            min_time,
+        } => stream_bench(params, *parallel, *max_working_set, *min_time),
+        Command::CacheSim {
+            driver,
+            block_size,
+            key_order,
+        } => cache_sim(params, *driver, *block_size, *key_order),
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let params = &cli.params;
+
+    // Counters are inherited by threads spawned after they're opened, so open them before rayon
+    // starts its pool
+    counters().reset();
+
+    if let Some(threads) = params.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+
+    // Tuned block sizes depend on the size of the pool
+    let command = cli.command.with_block_sizes(params);
+
+    let Timings {
+        times,
+        phases,
+        cost,
+    } = match run(&command, params) {
+        Ok(timings) => timings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let (min, median, max) = summarise(&times);
+
+    // The ceiling is the main memory triad bandwidth over the whole thread pool
+    let bandwidth = if params.roofline {
+        Some(bandwidth_ceiling(true))
+    } else {
+        None
+    };
+
+    let result = BenchResult {
+        kernel: command.name().to_string(),
+        variant: command.variant(),
+        backend: format!("{:?}", params.backend).to_lowercase(),
+        npoints: params.npoints,
+        distribution: params
+            .distribution
+            .to_possible_value()
+            .unwrap()
+            .get_name()
+            .to_string(),
+        octree: format!("{:?}", params.octree).to_lowercase(),
+        seed: params.seed,
+        ncrit: params.ncrit,
+        depth: params.depth,
+        expansion_order: params.expansion_order,
+        threads: rayon::current_num_threads(),
+        alloc: params.alloc().to_string(),
+        repetitions: times.len(),
+        min,
+        median,
+        max,
+        phases,
+        counters: counters().read().per_repetition(times.len()),
+        intensity: cost.map(|c| c.intensity()),
+        gflops: cost.map(|c| c.gflops(median)),
+        gbytes: cost.map(|c| c.gbytes(median)),
+        bandwidth,
+        roofline_fraction: cost
+            .zip(bandwidth)
+            .map(|(c, bw)| c.gflops(median) / c.attainable_gflops(bw)),
+        host: HostInfo::detect(),
+    };
+
+    println!(
+        "{} {} {} (alloc {}): min {:.6}s median {:.6}s max {:.6}s over {} repetitions",
+        result.kernel,
+        result.variant,
+        result.backend,
+        result.alloc,
+        min,
+        median,
+        max,
+        result.repetitions
+    );
+
+    for (name, t) in result.phases.iter() {
+        println!("  {:<15} {:.6}s (mean, summed over workers)", name, t);
+    }
+
+    print!(
+        "counters, total over {} repetitions:\n{}",
+        result.repetitions,
+        counters().read()
+    );
+
+    if let (Some(intensity), Some(gflops), Some(gbytes)) =
+        (result.intensity, result.gflops, result.gbytes)
+    {
+        println!(
+            "  {:.3} FLOP/byte, {:.3} GFLOP/s, {:.3} GB/s",
+            intensity, gflops, gbytes
+        );
+    }
+
+    if let (Some(bandwidth), Some(fraction)) = (result.bandwidth, result.roofline_fraction) {
+        println!(
+            "  bandwidth ceiling {:.3} GB/s, {:.1}% of attainable performance",
+            bandwidth,
+            100. * fraction
+        );
+    }
+
+    if let Some(output) = &params.output {
+        let format = match params.format {
+            OutputFormat::Jsonl => Format::JsonLines,
+            OutputFormat::Csv => Format::Csv,
+        };
+
+        if let Err(e) = write_results(output, format, &[result]) {
+            eprintln!("Failed to write results to {:?}: {}", output, e);
+            std::process::exit(1);
+        }
+    }
+}
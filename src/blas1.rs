@@ -0,0 +1,826 @@
+//! BLAS level-1 kernels, reducing dot products, axpy, scal and nrm2 over real and complex vectors.
+//! Complex vectors are processed as their interleaved real and imaginary parts, so each backend
+//! only needs a real reduction and a real update kernel. The SIMD reductions keep several
+//! independent accumulators so that consecutive FMAs don't wait on each other's results.
+use std::{ops::Add, simd::prelude::*, simd::StdFloat};
+
+use num::complex::Complex64;
+
+use crate::dotp::{check_lengths, LengthMismatch};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Blas1Backend {
+    Scalar,
+    Portable,
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    Avx2,
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    Neon,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Blas1Op {
+    // sum_i x[i] * y[i]
+    Dot,
+    // y = a * x + y
+    Axpy,
+    // x = a * x
+    Scal,
+    // ||x||_2
+    Nrm2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Summation {
+    // Plain accumulation, error grows linearly with the length
+    Naive,
+    // Compensated accumulation, error independent of the length
+    Kahan,
+    // Blocks accumulated plainly and combined in a binary tree, error grows logarithmically
+    Pairwise,
+}
+
+// Independent accumulators of the SIMD reductions
+const ACCUMULATORS: usize = 4;
+
+// Length below which pairwise summation accumulates plainly
+const PAIRWISE_BLOCK: usize = 256;
+
+// Sums of x[i] * y[i] over even and odd i, and of x[i] * y[i ^ 1] with y's pairs swapped. For
+// interleaved complex data the even terms are products of real parts with real (direct) or
+// imaginary (swapped) parts.
+#[derive(Clone, Copy, Debug, Default)]
+struct ParitySums {
+    direct: [f64; 2],
+    swapped: [f64; 2],
+}
+
+impl ParitySums {
+    fn from_lanes(direct: &[f64], swapped: &[f64]) -> Self {
+        let mut sums = ParitySums::default();
+        for (i, (d, s)) in direct.iter().zip(swapped.iter()).enumerate() {
+            sums.direct[i % 2] += d;
+            sums.swapped[i % 2] += s;
+        }
+        sums
+    }
+}
+
+impl Add for ParitySums {
+    type Output = ParitySums;
+
+    fn add(self, other: ParitySums) -> ParitySums {
+        ParitySums {
+            direct: [
+                self.direct[0] + other.direct[0],
+                self.direct[1] + other.direct[1],
+            ],
+            swapped: [
+                self.swapped[0] + other.swapped[0],
+                self.swapped[1] + other.swapped[1],
+            ],
+        }
+    }
+}
+
+type DotKernel = fn(&[f64], &[f64]) -> ParitySums;
+
+// y += a[0] * x, plus [-a[1], a[1]] * x with x's pairs swapped for complex a
+type AxpyKernel = fn([f64; 2], &[f64], &mut [f64]);
+
+// x = a[0] * x, plus [-a[1], a[1]] * x with x's pairs swapped for complex a
+type ScalKernel = fn([f64; 2], &mut [f64]);
+
+fn kahan(sum: &mut f64, compensation: &mut f64, value: f64) {
+    let y = value - *compensation;
+    let t = *sum + y;
+    *compensation = (t - *sum) - y;
+    *sum = t;
+}
+
+// Scalar reference kernels, also used for the tails of the SIMD kernels. Slices passed to them
+// start at an even index, so the parity of the local index is that of the global index.
+mod scalar {
+    use super::*;
+
+    pub(super) fn dot<const SWAP: bool, const KAHAN: bool>(x: &[f64], y: &[f64]) -> ParitySums {
+        let mut sums = ParitySums::default();
+        let mut compensation = ParitySums::default();
+
+        for (i, (a, b)) in x.iter().zip(y.iter()).enumerate() {
+            let parity = i % 2;
+            if KAHAN {
+                kahan(
+                    &mut sums.direct[parity],
+                    &mut compensation.direct[parity],
+                    a * b,
+                );
+            } else {
+                sums.direct[parity] += a * b;
+            }
+
+            if SWAP {
+                let b = y[i ^ 1];
+                if KAHAN {
+                    kahan(
+                        &mut sums.swapped[parity],
+                        &mut compensation.swapped[parity],
+                        a * b,
+                    );
+                } else {
+                    sums.swapped[parity] += a * b;
+                }
+            }
+        }
+
+        sums
+    }
+
+    pub(super) fn axpy<const COMPLEX: bool>(a: [f64; 2], x: &[f64], y: &mut [f64]) {
+        for (i, c) in y.iter_mut().enumerate() {
+            *c += a[0] * x[i];
+            if COMPLEX {
+                let sign = if i % 2 == 0 { -1. } else { 1. };
+                *c += sign * a[1] * x[i ^ 1];
+            }
+        }
+    }
+
+    pub(super) fn scal<const COMPLEX: bool>(a: [f64; 2], x: &mut [f64]) {
+        if COMPLEX {
+            for pair in x.chunks_exact_mut(2) {
+                let (re, im) = (pair[0], pair[1]);
+                pair[0] = a[0] * re - a[1] * im;
+                pair[1] = a[0] * im + a[1] * re;
+            }
+        } else {
+            x.iter_mut().for_each(|c| *c *= a[0]);
+        }
+    }
+}
+
+mod portable {
+    use super::*;
+
+    const LANES: usize = 4;
+
+    fn accumulate<const KAHAN: bool>(
+        sum: &mut f64x4,
+        compensation: &mut f64x4,
+        a: f64x4,
+        b: f64x4,
+    ) {
+        if KAHAN {
+            let y = a * b - *compensation;
+            let t = *sum + y;
+            *compensation = (t - *sum) - y;
+            *sum = t;
+        } else {
+            *sum = a.mul_add(b, *sum);
+        }
+    }
+
+    pub(super) fn dot<const SWAP: bool, const KAHAN: bool>(x: &[f64], y: &[f64]) -> ParitySums {
+        let block = LANES * ACCUMULATORS;
+        let body = x.len() - x.len() % block;
+
+        let mut direct = [f64x4::splat(0.); ACCUMULATORS];
+        let mut swapped = [f64x4::splat(0.); ACCUMULATORS];
+        let mut c_direct = [f64x4::splat(0.); ACCUMULATORS];
+        let mut c_swapped = [f64x4::splat(0.); ACCUMULATORS];
+
+        for (a, b) in x[..body]
+            .chunks_exact(block)
+            .zip(y[..body].chunks_exact(block))
+        {
+            for (k, (a, b)) in a.chunks_exact(LANES).zip(b.chunks_exact(LANES)).enumerate() {
+                let x_a = f64x4::from_slice(a);
+                let y_a = f64x4::from_slice(b);
+                accumulate::<KAHAN>(&mut direct[k], &mut c_direct[k], x_a, y_a);
+
+                if SWAP {
+                    let y_s = simd_swizzle!(y_a, [1, 0, 3, 2]);
+                    accumulate::<KAHAN>(&mut swapped[k], &mut c_swapped[k], x_a, y_s);
+                }
+            }
+        }
+
+        let reduce = |sums: [f64x4; ACCUMULATORS], compensations: [f64x4; ACCUMULATORS]| {
+            sums.iter()
+                .zip(compensations.iter())
+                .fold(f64x4::splat(0.), |acc, (s, c)| acc + (s - c))
+                .to_array()
+        };
+
+        ParitySums::from_lanes(&reduce(direct, c_direct), &reduce(swapped, c_swapped))
+            + scalar::dot::<SWAP, KAHAN>(&x[body..], &y[body..])
+    }
+
+    pub(super) fn axpy<const COMPLEX: bool>(a: [f64; 2], x: &[f64], y: &mut [f64]) {
+        let body = x.len() - x.len() % LANES;
+        let re = f64x4::splat(a[0]);
+        let im = f64x4::from_array([-a[1], a[1], -a[1], a[1]]);
+
+        for (a, c) in x[..body]
+            .chunks_exact(LANES)
+            .zip(y[..body].chunks_exact_mut(LANES))
+        {
+            let x_a = f64x4::from_slice(a);
+            let mut r = re.mul_add(x_a, f64x4::from_slice(c));
+            if COMPLEX {
+                r = im.mul_add(simd_swizzle!(x_a, [1, 0, 3, 2]), r);
+            }
+            r.copy_to_slice(c);
+        }
+
+        scalar::axpy::<COMPLEX>(a, &x[body..], &mut y[body..]);
+    }
+
+    pub(super) fn scal<const COMPLEX: bool>(a: [f64; 2], x: &mut [f64]) {
+        let body = x.len() - x.len() % LANES;
+        let re = f64x4::splat(a[0]);
+        let im = f64x4::from_array([-a[1], a[1], -a[1], a[1]]);
+
+        for c in x[..body].chunks_exact_mut(LANES) {
+            let x_a = f64x4::from_slice(c);
+            let mut r = re * x_a;
+            if COMPLEX {
+                r = im.mul_add(simd_swizzle!(x_a, [1, 0, 3, 2]), r);
+            }
+            r.copy_to_slice(c);
+        }
+
+        scalar::scal::<COMPLEX>(a, &mut x[body..]);
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+mod x86 {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 4;
+
+    // Swap the real and imaginary parts of each complex number
+    const SWAP_PAIRS: i32 = 0b0101;
+
+    unsafe fn accumulate<const KAHAN: bool>(
+        sum: &mut __m256d,
+        compensation: &mut __m256d,
+        a: __m256d,
+        b: __m256d,
+    ) {
+        if KAHAN {
+            let y = _mm256_sub_pd(_mm256_mul_pd(a, b), *compensation);
+            let t = _mm256_add_pd(*sum, y);
+            *compensation = _mm256_sub_pd(_mm256_sub_pd(t, *sum), y);
+            *sum = t;
+        } else {
+            *sum = _mm256_fmadd_pd(a, b, *sum);
+        }
+    }
+
+    unsafe fn reduce(
+        sums: [__m256d; ACCUMULATORS],
+        compensations: [__m256d; ACCUMULATORS],
+    ) -> [f64; LANES] {
+        let mut total = _mm256_setzero_pd();
+        for (s, c) in sums.iter().zip(compensations.iter()) {
+            total = _mm256_add_pd(total, _mm256_sub_pd(*s, *c));
+        }
+
+        let mut lanes = [0f64; LANES];
+        _mm256_storeu_pd(lanes.as_mut_ptr(), total);
+        lanes
+    }
+
+    pub(super) fn dot<const SWAP: bool, const KAHAN: bool>(x: &[f64], y: &[f64]) -> ParitySums {
+        let block = LANES * ACCUMULATORS;
+        let body = x.len() - x.len() % block;
+
+        unsafe {
+            let mut direct = [_mm256_setzero_pd(); ACCUMULATORS];
+            let mut swapped = [_mm256_setzero_pd(); ACCUMULATORS];
+            let mut c_direct = [_mm256_setzero_pd(); ACCUMULATORS];
+            let mut c_swapped = [_mm256_setzero_pd(); ACCUMULATORS];
+
+            for (a, b) in x[..body]
+                .chunks_exact(block)
+                .zip(y[..body].chunks_exact(block))
+            {
+                for (k, (a, b)) in a.chunks_exact(LANES).zip(b.chunks_exact(LANES)).enumerate() {
+                    let x_a = _mm256_loadu_pd(a.as_ptr());
+                    let y_a = _mm256_loadu_pd(b.as_ptr());
+                    accumulate::<KAHAN>(&mut direct[k], &mut c_direct[k], x_a, y_a);
+
+                    if SWAP {
+                        let y_s = _mm256_permute_pd(y_a, SWAP_PAIRS);
+                        accumulate::<KAHAN>(&mut swapped[k], &mut c_swapped[k], x_a, y_s);
+                    }
+                }
+            }
+
+            ParitySums::from_lanes(&reduce(direct, c_direct), &reduce(swapped, c_swapped))
+                + scalar::dot::<SWAP, KAHAN>(&x[body..], &y[body..])
+        }
+    }
+
+    pub(super) fn axpy<const COMPLEX: bool>(a: [f64; 2], x: &[f64], y: &mut [f64]) {
+        let body = x.len() - x.len() % LANES;
+
+        unsafe {
+            let re = _mm256_set1_pd(a[0]);
+            let im = _mm256_setr_pd(-a[1], a[1], -a[1], a[1]);
+
+            for (a, c) in x[..body]
+                .chunks_exact(LANES)
+                .zip(y[..body].chunks_exact_mut(LANES))
+            {
+                let x_a = _mm256_loadu_pd(a.as_ptr());
+                let mut r = _mm256_fmadd_pd(re, x_a, _mm256_loadu_pd(c.as_ptr()));
+                if COMPLEX {
+                    r = _mm256_fmadd_pd(im, _mm256_permute_pd(x_a, SWAP_PAIRS), r);
+                }
+                _mm256_storeu_pd(c.as_mut_ptr(), r);
+            }
+        }
+
+        scalar::axpy::<COMPLEX>(a, &x[body..], &mut y[body..]);
+    }
+
+    pub(super) fn scal<const COMPLEX: bool>(a: [f64; 2], x: &mut [f64]) {
+        let body = x.len() - x.len() % LANES;
+
+        unsafe {
+            let re = _mm256_set1_pd(a[0]);
+            let im = _mm256_setr_pd(-a[1], a[1], -a[1], a[1]);
+
+            for c in x[..body].chunks_exact_mut(LANES) {
+                let x_a = _mm256_loadu_pd(c.as_ptr());
+                let mut r = _mm256_mul_pd(re, x_a);
+                if COMPLEX {
+                    r = _mm256_fmadd_pd(im, _mm256_permute_pd(x_a, SWAP_PAIRS), r);
+                }
+                _mm256_storeu_pd(c.as_mut_ptr(), r);
+            }
+        }
+
+        scalar::scal::<COMPLEX>(a, &mut x[body..]);
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", feature = "neon"))]
+mod aarch64 {
+    use super::*;
+    use std::arch::aarch64::*;
+
+    const LANES: usize = 2;
+
+    unsafe fn accumulate<const KAHAN: bool>(
+        sum: &mut float64x2_t,
+        compensation: &mut float64x2_t,
+        a: float64x2_t,
+        b: float64x2_t,
+    ) {
+        if KAHAN {
+            let y = vsubq_f64(vmulq_f64(a, b), *compensation);
+            let t = vaddq_f64(*sum, y);
+            *compensation = vsubq_f64(vsubq_f64(t, *sum), y);
+            *sum = t;
+        } else {
+            *sum = vfmaq_f64(*sum, a, b);
+        }
+    }
+
+    unsafe fn reduce(
+        sums: [float64x2_t; ACCUMULATORS],
+        compensations: [float64x2_t; ACCUMULATORS],
+    ) -> [f64; LANES] {
+        let mut total = vdupq_n_f64(0.);
+        for (s, c) in sums.iter().zip(compensations.iter()) {
+            total = vaddq_f64(total, vsubq_f64(*s, *c));
+        }
+
+        [vgetq_lane_f64::<0>(total), vgetq_lane_f64::<1>(total)]
+    }
+
+    pub(super) fn dot<const SWAP: bool, const KAHAN: bool>(x: &[f64], y: &[f64]) -> ParitySums {
+        let block = LANES * ACCUMULATORS;
+        let body = x.len() - x.len() % block;
+
+        unsafe {
+            let mut direct = [vdupq_n_f64(0.); ACCUMULATORS];
+            let mut swapped = [vdupq_n_f64(0.); ACCUMULATORS];
+            let mut c_direct = [vdupq_n_f64(0.); ACCUMULATORS];
+            let mut c_swapped = [vdupq_n_f64(0.); ACCUMULATORS];
+
+            for (a, b) in x[..body]
+                .chunks_exact(block)
+                .zip(y[..body].chunks_exact(block))
+            {
+                for (k, (a, b)) in a.chunks_exact(LANES).zip(b.chunks_exact(LANES)).enumerate() {
+                    let x_a = vld1q_f64(a.as_ptr());
+                    let y_a = vld1q_f64(b.as_ptr());
+                    accumulate::<KAHAN>(&mut direct[k], &mut c_direct[k], x_a, y_a);
+
+                    if SWAP {
+                        let y_s = vextq_f64::<1>(y_a, y_a);
+                        accumulate::<KAHAN>(&mut swapped[k], &mut c_swapped[k], x_a, y_s);
+                    }
+                }
+            }
+
+            ParitySums::from_lanes(&reduce(direct, c_direct), &reduce(swapped, c_swapped))
+                + scalar::dot::<SWAP, KAHAN>(&x[body..], &y[body..])
+        }
+    }
+
+    pub(super) fn axpy<const COMPLEX: bool>(a: [f64; 2], x: &[f64], y: &mut [f64]) {
+        let body = x.len() - x.len() % LANES;
+
+        unsafe {
+            let re = vdupq_n_f64(a[0]);
+            let im = vld1q_f64([-a[1], a[1]].as_ptr());
+
+            for (a, c) in x[..body]
+                .chunks_exact(LANES)
+                .zip(y[..body].chunks_exact_mut(LANES))
+            {
+                let x_a = vld1q_f64(a.as_ptr());
+                let mut r = vfmaq_f64(vld1q_f64(c.as_ptr()), re, x_a);
+                if COMPLEX {
+                    r = vfmaq_f64(r, im, vextq_f64::<1>(x_a, x_a));
+                }
+                vst1q_f64(c.as_mut_ptr(), r);
+            }
+        }
+
+        scalar::axpy::<COMPLEX>(a, &x[body..], &mut y[body..]);
+    }
+
+    pub(super) fn scal<const COMPLEX: bool>(a: [f64; 2], x: &mut [f64]) {
+        let body = x.len() - x.len() % LANES;
+
+        unsafe {
+            let re = vdupq_n_f64(a[0]);
+            let im = vld1q_f64([-a[1], a[1]].as_ptr());
+
+            for c in x[..body].chunks_exact_mut(LANES) {
+                let x_a = vld1q_f64(c.as_ptr());
+                let mut r = vmulq_f64(re, x_a);
+                if COMPLEX {
+                    r = vfmaq_f64(r, im, vextq_f64::<1>(x_a, x_a));
+                }
+                vst1q_f64(c.as_mut_ptr(), r);
+            }
+        }
+
+        scalar::scal::<COMPLEX>(a, &mut x[body..]);
+    }
+}
+
+fn dot_kernel<const SWAP: bool, const KAHAN: bool>(backend: Blas1Backend) -> DotKernel {
+    match backend {
+        Blas1Backend::Scalar => scalar::dot::<SWAP, KAHAN>,
+        Blas1Backend::Portable => portable::dot::<SWAP, KAHAN>,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Blas1Backend::Avx2 => x86::dot::<SWAP, KAHAN>,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Blas1Backend::Neon => aarch64::dot::<SWAP, KAHAN>,
+    }
+}
+
+fn axpy_kernel<const COMPLEX: bool>(backend: Blas1Backend) -> AxpyKernel {
+    match backend {
+        Blas1Backend::Scalar => scalar::axpy::<COMPLEX>,
+        Blas1Backend::Portable => portable::axpy::<COMPLEX>,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Blas1Backend::Avx2 => x86::axpy::<COMPLEX>,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Blas1Backend::Neon => aarch64::axpy::<COMPLEX>,
+    }
+}
+
+fn scal_kernel<const COMPLEX: bool>(backend: Blas1Backend) -> ScalKernel {
+    match backend {
+        Blas1Backend::Scalar => scalar::scal::<COMPLEX>,
+        Blas1Backend::Portable => portable::scal::<COMPLEX>,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        Blas1Backend::Avx2 => x86::scal::<COMPLEX>,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        Blas1Backend::Neon => aarch64::scal::<COMPLEX>,
+    }
+}
+
+// Split at an even index, keeping complex numbers whole, until the halves are small enough to
+// accumulate plainly.
+fn pairwise(kernel: DotKernel, x: &[f64], y: &[f64]) -> ParitySums {
+    if x.len() <= PAIRWISE_BLOCK {
+        return kernel(x, y);
+    }
+
+    let mid = (x.len() / 2) & !1;
+    pairwise(kernel, &x[..mid], &y[..mid]) + pairwise(kernel, &x[mid..], &y[mid..])
+}
+
+fn reduce<const SWAP: bool>(
+    backend: Blas1Backend,
+    summation: Summation,
+    x: &[f64],
+    y: &[f64],
+) -> ParitySums {
+    match summation {
+        Summation::Naive => dot_kernel::<SWAP, false>(backend)(x, y),
+        Summation::Kahan => dot_kernel::<SWAP, true>(backend)(x, y),
+        Summation::Pairwise => pairwise(dot_kernel::<SWAP, false>(backend), x, y),
+    }
+}
+
+// Complex numbers as their interleaved real and imaginary parts
+fn as_f64(x: &[Complex64]) -> &[f64] {
+    unsafe { std::slice::from_raw_parts(x.as_ptr() as *const f64, 2 * x.len()) }
+}
+
+fn as_f64_mut(x: &mut [Complex64]) -> &mut [f64] {
+    unsafe { std::slice::from_raw_parts_mut(x.as_mut_ptr() as *mut f64, 2 * x.len()) }
+}
+
+// The two operand kernels report y's length in place of z's.
+fn check_pair<T>(x: &[T], y: &[T]) -> Result<(), LengthMismatch> {
+    check_lengths(x, y, y)
+}
+
+// sum_i x[i] * y[i]
+pub fn dot(
+    backend: Blas1Backend,
+    summation: Summation,
+    x: &[f64],
+    y: &[f64],
+) -> Result<f64, LengthMismatch> {
+    check_pair(x, y)?;
+    let sums = reduce::<false>(backend, summation, x, y);
+    Ok(sums.direct[0] + sums.direct[1])
+}
+
+// sum_i x[i] * y[i], or sum_i conj(x[i]) * y[i] if conjugated
+pub fn dot_complex(
+    backend: Blas1Backend,
+    summation: Summation,
+    conjugate: bool,
+    x: &[Complex64],
+    y: &[Complex64],
+) -> Result<Complex64, LengthMismatch> {
+    check_pair(x, y)?;
+    let ParitySums { direct, swapped } = reduce::<true>(backend, summation, as_f64(x), as_f64(y));
+
+    // direct sums re(x) re(y) and im(x) im(y), swapped sums re(x) im(y) and im(x) re(y)
+    Ok(if conjugate {
+        Complex64::new(direct[0] + direct[1], swapped[0] - swapped[1])
+    } else {
+        Complex64::new(direct[0] - direct[1], swapped[0] + swapped[1])
+    })
+}
+
+// Euclidean norm, without the rescaling reference BLAS uses to avoid overflow for |x| > 1e154
+pub fn nrm2(backend: Blas1Backend, summation: Summation, x: &[f64]) -> f64 {
+    let sums = reduce::<false>(backend, summation, x, x);
+    (sums.direct[0] + sums.direct[1]).sqrt()
+}
+
+pub fn nrm2_complex(backend: Blas1Backend, summation: Summation, x: &[Complex64]) -> f64 {
+    nrm2(backend, summation, as_f64(x))
+}
+
+// y = a * x + y
+pub fn axpy(backend: Blas1Backend, a: f64, x: &[f64], y: &mut [f64]) -> Result<(), LengthMismatch> {
+    check_pair(x, y)?;
+    axpy_kernel::<false>(backend)([a, 0.], x, y);
+    Ok(())
+}
+
+pub fn axpy_complex(
+    backend: Blas1Backend,
+    a: Complex64,
+    x: &[Complex64],
+    y: &mut [Complex64],
+) -> Result<(), LengthMismatch> {
+    check_pair(x, y)?;
+    axpy_kernel::<true>(backend)([a.re, a.im], as_f64(x), as_f64_mut(y));
+    Ok(())
+}
+
+// x = a * x
+pub fn scal(backend: Blas1Backend, a: f64, x: &mut [f64]) {
+    scal_kernel::<false>(backend)([a, 0.], x);
+}
+
+pub fn scal_complex(backend: Blas1Backend, a: Complex64, x: &mut [Complex64]) {
+    scal_kernel::<true>(backend)([a.re, a.im], as_f64_mut(x));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends() -> Vec<Blas1Backend> {
+        vec![
+            Blas1Backend::Scalar,
+            Blas1Backend::Portable,
+            #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+            Blas1Backend::Avx2,
+            #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+            Blas1Backend::Neon,
+        ]
+    }
+
+    const SUMMATIONS: [Summation; 3] = [Summation::Naive, Summation::Kahan, Summation::Pairwise];
+
+    // Empty and single element inputs, either side of a vector and of a block of accumulators for
+    // each backend, and long enough to be split by pairwise summation.
+    const LENGTHS: [usize; 14] = [
+        0,
+        1,
+        2,
+        3,
+        4,
+        5,
+        7,
+        8,
+        9,
+        15,
+        16,
+        17,
+        33,
+        2 * PAIRWISE_BLOCK + 3,
+    ];
+
+    fn data(n: usize, seed: usize) -> Vec<f64> {
+        (0..n)
+            .map(|i| ((7 * i + seed) as f64 * 0.61).sin())
+            .collect()
+    }
+
+    fn data_complex(n: usize, seed: usize) -> Vec<Complex64> {
+        data(2 * n, seed)
+            .chunks_exact(2)
+            .map(|c| Complex64::new(c[0], c[1]))
+            .collect()
+    }
+
+    fn assert_close(a: f64, b: f64, tol: f64) {
+        assert!((a - b).abs() <= tol, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn dot_matches_scalar() {
+        for backend in backends() {
+            for summation in SUMMATIONS {
+                for n in LENGTHS {
+                    let (x, y) = (data(n, 1), data(n, 2));
+                    let expected: f64 = x.iter().zip(y.iter()).map(|(a, b)| a * b).sum();
+                    let tol = 1e-15 * (n as f64 + 1.);
+
+                    assert_close(dot(backend, summation, &x, &y).unwrap(), expected, tol);
+
+                    let norm = x.iter().map(|a| a * a).sum::<f64>().sqrt();
+                    assert_close(nrm2(backend, summation, &x), norm, tol);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dot_complex_matches_scalar() {
+        for backend in backends() {
+            for summation in SUMMATIONS {
+                for conjugate in [false, true] {
+                    for n in LENGTHS {
+                        let (x, y) = (data_complex(n, 1), data_complex(n, 2));
+                        let expected: Complex64 = x
+                            .iter()
+                            .zip(y.iter())
+                            .map(|(a, b)| if conjugate { a.conj() * b } else { a * b })
+                            .sum();
+                        let tol = 1e-15 * (n as f64 + 1.);
+
+                        let result = dot_complex(backend, summation, conjugate, &x, &y).unwrap();
+                        assert_close(result.re, expected.re, tol);
+                        assert_close(result.im, expected.im, tol);
+
+                        let norm = x.iter().map(|a| a.norm_sqr()).sum::<f64>().sqrt();
+                        assert_close(nrm2_complex(backend, summation, &x), norm, tol);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn updates_match_scalar() {
+        let tol = 4. * f64::EPSILON;
+        let a = Complex64::new(0.7, -1.3);
+
+        for backend in backends() {
+            for n in LENGTHS {
+                let x = data(n, 1);
+                let mut y = data(n, 2);
+                let expected = x
+                    .iter()
+                    .zip(y.iter())
+                    .map(|(x, y)| a.re * x + y)
+                    .collect::<Vec<_>>();
+                axpy(backend, a.re, &x, &mut y).unwrap();
+                y.iter()
+                    .zip(expected.iter())
+                    .for_each(|(y, e)| assert_close(*y, *e, tol));
+
+                let expected = x.iter().map(|x| a.re * x).collect::<Vec<_>>();
+                let mut x = x;
+                scal(backend, a.re, &mut x);
+                x.iter()
+                    .zip(expected.iter())
+                    .for_each(|(x, e)| assert_close(*x, *e, tol));
+
+                let x = data_complex(n, 1);
+                let mut y = data_complex(n, 2);
+                let expected = x
+                    .iter()
+                    .zip(y.iter())
+                    .map(|(x, y)| a * x + y)
+                    .collect::<Vec<_>>();
+                axpy_complex(backend, a, &x, &mut y).unwrap();
+                for (y, e) in y.iter().zip(expected.iter()) {
+                    assert_close(y.re, e.re, tol);
+                    assert_close(y.im, e.im, tol);
+                }
+
+                let expected = x.iter().map(|x| a * x).collect::<Vec<_>>();
+                let mut x = x;
+                scal_complex(backend, a, &mut x);
+                for (x, e) in x.iter().zip(expected.iter()) {
+                    assert_close(x.re, e.re, tol);
+                    assert_close(x.im, e.im, tol);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mismatched_lengths_are_rejected() {
+        let mismatch = LengthMismatch { x: 3, y: 4, z: 4 };
+
+        for backend in backends() {
+            assert_eq!(
+                dot(backend, Summation::Naive, &data(3, 1), &data(4, 2)),
+                Err(mismatch)
+            );
+            assert_eq!(
+                dot_complex(
+                    backend,
+                    Summation::Kahan,
+                    true,
+                    &data_complex(3, 1),
+                    &data_complex(4, 2)
+                ),
+                Err(mismatch)
+            );
+
+            let mut y = data(4, 2);
+            assert_eq!(axpy(backend, 2., &data(3, 1), &mut y), Err(mismatch));
+            assert_eq!(y, data(4, 2));
+
+            let mut y = data_complex(4, 2);
+            assert_eq!(
+                axpy_complex(backend, Complex64::new(1., 1.), &data_complex(3, 1), &mut y),
+                Err(mismatch)
+            );
+        }
+    }
+
+    #[test]
+    fn compensated_summation_keeps_small_terms() {
+        // 1 followed by 2^20 terms each squaring to 2^-54, half an ulp of 1, so that plain
+        // summation from the left drops every one added to the 1.
+        let tiny = 2f64.powi(-27);
+        let mut x = vec![tiny; (1 << 20) + 1];
+        x[0] = 1.;
+        let expected = (1. + 2f64.powi(-34)).sqrt();
+
+        // The scalar kernel sums even and odd indices apart, so only the odd terms survive
+        assert_eq!(
+            nrm2(Blas1Backend::Scalar, Summation::Naive, &x),
+            (1. + 2f64.powi(-35)).sqrt()
+        );
+
+        for backend in backends() {
+            assert_close(nrm2(backend, Summation::Kahan, &x), expected, f64::EPSILON);
+
+            // Only the terms summed alongside the 1 in its pairwise block can be dropped
+            assert_close(
+                nrm2(backend, Summation::Pairwise, &x),
+                expected,
+                PAIRWISE_BLOCK as f64 * 2f64.powi(-55),
+            );
+        }
+    }
+}
@@ -0,0 +1,536 @@
+//! Software model of a set associative LRU cache hierarchy, replaying address traces of the
+//! expansion buffer accesses made by the M2L drivers to count misses at each level.
+use std::{collections::HashMap, fmt, fs};
+
+use itertools::Itertools;
+
+use bempp_tree::types::morton::MortonKey;
+
+use crate::m2l::scatter_displacements;
+use crate::periodic::Boundary;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub level: usize,
+    // Capacity in bytes
+    pub size: usize,
+    pub line_size: usize,
+    pub associativity: usize,
+}
+
+impl CacheConfig {
+    pub fn nsets(&self) -> usize {
+        (self.size / (self.line_size * self.associativity)).max(1)
+    }
+
+    // A typical server core, used when the topology can't be read from sysfs.
+    pub fn default_hierarchy() -> Vec<CacheConfig> {
+        vec![
+            CacheConfig {
+                level: 1,
+                size: 32 << 10,
+                line_size: 64,
+                associativity: 8,
+            },
+            CacheConfig {
+                level: 2,
+                size: 1 << 20,
+                line_size: 64,
+                associativity: 16,
+            },
+            CacheConfig {
+                level: 3,
+                size: 32 << 20,
+                line_size: 64,
+                associativity: 16,
+            },
+        ]
+    }
+
+    // Data and unified caches of cpu0 from sysfs, falling back to `default_hierarchy`.
+    pub fn detect() -> Vec<CacheConfig> {
+        let mut configs = Vec::new();
+
+        for index in 0.. {
+            let dir = format!("/sys/devices/system/cpu/cpu0/cache/index{}", index);
+            let read = |f: &str| {
+                fs::read_to_string(format!("{}/{}", dir, f))
+                    .ok()
+                    .map(|s| s.trim().to_string())
+            };
+
+            let Some(kind) = read("type") else {
+                break;
+            };
+
+            if kind == "Instruction" {
+                continue;
+            }
+
+            let number = |f: &str| read(f).and_then(|s| s.parse::<usize>().ok());
+            let size = read("size").and_then(|s| {
+                if let Some(k) = s.strip_suffix('K') {
+                    k.parse::<usize>().ok().map(|k| k << 10)
+                } else if let Some(m) = s.strip_suffix('M') {
+                    m.parse::<usize>().ok().map(|m| m << 20)
+                } else {
+                    s.parse::<usize>().ok()
+                }
+            });
+
+            if let (Some(level), Some(size), Some(line_size), Some(associativity)) = (
+                number("level"),
+                size,
+                number("coherency_line_size"),
+                number("ways_of_associativity"),
+            ) {
+                configs.push(CacheConfig {
+                    level,
+                    size,
+                    line_size,
+                    associativity: associativity.max(1),
+                });
+            }
+        }
+
+        if configs.is_empty() {
+            return Self::default_hierarchy();
+        }
+
+        configs.sort_by_key(|c| c.level);
+        configs
+    }
+}
+
+// A single level, each set holds its tags in LRU order, most recently used last.
+pub struct Cache {
+    pub config: CacheConfig,
+    sets: Vec<Vec<u64>>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            sets: vec![Vec::with_capacity(config.associativity); config.nsets()],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Access a cache line, returning whether it hit. On a miss the line is filled, evicting the
+    // least recently used line of its set.
+    pub fn access(&mut self, line: u64) -> bool {
+        let nsets = self.sets.len() as u64;
+        let set = &mut self.sets[(line % nsets) as usize];
+        let tag = line / nsets;
+
+        if let Some(pos) = set.iter().position(|&t| t == tag) {
+            set.remove(pos);
+            set.push(tag);
+            self.hits += 1;
+            true
+        } else {
+            if set.len() == self.config.associativity {
+                set.remove(0);
+            }
+            set.push(tag);
+            self.misses += 1;
+            false
+        }
+    }
+}
+
+// Caches ordered from L1 outwards, a line missing in one level is looked up in the next and filled
+// in every level it missed.
+pub struct Hierarchy {
+    pub levels: Vec<Cache>,
+    pub accesses: u64,
+}
+
+impl Hierarchy {
+    pub fn new(configs: &[CacheConfig]) -> Self {
+        Self {
+            levels: configs.iter().map(|c| Cache::new(*c)).collect(),
+            accesses: 0,
+        }
+    }
+
+    pub fn access(&mut self, address: u64) {
+        self.accesses += 1;
+        let line_size = self.levels[0].config.line_size as u64;
+        let line = address / line_size;
+
+        for cache in self.levels.iter_mut() {
+            if cache.access(line) {
+                break;
+            }
+        }
+    }
+
+    // Touch every cache line of a contiguous region.
+    pub fn access_region(&mut self, address: u64, bytes: u64) {
+        let line_size = self.levels[0].config.line_size as u64;
+        let first = address / line_size;
+        let last = (address + bytes.max(1) - 1) / line_size;
+
+        for line in first..=last {
+            self.access(line * line_size);
+        }
+    }
+
+    pub fn replay(&mut self, trace: &Trace) {
+        for region in trace.regions.iter() {
+            self.access_region(region.address, region.bytes);
+        }
+    }
+
+    // (level, hits, misses) for each level
+    pub fn stats(&self) -> Vec<(usize, u64, u64)> {
+        self.levels
+            .iter()
+            .map(|c| (c.config.level, c.hits, c.misses))
+            .collect()
+    }
+}
+
+impl fmt::Display for Hierarchy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} line accesses", self.accesses)?;
+        for cache in self.levels.iter() {
+            let lookups = cache.hits + cache.misses;
+            let rate = if lookups > 0 {
+                100. * cache.misses as f64 / lookups as f64
+            } else {
+                0.
+            };
+            writeln!(
+                f,
+                "  L{} {:>8} KiB {:>2}-way: {:>12} misses {:>5.1}% of lookups",
+                cache.config.level,
+                cache.config.size >> 10,
+                cache.config.associativity,
+                cache.misses,
+                rate
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A contiguous read or write of an expansion buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub address: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    pub regions: Vec<Region>,
+}
+
+impl Trace {
+    fn push(&mut self, address: u64, bytes: usize) {
+        self.regions.push(Region {
+            address,
+            bytes: bytes as u64,
+        })
+    }
+
+    // In place update of a buffer, a read followed by a write of the same region.
+    fn update(&mut self, address: u64, bytes: usize) {
+        self.push(address, bytes);
+        self.push(address, bytes);
+    }
+
+    // Total bytes touched, counting repeated accesses
+    pub fn bytes(&self) -> u64 {
+        self.regions.iter().map(|r| r.bytes).sum()
+    }
+}
+
+const COMPLEX_BYTES: usize = 16;
+
+// Virtual address layout of the driver data, each map of buffers is contiguous with one buffer
+// per key in the order given, separated by a gap so that the maps don't share lines.
+struct Layout {
+    size_real: usize,
+    index: HashMap<MortonKey, usize>,
+    fft_base: u64,
+    ifft_base: u64,
+    kernel_base: u64,
+    result_base: u64,
+}
+
+impl Layout {
+    fn new(expansion_order: usize, keys: &[MortonKey]) -> Self {
+        let n = 2 * expansion_order - 1;
+        let &(m, n, o) = &(n, n, n);
+
+        let p = m + 1;
+        let q = n + 1;
+        let r = o + 1;
+        let size_real = p * q * (r / 2 + 1);
+
+        let index: HashMap<MortonKey, usize> =
+            keys.iter().enumerate().map(|(i, k)| (*k, i)).collect();
+
+        let page = 4096u64;
+        let align = |x: u64| x.div_ceil(page) * page + page;
+        let buffers = (keys.len() * size_real * COMPLEX_BYTES) as u64;
+
+        let fft_base = page;
+        let ifft_base = fft_base + align(buffers);
+        let kernel_base = ifft_base + align(buffers);
+        let result_base = kernel_base + align((16 * size_real * COMPLEX_BYTES) as u64);
+
+        Self {
+            size_real,
+            index,
+            fft_base,
+            ifft_base,
+            kernel_base,
+            result_base,
+        }
+    }
+
+    fn buffer_bytes(&self) -> usize {
+        self.size_real * COMPLEX_BYTES
+    }
+
+    fn fft(&self, key: &MortonKey) -> Option<u64> {
+        self.index
+            .get(key)
+            .map(|i| self.fft_base + (i * self.buffer_bytes()) as u64)
+    }
+
+    fn ifft(&self, key: &MortonKey) -> Option<u64> {
+        self.index
+            .get(key)
+            .map(|i| self.ifft_base + (i * self.buffer_bytes()) as u64)
+    }
+
+    fn kernel(&self, i: usize) -> u64 {
+        self.kernel_base + (i * self.buffer_bytes()) as u64
+    }
+
+    // Hadamard product of a sibling set, 16 kernels for each of 8 siblings
+    fn result(&self, k: usize, i: usize) -> u64 {
+        self.result_base + ((k * 16 + i) * self.buffer_bytes()) as u64
+    }
+}
+
+// Accesses of `m2l_naive_par`, each member of a key's interaction list is read and written.
+pub fn trace_m2l_naive(expansion_order: usize, keys: &[MortonKey]) -> Trace {
+    let layout = Layout::new(expansion_order, keys);
+    let bytes = layout.buffer_bytes();
+    let mut trace = Trace::default();
+
+    for key in keys.iter() {
+        let interaction_list = key
+            .parent()
+            .neighbors()
+            .iter()
+            .flat_map(|pn| pn.children())
+            .filter(|pnc| !key.is_adjacent_same_level(pnc))
+            .collect_vec();
+
+        for source in interaction_list.iter() {
+            if let Some(address) = layout.ifft(source) {
+                trace.update(address, bytes);
+            }
+        }
+    }
+
+    trace
+}
+
+// Hadamard product of a sibling set, as in `hadamard_product_naive`.
+fn trace_hadamard(layout: &Layout, children: &[MortonKey], trace: &mut Trace) {
+    let bytes = layout.buffer_bytes();
+
+    for i in 0..16 {
+        trace.push(layout.kernel(i), bytes);
+        for (k, child) in children.iter().enumerate() {
+            if let Some(address) = layout.fft(child) {
+                trace.push(address, bytes);
+            }
+            trace.push(layout.result(k, i), bytes);
+        }
+    }
+}
+
+// Scatter of a sibling set's Hadamard products to the halo of its parent.
+fn trace_scatter(
+    layout: &Layout,
+    children: &[MortonKey],
+    scatter_idxs: &[Vec<usize>],
+    trace: &mut Trace,
+) {
+    let bytes = layout.buffer_bytes();
+
+    let halo = children[0]
+        .parent()
+        .all_neighbors()
+        .iter()
+        .flat_map(|p| match p {
+            Some(p) => p.children().into_iter().map(Some).collect_vec(),
+            None => vec![None; 8],
+        })
+        .collect_vec();
+
+    for (i, pnc) in halo.iter().enumerate() {
+        let Some(address) = pnc.and_then(|pnc| layout.ifft(&pnc)) else {
+            continue;
+        };
+
+        for &sibling in scatter_idxs.get(i).map(|s| s.as_slice()).unwrap_or(&[]) {
+            trace.push(layout.result(sibling, 0), bytes);
+            trace.update(address, bytes);
+        }
+    }
+}
+
+// Accesses of `m2l_parent_par_naive` run on a single thread, each sibling set's Hadamard product is
+// scattered before moving on to the next. `keys` must be grouped into sibling sets.
+pub fn trace_m2l_parent(expansion_order: usize, keys: &[MortonKey]) -> Trace {
+    let layout = Layout::new(expansion_order, keys);
+    let scatter_idxs = scatter_displacements(Boundary::Free);
+    let mut trace = Trace::default();
+
+    for children in keys.chunks_exact(8) {
+        trace_hadamard(&layout, children, &mut trace);
+        trace_scatter(&layout, children, &scatter_idxs, &mut trace);
+    }
+
+    trace
+}
+
+// Accesses of the blocked `m2l_parent_par_simd` run on a single thread. Blocks of `block_size` keys
+// are taken in turn and, as in the driver, each sibling set of a block has its Hadamard product
+// scattered before the next is computed. Without concurrent workers the blocks only bound the
+// driver's prefetch window, so the trace matches `trace_m2l_parent` for whole sibling sets.
+pub fn trace_m2l_blocked(expansion_order: usize, keys: &[MortonKey], block_size: usize) -> Trace {
+    assert!(
+        block_size > 0 && block_size % 8 == 0,
+        "block size must be a positive multiple of 8 keys"
+    );
+
+    let layout = Layout::new(expansion_order, keys);
+    let scatter_idxs = scatter_displacements(Boundary::Free);
+    let mut trace = Trace::default();
+
+    for block in keys.chunks(block_size) {
+        for children in block.chunks_exact(8) {
+            trace_hadamard(&layout, children, &mut trace);
+            trace_scatter(&layout, children, &scatter_idxs, &mut trace);
+        }
+    }
+
+    trace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::helpers::{full_octree_keys, unit_domain};
+
+    fn config(level: usize, lines: usize, associativity: usize) -> CacheConfig {
+        CacheConfig {
+            level,
+            size: 64 * lines,
+            line_size: 64,
+            associativity,
+        }
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        // A single set of two ways
+        let mut cache = Cache::new(config(1, 2, 2));
+        let hits = [0, 1, 0, 2, 1].map(|line| cache.access(line));
+        assert_eq!(hits, [false, false, true, false, false]);
+        assert_eq!((cache.hits, cache.misses), (1, 4));
+
+        // Two direct mapped sets, even lines conflict while odd lines don't
+        let mut cache = Cache::new(config(1, 2, 1));
+        let hits = [0, 2, 1, 0, 1].map(|line| cache.access(line));
+        assert_eq!(hits, [false, false, false, false, true]);
+        assert_eq!((cache.hits, cache.misses), (1, 4));
+    }
+
+    #[test]
+    fn hierarchy_looks_up_missed_lines_in_the_next_level() {
+        let mut hierarchy = Hierarchy::new(&[config(1, 1, 1), config(2, 4, 4)]);
+
+        // Two lines, the second evicting the first from L1 but not L2
+        hierarchy.access_region(0, 128);
+        hierarchy.access(0);
+
+        assert_eq!(hierarchy.accesses, 3);
+        assert_eq!(hierarchy.stats(), vec![(1, 0, 3), (2, 1, 2)]);
+    }
+
+    #[test]
+    fn naive_reads_and_writes_each_source() {
+        // Two keys at level 3 in each other's interaction lists, 3 boxes apart. At order 2 each
+        // buffer is 48 complex doubles, 12 lines.
+        let domain = unit_domain();
+        let a = MortonKey::from_point(&[0.0625, 0.0625, 0.0625], &domain, 3);
+        let b = MortonKey::from_point(&[0.4375, 0.0625, 0.0625], &domain, 3);
+
+        let trace = trace_m2l_naive(2, &[a, b]);
+        assert_eq!(trace.regions.len(), 4);
+        assert_eq!(trace.regions[0], trace.regions[1]);
+        assert_eq!(trace.regions[2], trace.regions[3]);
+        assert_eq!(trace.bytes(), 4 * 768);
+
+        // With room for 16 lines each buffer is written back while still cached
+        let mut hierarchy = Hierarchy::new(&[config(1, 16, 16)]);
+        hierarchy.replay(&trace);
+        assert_eq!(hierarchy.stats(), vec![(1, 24, 24)]);
+
+        // With room for 8 the write streams through the buffer after the read has evicted its start
+        let mut hierarchy = Hierarchy::new(&[config(1, 8, 8)]);
+        hierarchy.replay(&trace);
+        assert_eq!(hierarchy.stats(), vec![(1, 0, 48)]);
+    }
+
+    #[test]
+    fn blocked_scatters_each_sibling_set_before_the_next_product() {
+        let mut keys: Vec<MortonKey> = full_octree_keys(2).into_iter().collect();
+        keys.sort();
+
+        let layout = Layout::new(2, &keys);
+        let trace = trace_m2l_blocked(2, &keys, 16);
+
+        // 16 kernels, each followed by the signal and product of the 8 siblings
+        let products = 16 * (1 + 2 * 8);
+        assert_eq!(trace.regions[0].address, layout.kernel(0));
+        assert_eq!(trace.regions[1].address, layout.fft(&keys[0]).unwrap());
+        assert_eq!(trace.regions[2].address, layout.result(0, 0));
+
+        // The scatter of the first sibling set reads its products before the second set's
+        // kernels are loaded
+        assert!(trace.regions[products].address >= layout.result_base);
+        assert_eq!(
+            trace.regions.iter().filter(|r| r.address == layout.kernel(0)).count(),
+            keys.len() / 8
+        );
+
+        assert_eq!(trace.regions, trace_m2l_parent(2, &keys).regions);
+    }
+
+    #[test]
+    #[should_panic(expected = "block size must be a positive multiple of 8 keys")]
+    fn blocked_rejects_partial_sibling_sets() {
+        let mut keys: Vec<MortonKey> = full_octree_keys(2).into_iter().collect();
+        keys.sort();
+
+        trace_m2l_blocked(2, &keys, 12);
+    }
+}
@@ -0,0 +1,129 @@
+//! Seeded point distributions for benchmark trees, from near uniform clouds to the surface meshes
+//! and clustered particles that produce adaptive, load imbalanced trees.
+use std::f64::consts::PI;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::helpers::points_uniform;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distribution {
+    // Uniform in the unit cube
+    Uniform,
+    // Uniform on the surface of the sphere inscribed in the unit cube
+    SphereSurface,
+    // Plummer model, a single cluster with a dense core and long tails
+    Plummer,
+    // Isotropic Gaussian clusters of varying width at random centres
+    GaussianMixture,
+}
+
+// Plummer tails are truncated at PLUMMER_CUTOFF Plummer radii, which are scaled to the half width
+// of the unit cube.
+const PLUMMER_CUTOFF: f64 = 9.;
+
+const NCLUSTERS: usize = 8;
+
+// Standard normal variate by the Box-Muller transform.
+fn normal(rng: &mut StdRng) -> f64 {
+    let u1: f64 = 1. - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    (-2. * u1.ln()).sqrt() * (2. * PI * u2).cos()
+}
+
+// Uniformly distributed direction.
+fn direction(rng: &mut StdRng) -> [f64; 3] {
+    loop {
+        let d = [normal(rng), normal(rng), normal(rng)];
+        let norm = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+        if norm > 0. {
+            return d.map(|x| x / norm);
+        }
+    }
+}
+
+// Collect points into an (npoints, 3) column major array of coordinates.
+fn column_major(points: &[[f64; 3]]) -> Vec<f64> {
+    let npoints = points.len();
+    let mut result = vec![0f64; 3 * npoints];
+
+    for (i, p) in points.iter().enumerate() {
+        for (j, x) in p.iter().enumerate() {
+            result[j * npoints + i] = *x;
+        }
+    }
+
+    result
+}
+
+fn sphere_surface(npoints: usize, rng: &mut StdRng) -> Vec<[f64; 3]> {
+    (0..npoints)
+        .map(|_| direction(rng).map(|x| 0.5 + 0.5 * x))
+        .collect()
+}
+
+// Radii, in Plummer radii, sampled from the inverse of the Plummer cumulative mass profile.
+fn plummer(npoints: usize, rng: &mut StdRng) -> Vec<[f64; 3]> {
+    let scale = 0.5 / PLUMMER_CUTOFF;
+
+    (0..npoints)
+        .map(|_| {
+            let r = loop {
+                let x: f64 = rng.gen();
+                let r = 1. / (x.powf(-2. / 3.) - 1.).sqrt();
+                if r.is_finite() && r < PLUMMER_CUTOFF {
+                    break r;
+                }
+            };
+
+            direction(rng).map(|x| 0.5 + scale * r * x)
+        })
+        .collect()
+}
+
+// Clusters with random centres, widths and weights, points falling outside the unit cube are
+// redrawn.
+fn gaussian_mixture(npoints: usize, rng: &mut StdRng) -> Vec<[f64; 3]> {
+    let clusters: Vec<([f64; 3], f64, f64)> = (0..NCLUSTERS)
+        .map(|_| {
+            let centre = [0.; 3].map(|_: f64| rng.gen_range(0.15..0.85));
+            let sigma = rng.gen_range(0.01..0.08);
+            let weight = rng.gen_range(0.2..1.0);
+            (centre, sigma, weight)
+        })
+        .collect();
+
+    let total: f64 = clusters.iter().map(|c| c.2).sum();
+
+    (0..npoints)
+        .map(|_| {
+            let mut u = rng.gen::<f64>() * total;
+            let &(centre, sigma, _) = clusters
+                .iter()
+                .find(|c| {
+                    u -= c.2;
+                    u <= 0.
+                })
+                .unwrap_or(&clusters[NCLUSTERS - 1]);
+
+            loop {
+                let p = centre.map(|c| c + sigma * normal(rng));
+                if p.iter().all(|&x| (0. ..1.).contains(&x)) {
+                    break p;
+                }
+            }
+        })
+        .collect()
+}
+
+// Points within the unit cube, as an (npoints, 3) column major array of coordinates.
+pub fn points(distribution: Distribution, npoints: usize, seed: u64) -> Vec<f64> {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    match distribution {
+        Distribution::Uniform => points_uniform(npoints, seed),
+        Distribution::SphereSurface => column_major(&sphere_surface(npoints, &mut rng)),
+        Distribution::Plummer => column_major(&plummer(npoints, &mut rng)),
+        Distribution::GaussianMixture => column_major(&gaussian_mixture(npoints, &mut rng)),
+    }
+}
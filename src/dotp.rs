@@ -1,101 +1,276 @@
-use std::simd::*;
+use std::{
+    error::Error,
+    fmt,
+    ops::{Add, Mul},
+    simd::*,
+};
 
-#[cfg(all(target_arch = "x86_64", feature = "avx2"))] 
-mod x86 {
-    use std::arc::x86_64::*;
-    use rayon::prelude::*;
-    
-    pub fn dotp_naive_f64(x: &[f64], y: &[f64], z: &mut [f64]) {
-        for ((a, b), c) in x
-            .chunks_exact(4)
-            .zip(y.chunks_exact(4))
-            .zip(z.chunks_exact_mut(4))
-        {
-            // assert_eq!(a.len(), b.len());
-            // assert_eq!(c.len(), b.len());
+use rayon::prelude::*;
+
+use crate::{aligned::is_aligned, helpers::BLOCK_SIZE};
+
+// Lengths of the inputs to an elementwise kernel, which must all match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LengthMismatch {
+    pub x: usize,
+    pub y: usize,
+    pub z: usize,
+}
+
+impl fmt::Display for LengthMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "input lengths don't match: x {}, y {}, z {}",
+            self.x, self.y, self.z
+        )
+    }
+}
 
-            // for i in 0..c.len() {
-            //     c[i] += a[i] * b[i];
-            // }
+impl Error for LengthMismatch {}
 
-            c.iter_mut()
-                .zip(a.iter())
-                .zip(b.iter())
-                .for_each(|((c, a), b)| *c = *a * b);
+// Inputs an elementwise kernel can't process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DotpError {
+    Length(LengthMismatch),
+    // The aligned kernels need every input to start on an `align` byte boundary
+    Misaligned { align: usize },
+}
+
+impl fmt::Display for DotpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DotpError::Length(e) => e.fmt(f),
+            DotpError::Misaligned { align } => write!(f, "inputs must be {} byte aligned", align),
         }
     }
+}
 
-    #[inline(never)]
-    pub fn dotp_simd_f64(x: &[f64], y: &[f64], z: &mut [f64]) {
+impl Error for DotpError {}
+
+impl From<LengthMismatch> for DotpError {
+    fn from(e: LengthMismatch) -> Self {
+        DotpError::Length(e)
+    }
+}
+
+pub type DotpFn<T> = fn(&[T], &[T], &mut [T]) -> Result<(), DotpError>;
+
+pub fn check_lengths<T>(x: &[T], y: &[T], z: &[T]) -> Result<(), LengthMismatch> {
+    if x.len() == y.len() && y.len() == z.len() {
+        Ok(())
+    } else {
+        Err(LengthMismatch {
+            x: x.len(),
+            y: y.len(),
+            z: z.len(),
+        })
+    }
+}
+
+// Whether x, y and z all start on an `align` byte boundary, as the aligned kernels require.
+pub fn check_aligned<T>(x: &[T], y: &[T], z: &[T], align: usize) -> Result<(), DotpError> {
+    if is_aligned(x.as_ptr(), align)
+        && is_aligned(y.as_ptr(), align)
+        && is_aligned(z.as_ptr(), align)
+    {
+        Ok(())
+    } else {
+        Err(DotpError::Misaligned { align })
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+pub mod x86 {
+    use rayon::prelude::*;
+    use std::arch::x86_64::*;
+
+    use super::{check_aligned, check_lengths, DotpError};
+    use crate::helpers::BLOCK_SIZE;
+
+    pub fn dotp_naive_f64(x: &[f64], y: &[f64], z: &mut [f64]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+
+        z.iter_mut()
+            .zip(x.iter())
+            .zip(y.iter())
+            .for_each(|((c, a), b)| *c = *a * b);
+
+        Ok(())
+    }
+
+    // Masks selecting the first n lanes, for loading and storing the tail of an array.
+    unsafe fn tail_mask_pd(n: usize) -> __m256i {
+        _mm256_cmpgt_epi64(_mm256_set1_epi64x(n as i64), _mm256_setr_epi64x(0, 1, 2, 3))
+    }
+
+    unsafe fn tail_mask_ps(n: usize) -> __m256i {
+        _mm256_cmpgt_epi32(
+            _mm256_set1_epi32(n as i32),
+            _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7),
+        )
+    }
+
+    // z = x * y + z over inputs of equal length, the tail is processed with masked loads and
+    // stores, which have no alignment requirement.
+    fn fma_f64<const ALIGNED: bool>(x: &[f64], y: &[f64], z: &mut [f64]) {
         let chunk_size = 4;
         for ((a, b), c) in x
-            .chunks_exact(chunk_size)
-            .zip(y.chunks_exact(chunk_size))
-            .zip(z.chunks_exact_mut(chunk_size))
+            .chunks(chunk_size)
+            .zip(y.chunks(chunk_size))
+            .zip(z.chunks_mut(chunk_size))
         {
             unsafe {
-                let x_a = _mm256_loadu_pd(a.as_ptr());
-                let y_a = _mm256_loadu_pd(b.as_ptr());
-                let r_a = _mm256_loadu_pd(c.as_ptr());
-
-                // let tmp = _mm256_mul_pd(x_a, y_a);
-                // let tmp2 = _mm256_add_pd(tmp, r_a);
-                _mm256_storeu_pd(c.as_mut_ptr(), _mm256_fmadd_pd(x_a, y_a, r_a));
-                // _mm256_storeu_pd(c.as_mut_ptr(), tmp2);
+                if c.len() == chunk_size && ALIGNED {
+                    let x_a = _mm256_load_pd(a.as_ptr());
+                    let y_a = _mm256_load_pd(b.as_ptr());
+                    let r_a = _mm256_load_pd(c.as_ptr());
+
+                    _mm256_store_pd(c.as_mut_ptr(), _mm256_fmadd_pd(x_a, y_a, r_a));
+                } else if c.len() == chunk_size {
+                    let x_a = _mm256_loadu_pd(a.as_ptr());
+                    let y_a = _mm256_loadu_pd(b.as_ptr());
+                    let r_a = _mm256_loadu_pd(c.as_ptr());
+
+                    // let tmp = _mm256_mul_pd(x_a, y_a);
+                    // let tmp2 = _mm256_add_pd(tmp, r_a);
+                    _mm256_storeu_pd(c.as_mut_ptr(), _mm256_fmadd_pd(x_a, y_a, r_a));
+                    // _mm256_storeu_pd(c.as_mut_ptr(), tmp2);
+                } else {
+                    let mask = tail_mask_pd(c.len());
+                    let x_a = _mm256_maskload_pd(a.as_ptr(), mask);
+                    let y_a = _mm256_maskload_pd(b.as_ptr(), mask);
+                    let r_a = _mm256_maskload_pd(c.as_ptr(), mask);
+
+                    _mm256_maskstore_pd(c.as_mut_ptr(), mask, _mm256_fmadd_pd(x_a, y_a, r_a));
+                }
             }
         }
     }
 
-    #[inline(never)]
-    pub fn dotp_simd_f32(x: &[f32], y: &[f32], z: &mut [f32]) {
+    fn fma_f32<const ALIGNED: bool>(x: &[f32], y: &[f32], z: &mut [f32]) {
+        let chunk_size = 8;
         for ((a, b), c) in x
-            .chunks_exact(8)
-            .zip(y.chunks_exact(8))
-            .zip(z.chunks_exact_mut(8))
+            .chunks(chunk_size)
+            .zip(y.chunks(chunk_size))
+            .zip(z.chunks_mut(chunk_size))
         {
             unsafe {
-                let x_a = _mm256_loadu_ps(a.as_ptr());
-                let y_a = _mm256_loadu_ps(b.as_ptr());
-                let r_a = _mm256_loadu_ps(c.as_ptr());
+                if c.len() == chunk_size && ALIGNED {
+                    let x_a = _mm256_load_ps(a.as_ptr());
+                    let y_a = _mm256_load_ps(b.as_ptr());
+                    let r_a = _mm256_load_ps(c.as_ptr());
+
+                    _mm256_store_ps(c.as_mut_ptr(), _mm256_fmadd_ps(x_a, y_a, r_a));
+                } else if c.len() == chunk_size {
+                    let x_a = _mm256_loadu_ps(a.as_ptr());
+                    let y_a = _mm256_loadu_ps(b.as_ptr());
+                    let r_a = _mm256_loadu_ps(c.as_ptr());
 
-                _mm256_storeu_ps(c.as_mut_ptr(), _mm256_fmadd_ps(x_a, y_a, r_a));
+                    _mm256_storeu_ps(c.as_mut_ptr(), _mm256_fmadd_ps(x_a, y_a, r_a));
+                } else {
+                    let mask = tail_mask_ps(c.len());
+                    let x_a = _mm256_maskload_ps(a.as_ptr(), mask);
+                    let y_a = _mm256_maskload_ps(b.as_ptr(), mask);
+                    let r_a = _mm256_maskload_ps(c.as_ptr(), mask);
+
+                    _mm256_maskstore_ps(c.as_mut_ptr(), mask, _mm256_fmadd_ps(x_a, y_a, r_a));
+                }
             }
         }
     }
 
     #[inline(never)]
-    pub fn dotp_simd_f32_par(x: &[f32], y: &[f32], z: &mut [f32]) {
-        let chunk_size = 8;
-        x.par_chunks_exact(chunk_size)
-            .zip(y.par_chunks_exact(chunk_size))
-            .zip(z.par_chunks_exact_mut(chunk_size))
-            .for_each(|((a, b), c)| unsafe {
-                let x_a = _mm256_loadu_ps(a.as_ptr());
-                let y_a = _mm256_loadu_ps(b.as_ptr());
-                let r_a = _mm256_loadu_ps(c.as_ptr());
+    pub fn dotp_simd_f64(x: &[f64], y: &[f64], z: &mut [f64]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+        fma_f64::<false>(x, y, z);
+        Ok(())
+    }
 
-                _mm256_storeu_ps(c.as_mut_ptr(), _mm256_fmadd_ps(x_a, y_a, r_a));
-            });
+    // As `dotp_simd_f64` with aligned loads and stores, fails unless x, y and z are 32 byte
+    // aligned.
+    #[inline(never)]
+    pub fn dotp_simd_f64_aligned(x: &[f64], y: &[f64], z: &mut [f64]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+        check_aligned(x, y, z, 32)?;
+        fma_f64::<true>(x, y, z);
+        Ok(())
     }
 
     #[inline(never)]
-    pub fn dotp_simd_f64_par(x: &[f64], y: &[f64], z: &mut [f64]) {
-        let chunk_size = 4;
-        x.par_chunks_exact(chunk_size)
-            .zip(y.par_chunks_exact(chunk_size))
-            .zip(z.par_chunks_exact_mut(chunk_size))
-            .for_each(|((a, b), c)| unsafe {
-                let x_a = _mm256_loadu_pd(a.as_ptr());
-                let y_a = _mm256_loadu_pd(b.as_ptr());
-                let r_a = _mm256_loadu_pd(c.as_ptr());
+    pub fn dotp_simd_f32(x: &[f32], y: &[f32], z: &mut [f32]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+        fma_f32::<false>(x, y, z);
+        Ok(())
+    }
+
+    #[inline(never)]
+    pub fn dotp_simd_f32_aligned(x: &[f32], y: &[f32], z: &mut [f32]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+        check_aligned(x, y, z, 32)?;
+        fma_f32::<true>(x, y, z);
+        Ok(())
+    }
+
+    // Each rayon task takes BLOCK_SIZE elements, only the last block has a tail.
+    #[inline(never)]
+    pub fn dotp_simd_f32_par(x: &[f32], y: &[f32], z: &mut [f32]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+
+        x.par_chunks(BLOCK_SIZE)
+            .zip(y.par_chunks(BLOCK_SIZE))
+            .zip(z.par_chunks_mut(BLOCK_SIZE))
+            .for_each(|((a, b), c)| fma_f32::<false>(a, b, c));
 
-                _mm256_storeu_pd(c.as_mut_ptr(), _mm256_fmadd_pd(x_a, y_a, r_a));
-            });
+        Ok(())
     }
 
+    // Blocks of BLOCK_SIZE elements keep the alignment of the start of the arrays.
     #[inline(never)]
-    pub fn dotp_no_simd_bounds_f32(x: &[f32], y: &[f32], z: &mut [f32]) {
-        for idx in 0..x.len() / 8 {
+    pub fn dotp_simd_f32_par_aligned(x: &[f32], y: &[f32], z: &mut [f32]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+        check_aligned(x, y, z, 32)?;
+
+        x.par_chunks(BLOCK_SIZE)
+            .zip(y.par_chunks(BLOCK_SIZE))
+            .zip(z.par_chunks_mut(BLOCK_SIZE))
+            .for_each(|((a, b), c)| fma_f32::<true>(a, b, c));
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    pub fn dotp_simd_f64_par(x: &[f64], y: &[f64], z: &mut [f64]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+
+        x.par_chunks(BLOCK_SIZE)
+            .zip(y.par_chunks(BLOCK_SIZE))
+            .zip(z.par_chunks_mut(BLOCK_SIZE))
+            .for_each(|((a, b), c)| fma_f64::<false>(a, b, c));
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    pub fn dotp_simd_f64_par_aligned(x: &[f64], y: &[f64], z: &mut [f64]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+        check_aligned(x, y, z, 32)?;
+
+        x.par_chunks(BLOCK_SIZE)
+            .zip(y.par_chunks(BLOCK_SIZE))
+            .zip(z.par_chunks_mut(BLOCK_SIZE))
+            .for_each(|((a, b), c)| fma_f64::<true>(a, b, c));
+
+        Ok(())
+    }
+
+    // Unchecked indexing is sound once the lengths are validated.
+    #[inline(never)]
+    pub fn dotp_no_simd_bounds_f32(x: &[f32], y: &[f32], z: &mut [f32]) -> Result<(), DotpError> {
+        check_lengths(x, y, z)?;
+
+        let nchunks = x.len() / 8;
+        for idx in 0..nchunks {
             unsafe {
                 let (a, b, c) = (
                     x.get_unchecked(idx * 8),
@@ -108,29 +283,36 @@ mod x86 {
                 _mm256_storeu_ps(c, _mm256_fmadd_ps(x_a, y_a, r_a));
             }
         }
+
+        let tail = nchunks * 8;
+        fma_f32::<false>(&x[tail..], &y[tail..], &mut z[tail..]);
+
+        Ok(())
     }
 }
 
-pub fn dotp_naive_f32(x: &[f32], y: &[f32], z: &mut [f32]) {
-    for ((a, b), c) in x
-        .chunks_exact(8)
-        .zip(y.chunks_exact(8))
-        .zip(z.chunks_exact_mut(8))
-    {
+pub fn dotp_naive_f32(x: &[f32], y: &[f32], z: &mut [f32]) -> Result<(), DotpError> {
+    check_lengths(x, y, z)?;
 
-        c.iter_mut()
-            .zip(a.iter())
-            .zip(b.iter())
-            .for_each(|((c, a), b)| *c = *a * b);
-    }
+    z.iter_mut()
+        .zip(x.iter())
+        .zip(y.iter())
+        .for_each(|((c, a), b)| *c = *a * b);
+
+    Ok(())
 }
 
-pub fn dotp_simd_f32_portable(x: &[f32], y: &[f32], z: &mut [f32]) {
-    x.array_chunks::<8>()
+// The last len % 8 elements are handled by a scalar epilogue.
+pub fn dotp_simd_f32_portable(x: &[f32], y: &[f32], z: &mut [f32]) -> Result<(), DotpError> {
+    check_lengths(x, y, z)?;
+    let body = x.len() - x.len() % 8;
+
+    x[..body]
+        .array_chunks::<8>()
         .map(|&a| f32x8::from_array(a))
-        .zip(y.array_chunks::<8>().map(|&b| f32x8::from_array(b)))
+        .zip(y[..body].array_chunks::<8>().map(|&b| f32x8::from_array(b)))
         .zip(
-            z.array_chunks_mut::<8>(), // .map(|&mut c| f32x8::from_array(c))
+            z[..body].array_chunks_mut::<8>(), // .map(|&mut c| f32x8::from_array(c))
         )
         .for_each(|((a, b), c)| {
             let mut c_s = f32x8::splat(0.0);
@@ -139,13 +321,23 @@ pub fn dotp_simd_f32_portable(x: &[f32], y: &[f32], z: &mut [f32]) {
             let idxs = Simd::from_array([0, 1, 2, 3, 4, 5, 6, 7]);
             c_s.scatter(c, idxs);
         });
+
+    for ((a, b), c) in x[body..].iter().zip(&y[body..]).zip(&mut z[body..]) {
+        *c = *a * *b;
+    }
+
+    Ok(())
 }
 
-pub fn dotp_simd_f64_portable(x: &[f64], y: &[f64], z: &mut [f64]) {
-    x.array_chunks::<4>()
+pub fn dotp_simd_f64_portable(x: &[f64], y: &[f64], z: &mut [f64]) -> Result<(), DotpError> {
+    check_lengths(x, y, z)?;
+    let body = x.len() - x.len() % 4;
+
+    x[..body]
+        .array_chunks::<4>()
         .map(|&a| f64x4::from_array(a))
-        .zip(y.array_chunks::<4>().map(|&b| f64x4::from_array(b)))
-        .zip(z.array_chunks_mut::<4>())
+        .zip(y[..body].array_chunks::<4>().map(|&b| f64x4::from_array(b)))
+        .zip(z[..body].array_chunks_mut::<4>())
         .for_each(|((a, b), c)| {
             let mut c_s = f64x4::splat(0.0);
             c_s = a.mul_add(b, c_s);
@@ -153,4 +345,304 @@ pub fn dotp_simd_f64_portable(x: &[f64], y: &[f64], z: &mut [f64]) {
             let idxs = Simd::from_array([0, 1, 2, 3]);
             c_s.scatter(c, idxs);
         });
+
+    for ((a, b), c) in x[body..].iter().zip(&y[body..]).zip(&mut z[body..]) {
+        *c = *a * *b;
+    }
+
+    Ok(())
+}
+
+// As `dotp_simd_f32_portable`, with aligned loads and stores of whole vectors, fails unless x, y
+// and z are aligned to the vector width. Inputs of the same alignment and length are split alike
+// by `as_simd`, which may still leave any prefix or suffix to be processed as scalars.
+pub fn dotp_simd_f32_portable_aligned(
+    x: &[f32],
+    y: &[f32],
+    z: &mut [f32],
+) -> Result<(), DotpError> {
+    check_lengths(x, y, z)?;
+    check_aligned(x, y, z, std::mem::align_of::<f32x8>())?;
+
+    let (x_p, x_s, x_t) = x.as_simd::<8>();
+    let (y_p, y_s, y_t) = y.as_simd::<8>();
+    let (z_p, z_s, z_t) = z.as_simd_mut::<8>();
+    assert!(x_p.len() == y_p.len() && y_p.len() == z_p.len());
+
+    for ((a, b), c) in x_p.iter().zip(y_p).zip(z_p.iter_mut()) {
+        *c = *a * *b;
+    }
+
+    for ((a, b), c) in x_s.iter().zip(y_s).zip(z_s.iter_mut()) {
+        *c = a * b;
+    }
+
+    for ((a, b), c) in x_t.iter().zip(y_t).zip(z_t.iter_mut()) {
+        *c = *a * *b;
+    }
+
+    Ok(())
+}
+
+pub fn dotp_simd_f64_portable_aligned(
+    x: &[f64],
+    y: &[f64],
+    z: &mut [f64],
+) -> Result<(), DotpError> {
+    check_lengths(x, y, z)?;
+    check_aligned(x, y, z, std::mem::align_of::<f64x4>())?;
+
+    let (x_p, x_s, x_t) = x.as_simd::<4>();
+    let (y_p, y_s, y_t) = y.as_simd::<4>();
+    let (z_p, z_s, z_t) = z.as_simd_mut::<4>();
+    assert!(x_p.len() == y_p.len() && y_p.len() == z_p.len());
+
+    for ((a, b), c) in x_p.iter().zip(y_p).zip(z_p.iter_mut()) {
+        *c = *a * *b;
+    }
+
+    for ((a, b), c) in x_s.iter().zip(y_s).zip(z_s.iter_mut()) {
+        *c = a * b;
+    }
+
+    for ((a, b), c) in x_t.iter().zip(y_t).zip(z_t.iter_mut()) {
+        *c = *a * *b;
+    }
+
+    Ok(())
+}
+
+// z = x * y + z over inputs of equal length, the last len % LANES elements are handled by a
+// scalar epilogue.
+fn fma_simd_unchecked<T, const LANES: usize>(x: &[T], y: &[T], z: &mut [T])
+where
+    T: SimdElement + Mul<Output = T> + Add<Output = T>,
+    LaneCount<LANES>: SupportedLaneCount,
+    Simd<T, LANES>: StdFloat,
+{
+    let n = z.len();
+    let body = n - n % LANES;
+
+    for ((a, b), c) in x[..body]
+        .chunks_exact(LANES)
+        .zip(y[..body].chunks_exact(LANES))
+        .zip(z[..body].chunks_exact_mut(LANES))
+    {
+        let a = Simd::<T, LANES>::from_slice(a);
+        let b = Simd::<T, LANES>::from_slice(b);
+        let r = Simd::<T, LANES>::from_slice(c);
+        a.mul_add(b, r).copy_to_slice(c);
+    }
+
+    for ((a, b), c) in x[body..n].iter().zip(&y[body..n]).zip(&mut z[body..n]) {
+        *c = *a * *b + *c;
+    }
+}
+
+// Elementwise z = x * y + z for any float element type and lane width.
+pub fn fma_simd<T, const LANES: usize>(x: &[T], y: &[T], z: &mut [T]) -> Result<(), DotpError>
+where
+    T: SimdElement + Mul<Output = T> + Add<Output = T>,
+    LaneCount<LANES>: SupportedLaneCount,
+    Simd<T, LANES>: StdFloat,
+{
+    check_lengths(x, y, z)?;
+    fma_simd_unchecked::<T, LANES>(x, y, z);
+    Ok(())
+}
+
+// Parallel `fma_simd`, each rayon task takes BLOCK_SIZE elements.
+pub fn fma_simd_par<T, const LANES: usize>(x: &[T], y: &[T], z: &mut [T]) -> Result<(), DotpError>
+where
+    T: SimdElement + Mul<Output = T> + Add<Output = T> + Send + Sync,
+    LaneCount<LANES>: SupportedLaneCount,
+    Simd<T, LANES>: StdFloat,
+{
+    check_lengths(x, y, z)?;
+
+    x.par_chunks(BLOCK_SIZE)
+        .zip(y.par_chunks(BLOCK_SIZE))
+        .zip(z.par_chunks_mut(BLOCK_SIZE))
+        .for_each(|((a, b), c)| fma_simd_unchecked::<T, LANES>(a, b, c));
+
+    Ok(())
+}
+
+// `fma_simd` for a lane width chosen at runtime, for sweeping lane widths. None for widths other
+// than 2, 4, 8 or 16.
+pub fn fma_simd_lanes<T>(lanes: usize, parallel: bool) -> Option<DotpFn<T>>
+where
+    T: SimdElement + Mul<Output = T> + Add<Output = T> + Send + Sync,
+    Simd<T, 2>: StdFloat,
+    Simd<T, 4>: StdFloat,
+    Simd<T, 8>: StdFloat,
+    Simd<T, 16>: StdFloat,
+{
+    match (lanes, parallel) {
+        (2, false) => Some(fma_simd::<T, 2>),
+        (4, false) => Some(fma_simd::<T, 4>),
+        (8, false) => Some(fma_simd::<T, 8>),
+        (16, false) => Some(fma_simd::<T, 16>),
+        (2, true) => Some(fma_simd_par::<T, 2>),
+        (4, true) => Some(fma_simd_par::<T, 4>),
+        (8, true) => Some(fma_simd_par::<T, 8>),
+        (16, true) => Some(fma_simd_par::<T, 16>),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use num::Float;
+
+    use super::{
+        check_lengths, dotp_naive_f32, dotp_simd_f32_portable, dotp_simd_f32_portable_aligned,
+        dotp_simd_f64_portable, dotp_simd_f64_portable_aligned, fma_simd_lanes, DotpError, DotpFn,
+        LengthMismatch,
+    };
+    use crate::{aligned::AlignedVec, helpers::BLOCK_SIZE};
+
+    // Empty and single element inputs, either side of one and two vectors, and either side of a
+    // parallel block.
+    fn lengths(lanes: usize) -> Vec<usize> {
+        let mut lengths = vec![
+            0,
+            1,
+            lanes - 1,
+            lanes,
+            lanes + 1,
+            2 * lanes - 1,
+            2 * lanes + 1,
+            BLOCK_SIZE - 1,
+            BLOCK_SIZE + lanes + 1,
+        ];
+        lengths.sort();
+        lengths.dedup();
+        lengths
+    }
+
+    fn data<T: Float>(n: usize, seed: usize) -> AlignedVec<T> {
+        (0..n)
+            .map(|i| T::from(((7 * i + seed) as f64 * 0.61).sin()).unwrap())
+            .collect()
+    }
+
+    // Compare `kernel` with the scalar z = x * y, or z = x * y + z if `fma`, over aligned inputs
+    // of each length, and check that it rejects inputs of different lengths.
+    fn assert_matches_scalar<T: Float + fmt::Debug>(kernel: DotpFn<T>, fma: bool, lanes: usize) {
+        let tol = T::epsilon() * T::from(4.).unwrap();
+
+        for n in lengths(lanes) {
+            let x = data::<T>(n, 1);
+            let y = data::<T>(n, 2);
+            let z0 = data::<T>(n, 3);
+
+            let mut z = z0.clone();
+            kernel(&x, &y, &mut z).unwrap();
+
+            for i in 0..n {
+                let expected = if fma {
+                    x[i] * y[i] + z0[i]
+                } else {
+                    x[i] * y[i]
+                };
+                assert!(
+                    (z[i] - expected).abs() <= tol,
+                    "n {} i {}: {:?} != {:?}",
+                    n,
+                    i,
+                    z[i],
+                    expected
+                );
+            }
+
+            let long = data::<T>(n + 1, 2);
+            assert_eq!(
+                kernel(&x, &long, &mut z),
+                Err(DotpError::Length(LengthMismatch {
+                    x: n,
+                    y: n + 1,
+                    z: n
+                }))
+            );
+        }
+    }
+
+    #[test]
+    fn check_lengths_reports_each_length() {
+        assert_eq!(check_lengths::<f64>(&[], &[], &[]), Ok(()));
+        assert_eq!(
+            check_lengths(&[1.], &[1., 2.], &[0.; 3]),
+            Err(LengthMismatch { x: 1, y: 2, z: 3 })
+        );
+    }
+
+    #[test]
+    fn portable_kernels_match_scalar() {
+        assert_matches_scalar(dotp_naive_f32, false, 8);
+        assert_matches_scalar(dotp_simd_f32_portable, false, 8);
+        assert_matches_scalar(dotp_simd_f64_portable, false, 4);
+        assert_matches_scalar(dotp_simd_f32_portable_aligned, false, 8);
+        assert_matches_scalar(dotp_simd_f64_portable_aligned, false, 4);
+    }
+
+    // Offset one input by an element, so that it's misaligned however wide the vectors are.
+    fn assert_rejects_misaligned<T: Float + fmt::Debug>(kernel: DotpFn<T>, align: usize) {
+        let x = data::<T>(33, 1);
+        let y = data::<T>(33, 2);
+        let mut z = data::<T>(33, 3);
+
+        assert_eq!(
+            kernel(&x[1..], &y[..32], &mut z[..32]),
+            Err(DotpError::Misaligned { align })
+        );
+        assert_eq!(
+            kernel(&x[..32], &y[..32], &mut z[1..]),
+            Err(DotpError::Misaligned { align })
+        );
+        assert_eq!(z, data::<T>(33, 3));
+    }
+
+    #[test]
+    fn aligned_kernels_reject_misaligned_inputs() {
+        assert_rejects_misaligned(dotp_simd_f32_portable_aligned, 32);
+        assert_rejects_misaligned(dotp_simd_f64_portable_aligned, 32);
+    }
+
+    #[test]
+    fn fma_simd_matches_scalar() {
+        for lanes in [2, 4, 8, 16] {
+            for parallel in [false, true] {
+                assert_matches_scalar(fma_simd_lanes::<f32>(lanes, parallel).unwrap(), true, lanes);
+                assert_matches_scalar(fma_simd_lanes::<f64>(lanes, parallel).unwrap(), true, lanes);
+            }
+        }
+
+        assert!(fma_simd_lanes::<f64>(3, false).is_none());
+        assert!(fma_simd_lanes::<f64>(32, true).is_none());
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    #[test]
+    fn avx2_kernels_match_scalar() {
+        use super::x86::*;
+
+        assert_matches_scalar(dotp_naive_f64, false, 4);
+        assert_matches_scalar(dotp_simd_f64, true, 4);
+        assert_matches_scalar(dotp_simd_f64_aligned, true, 4);
+        assert_matches_scalar(dotp_simd_f64_par, true, 4);
+        assert_matches_scalar(dotp_simd_f64_par_aligned, true, 4);
+        assert_matches_scalar(dotp_simd_f32, true, 8);
+        assert_matches_scalar(dotp_simd_f32_aligned, true, 8);
+        assert_matches_scalar(dotp_simd_f32_par, true, 8);
+        assert_matches_scalar(dotp_simd_f32_par_aligned, true, 8);
+        assert_matches_scalar(dotp_no_simd_bounds_f32, true, 8);
+
+        assert_rejects_misaligned(dotp_simd_f64_aligned, 32);
+        assert_rejects_misaligned(dotp_simd_f64_par_aligned, 32);
+        assert_rejects_misaligned(dotp_simd_f32_aligned, 32);
+        assert_rejects_misaligned(dotp_simd_f32_par_aligned, 32);
+    }
 }
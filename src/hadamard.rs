@@ -1,15 +1,23 @@
 use std::sync::{Arc, Mutex, RwLock};
 use num::{complex::Complex64, Zero};
 
+use crate::aligned::AlignedVec;
+
+pub type HadamardFn = fn(
+    usize,
+    &Vec<Arc<Mutex<AlignedVec<Complex64>>>>,
+    &RwLock<AlignedVec<Complex64>>,
+) -> AlignedVec<Complex64>;
+
 // Compute the Hadamard product of a sibling set of FFT coefficients (i.e. the multipole expansions)
 // With all 16 unique Green kernels corresponding to the unique convolutions.
 // This function doesn't do any special optimisations, just implementing the convolutions as a triple
 // for loop
 pub fn hadamard_product_naive(
     expansion_order: usize,
-    sibling_set: &Vec<Arc<Mutex<Vec<Complex64>>>>,
-    kernel_data: &RwLock<Vec<Complex64>>,
-) -> Vec<Complex64> {
+    sibling_set: &Vec<Arc<Mutex<AlignedVec<Complex64>>>>,
+    kernel_data: &RwLock<AlignedVec<Complex64>>,
+) -> AlignedVec<Complex64> {
     let n = 2 * expansion_order - 1;
     let &(m, n, o) = &(n, n, n);
 
@@ -18,7 +26,7 @@ pub fn hadamard_product_naive(
     let r = o + 1;
     let size_real = p * q * (r / 2 + 1);
 
-    let mut res = vec![Complex64::zero(); size_real * 16 * 8];
+    let mut res = AlignedVec::from_elem(Complex64::zero(), size_real * 16 * 8);
 
     for i in 0..16 {
         let m2l_matrix_offset = i * size_real;
@@ -38,20 +46,82 @@ pub fn hadamard_product_naive(
     res
 }
 
-#[cfg(all(target_arch = "x86", feature = "avx"))]
+#[cfg(all(target_arch = "x86_64", feature = "avx2"))]
 pub mod x86 {
     use super::*;
     use std::arch::x86_64::*;
 
+    use crate::{aligned::is_aligned, dotp::DotpError};
+
+    unsafe fn load<const ALIGNED: bool>(ptr: *const f64) -> __m256d {
+        if ALIGNED {
+            _mm256_load_pd(ptr)
+        } else {
+            _mm256_loadu_pd(ptr)
+        }
+    }
+
+    unsafe fn store<const ALIGNED: bool>(ptr: *mut f64, a: __m256d) {
+        if ALIGNED {
+            _mm256_store_pd(ptr, a)
+        } else {
+            _mm256_storeu_pd(ptr, a)
+        }
+    }
+
     // Compute the Hadamard product of a sibling set of FFT coefficients (i.e. the multipole expansions)
     // With all 16 unique Green kernels corresponding to the unique convolutions.
     // This function uses explicit SIMD to fetch and compute the component wise product of the complex
     // numbers corresponding to the FFT outputs.
     pub fn hadamard_product_simd(
         expansion_order: usize,
-        sibling_set: &Vec<Arc<Mutex<Vec<Complex64>>>>,
-        kernel_data: &RwLock<Vec<Complex64>>,
-    ) -> Vec<Complex64> {
+        sibling_set: &Vec<Arc<Mutex<AlignedVec<Complex64>>>>,
+        kernel_data: &RwLock<AlignedVec<Complex64>>,
+    ) -> AlignedVec<Complex64> {
+        hadamard_product_avx2::<false, false>(expansion_order, sibling_set, kernel_data)
+    }
+
+    // As `hadamard_product_simd`, with aligned loads and stores. Every expansion in the sibling set
+    // and the kernel data must start on a 32 byte boundary, as `AlignedVec`s do, else the inputs
+    // are rejected as misaligned like those of the aligned dotp kernels. Each kernel is then
+    // aligned too as their length is a multiple of two complex numbers.
+    pub fn hadamard_product_simd_aligned(
+        expansion_order: usize,
+        sibling_set: &Vec<Arc<Mutex<AlignedVec<Complex64>>>>,
+        kernel_data: &RwLock<AlignedVec<Complex64>>,
+    ) -> Result<AlignedVec<Complex64>, DotpError> {
+        let aligned = sibling_set
+            .iter()
+            .all(|s| is_aligned(s.lock().unwrap().as_ptr(), 32))
+            && is_aligned(kernel_data.read().unwrap().as_ptr(), 32);
+        if !aligned {
+            return Err(DotpError::Misaligned { align: 32 });
+        }
+
+        Ok(hadamard_product_avx2::<true, false>(
+            expansion_order,
+            sibling_set,
+            kernel_data,
+        ))
+    }
+
+    // As `hadamard_product_simd`, writing the products with non-temporal stores that bypass the
+    // cache. The result is far larger than L2 and only read again in the scatter, so this avoids
+    // reading each of its cache lines in before overwriting it. Each product is stored to its own
+    // block of the result, which isn't zeroed first.
+    pub fn hadamard_product_simd_stream(
+        expansion_order: usize,
+        sibling_set: &Vec<Arc<Mutex<AlignedVec<Complex64>>>>,
+        kernel_data: &RwLock<AlignedVec<Complex64>>,
+    ) -> AlignedVec<Complex64> {
+        hadamard_product_avx2::<false, true>(expansion_order, sibling_set, kernel_data)
+    }
+
+    fn hadamard_product_avx2<const ALIGNED: bool, const STREAM: bool>(
+        expansion_order: usize,
+        sibling_set: &Vec<Arc<Mutex<AlignedVec<Complex64>>>>,
+        kernel_data: &RwLock<AlignedVec<Complex64>>,
+    ) -> AlignedVec<Complex64> {
         let n = 2 * expansion_order - 1;
         let &(m, n, o) = &(n, n, n);
 
@@ -60,7 +130,13 @@ pub mod x86 {
         let r = o + 1;
         let size_real = p * q * (r / 2 + 1);
 
-        let mut res = vec![Complex64::zero(); size_real * 16 * 8];
+        // Every element is streamed to exactly once, the result is 32 byte aligned as an
+        // `AlignedVec`, and so is each block of it as size_real is even
+        let mut res = if STREAM {
+            unsafe { AlignedVec::uninit(size_real * 16 * 8) }
+        } else {
+            AlignedVec::from_elem(Complex64::zero(), size_real * 16 * 8)
+        };
 
         for i in 0..16 {
             let m2l_matrix_offset = i * size_real;
@@ -82,43 +158,47 @@ pub mod x86 {
 
                 for j in 0..chunks {
                     let simd_index = j * chunk_size;
-                    unsafe {
-                        let complex_ref = &signal[simd_index];
-                        let tuple_ptr: *const (f64, f64) = complex_ref as *const _ as *const (f64, f64);
-                        let ptr = tuple_ptr as *const f64;
-                        let signal_chunk = _mm256_loadu_pd(ptr);
 
-                        let complex_ref = &m2l_matrix[simd_index];
-                        let tuple_ptr: *const (f64, f64) = complex_ref as *const _ as *const (f64, f64);
-                        let ptr = tuple_ptr as *const f64;
-                        let kernel_chunk = _mm256_loadu_pd(ptr);
-
-                        let complex_ref = &res[simd_index];
-                        let tuple_ptr: *const (f64, f64) = complex_ref as *const _ as *const (f64, f64);
-                        let ptr = tuple_ptr as *const f64;
-                        let res_chunk = _mm256_loadu_pd(ptr);
-
-                        // Find component wise product, add with what's already there
+                    unsafe {
+                        let signal_ptr = signal[simd_index..].as_ptr() as *const f64;
+                        let kernel_ptr = m2l_matrix[simd_index..].as_ptr() as *const f64;
+                        let signal_chunk = load::<ALIGNED>(signal_ptr);
+                        let kernel_chunk = load::<ALIGNED>(kernel_ptr);
                         let product = hadamard_product_kernel_avx2(signal_chunk, kernel_chunk);
-                        let tmp = _mm256_add_pd(product, res_chunk);
 
-                        // Save
-                        let complex_ref = &res[simd_index];
-                        let tuple_ptr: *const (f64, f64) = complex_ref as *const _ as *const (f64, f64);
-                        let ptr = tuple_ptr as *mut f64;
+                        let ptr = res.as_mut_ptr().add(res_offset + simd_index) as *mut f64;
 
-                        _mm256_storeu_pd(ptr, tmp);
+                        if STREAM {
+                            _mm256_stream_pd(ptr, product);
+                        } else {
+                            // Add to what's already there
+                            let res_chunk = load::<ALIGNED>(ptr);
+                            store::<ALIGNED>(ptr, _mm256_add_pd(product, res_chunk));
+                        }
                     }
                 }
 
-                // Handle remainder
+                // Handle remainder, writing through the pointer as the streamed result is
+                // uninitialised
                 let start_remainder = chunks * chunk_size;
                 for j in start_remainder..size_real {
-                    res[res_offset + j] += signal[j] * m2l_matrix[j];
+                    let product = signal[j] * m2l_matrix[j];
+                    let ptr = unsafe { res.as_mut_ptr().add(res_offset + j) };
+
+                    if STREAM {
+                        unsafe { ptr.write(product) };
+                    } else {
+                        unsafe { *ptr += product };
+                    }
                 }
             }
         }
 
+        // Non-temporal stores are weakly ordered, make them visible before the result is handed on
+        if STREAM {
+            unsafe { _mm_sfence() };
+        }
+
         res
     }
 
@@ -165,9 +245,9 @@ pub mod aarch64 {
 
     pub fn hadamard_product_simd_neon(
         expansion_order: usize, 
-        sibling_set: &Vec<Arc<Mutex<Vec<Complex64>>>>, 
-        kernel_data: &RwLock<Vec<Complex64>>
-    ) -> Vec<Complex64> {
+        sibling_set: &Vec<Arc<Mutex<AlignedVec<Complex64>>>>, 
+        kernel_data: &RwLock<AlignedVec<Complex64>>
+    ) -> AlignedVec<Complex64> {
         
         let n = 2 * expansion_order - 1;
         let &(m, n, o) = &(n, n, n);
@@ -177,7 +257,7 @@ pub mod aarch64 {
         let r = o + 1;
         let size_real = p * q * (r / 2 + 1);
 
-        let res = vec![Complex64::zero(); size_real * 16 * 8];
+        let mut res = AlignedVec::from_elem(Complex64::zero(), size_real * 16 * 8);
 
         for i in 0..16 {
             let m2l_matrix_offset = i * size_real;
@@ -192,37 +272,20 @@ pub mod aarch64 {
             // access pattern to PVFMM and should not have to do the scatter operation as an additional step.
             for k in 0..8 {
                 let signal = sibling_set[k].lock().unwrap();
-                
-                let chunks = size_real;
+                let res_offset = k * size_real * 16 + i * size_real;
 
-                for j in 0..chunks {
-                    let simd_index = j;
+                // One complex number per register
+                for simd_index in 0..size_real {
                     unsafe {
-                        let complex_ref = &signal[simd_index];
-                        let tuple_ptr: *const (f64, f64) = complex_ref as *const _ as *const (f64, f64);
-                        let ptr = tuple_ptr as *const f64;
-                        let signal_chunk = vld1q_f64(ptr); 
-
-                        let complex_ref = &m2l_matrix[simd_index];
-                        let tuple_ptr: *const (f64, f64) = complex_ref as *const _ as *const (f64, f64);
-                        let ptr = tuple_ptr as *const f64;
-                        let kernel_chunk = vld1q_f64(ptr);
-
-                        let complex_ref = &res[simd_index];
-                        let tuple_ptr: *const (f64, f64) = complex_ref as *const _ as *const (f64, f64);
-                        let ptr = tuple_ptr as *const f64;
-                        let res_chunk = vld1q_f64(ptr);
+                        let signal_ptr = signal[simd_index..].as_ptr() as *const f64;
+                        let kernel_ptr = m2l_matrix[simd_index..].as_ptr() as *const f64;
+                        let signal_chunk = vld1q_f64(signal_ptr);
+                        let kernel_chunk = vld1q_f64(kernel_ptr);
 
                         // Find component wise product, add with what's already there
                         let product = hadamard_product_kernel_neon(signal_chunk, kernel_chunk);
-                        let tmp = vaddq_f64(product, res_chunk);
-
-                        // Save
-                        let complex_ref = &res[simd_index];
-                        let tuple_ptr: *const (f64, f64) = complex_ref as *const _ as *const (f64, f64);
-                        let ptr = tuple_ptr as *mut f64;
-
-                        vst1q_f64(ptr, tmp)
+                        let ptr = res.as_mut_ptr().add(res_offset + simd_index) as *mut f64;
+                        vst1q_f64(ptr, vaddq_f64(product, vld1q_f64(ptr)));
                     }
                 }
             }
@@ -232,6 +295,82 @@ pub mod aarch64 {
 
     }
     
+    // As `hadamard_product_simd_neon`, writing the products with non-temporal store pairs that bypass
+    // the cache. The result is far larger than L2 and only read again in the scatter, so this avoids
+    // reading each of its cache lines in before overwriting it. Each product is stored to its own
+    // block of the result, which isn't zeroed first.
+    pub fn hadamard_product_simd_neon_stream(
+        expansion_order: usize,
+        sibling_set: &Vec<Arc<Mutex<AlignedVec<Complex64>>>>,
+        kernel_data: &RwLock<AlignedVec<Complex64>>,
+    ) -> AlignedVec<Complex64> {
+        let n = 2 * expansion_order - 1;
+        let &(m, n, o) = &(n, n, n);
+
+        let p = m + 1;
+        let q = n + 1;
+        let r = o + 1;
+        let size_real = p * q * (r / 2 + 1);
+
+        // Every element is written exactly once
+        let mut res = unsafe { AlignedVec::uninit(size_real * 16 * 8) };
+
+        for i in 0..16 {
+            let m2l_matrix_offset = i * size_real;
+
+            let m2l_matrix =
+                &kernel_data.read().unwrap()[m2l_matrix_offset..m2l_matrix_offset + size_real];
+
+            for k in 0..8 {
+                let signal = sibling_set[k].lock().unwrap();
+                let res_offset = k * size_real * 16 + i * size_real;
+
+                // stnp stores a pair of registers, two complex numbers
+                let chunk_size = 2;
+                let chunks = size_real / chunk_size;
+
+                for j in 0..chunks {
+                    let simd_index = j * chunk_size;
+                    unsafe {
+                        let signal_ptr = signal[simd_index..].as_ptr() as *const f64;
+                        let kernel_ptr = m2l_matrix[simd_index..].as_ptr() as *const f64;
+
+                        let lo = hadamard_product_kernel_neon(
+                            vld1q_f64(signal_ptr),
+                            vld1q_f64(kernel_ptr),
+                        );
+                        let hi = hadamard_product_kernel_neon(
+                            vld1q_f64(signal_ptr.add(2)),
+                            vld1q_f64(kernel_ptr.add(2)),
+                        );
+
+                        let ptr = res.as_mut_ptr().add(res_offset + simd_index) as *mut f64;
+                        std::arch::asm!(
+                            "stnp {0:q}, {1:q}, [{2}]",
+                            in(vreg) lo,
+                            in(vreg) hi,
+                            in(reg) ptr,
+                            options(nostack, preserves_flags)
+                        );
+                    }
+                }
+
+                // Handle remainder, writing through the pointer as the result is uninitialised
+                let start_remainder = chunks * chunk_size;
+                for j in start_remainder..size_real {
+                    let product = signal[j] * m2l_matrix[j];
+                    unsafe { res.as_mut_ptr().add(res_offset + j).write(product) };
+                }
+            }
+        }
+
+        // Order the non-temporal stores before any later store, e.g. releasing the result to
+        // another thread
+        unsafe { std::arch::asm!("dmb ishst", options(nostack, preserves_flags)) };
+
+        res
+    }
+
     pub fn hadamard_product_kernel_neon(a_ra: float64x2_t, b_ra: float64x2_t) -> float64x2_t {
         unsafe {
             // Extract real parts [a1, a1]
@@ -259,3 +398,94 @@ pub mod aarch64 {
    
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPANSION_ORDERS: [usize; 4] = [2, 3, 5, 6];
+
+    fn size_real(expansion_order: usize) -> usize {
+        let n = 2 * expansion_order - 1;
+        let &(m, n, o) = &(n, n, n);
+
+        let p = m + 1;
+        let q = n + 1;
+        let r = o + 1;
+        p * q * (r / 2 + 1)
+    }
+
+    fn data(n: usize, seed: usize) -> AlignedVec<Complex64> {
+        (0..n)
+            .map(|i| {
+                let x = (i * 7 + seed) as f64;
+                Complex64::new(x.sin(), (0.5 * x).cos())
+            })
+            .collect()
+    }
+
+    fn sibling_set(expansion_order: usize) -> Vec<Arc<Mutex<AlignedVec<Complex64>>>> {
+        (0..8)
+            .map(|k| Arc::new(Mutex::new(data(size_real(expansion_order), k))))
+            .collect()
+    }
+
+    fn kernel_data(expansion_order: usize) -> RwLock<AlignedVec<Complex64>> {
+        RwLock::new(data(16 * size_real(expansion_order), 8))
+    }
+
+    // Every block of the result, one per sibling and kernel, matches the naive kernel
+    fn assert_matches_naive(hadamard: HadamardFn) {
+        for expansion_order in EXPANSION_ORDERS {
+            let sibling_set = sibling_set(expansion_order);
+            let kernel_data = kernel_data(expansion_order);
+
+            let expected = hadamard_product_naive(expansion_order, &sibling_set, &kernel_data);
+            let found = hadamard(expansion_order, &sibling_set, &kernel_data);
+
+            assert_eq!(expected.len(), found.len());
+            for (i, (e, f)) in expected.iter().zip(found.iter()).enumerate() {
+                assert!(
+                    (e - f).norm() <= 1e-14 * e.norm().max(1.),
+                    "order {}, index {}: expected {}, found {}",
+                    expansion_order,
+                    i,
+                    e,
+                    f
+                );
+            }
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    #[test]
+    fn avx2_matches_naive() {
+        assert_matches_naive(x86::hadamard_product_simd);
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    #[test]
+    fn avx2_aligned_matches_naive() {
+        assert_matches_naive(|expansion_order, sibling_set, kernel_data| {
+            x86::hadamard_product_simd_aligned(expansion_order, sibling_set, kernel_data).unwrap()
+        });
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    #[test]
+    fn avx2_stream_matches_naive() {
+        assert_matches_naive(x86::hadamard_product_simd_stream);
+    }
+
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    #[test]
+    fn neon_matches_naive() {
+        assert_matches_naive(aarch64::hadamard_product_simd_neon);
+    }
+
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    #[test]
+    fn neon_stream_matches_naive() {
+        assert_matches_naive(aarch64::hadamard_product_simd_neon_stream);
+    }
+}
@@ -1,43 +1,59 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
 use num::{complex::*, Float, Zero, One};
 
-use bempp_tree::{
-    implementations::helpers::points_fixture,
-    types::{morton::MortonKey, single_node::SingleNodeTree},
-};
+use bempp_tree::types::{domain::Domain, morton::MortonKey};
 
-use bempp_traits::tree::Tree;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-use rlst::dense::{rlst_rand_mat, RawAccess};
+use crate::{
+    aligned::{alloc_buffers, AlignedVec, AllocPolicy},
+    kernel::Kernel,
+};
 
 pub const BLOCK_SIZE: usize = 1024;
 
-pub fn data_f64(n: usize) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+// Seed of the random inputs generated inside the drivers
+pub const DEFAULT_SEED: u64 = 0;
+
+// Random complex numbers with real and imaginary parts uniform in [0, 1), reproducible for a
+// given seed across runs and machines.
+pub fn random_complex(n: usize, seed: u64) -> Vec<Complex64> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n).map(|_| Complex64::new(rng.gen(), rng.gen())).collect()
+}
+
+// Points uniform in the unit cube, as an (npoints, 3) column major array of coordinates.
+pub fn points_uniform(npoints: usize, seed: u64) -> Vec<f64> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..3 * npoints).map(|_| rng.gen()).collect()
+}
+
+pub fn data_f64(n: usize) -> (AlignedVec<f64>, AlignedVec<f64>, AlignedVec<f64>) {
     let size: usize = BLOCK_SIZE * n;
 
-    let x: Vec<f64> = (0..size).enumerate().map(|(i, _)| i as f64).collect();
-    let y: Vec<f64> = (0..size).enumerate().map(|(i, _)| i as f64).collect();
-    let z = vec![0f64; size];
+    let x: AlignedVec<f64> = (0..size).enumerate().map(|(i, _)| i as f64).collect();
+    let y: AlignedVec<f64> = (0..size).enumerate().map(|(i, _)| i as f64).collect();
+    let z = AlignedVec::from_elem(0f64, size);
 
     (x, y, z)
 }
 
-pub fn data_f32(n: usize) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+pub fn data_f32(n: usize) -> (AlignedVec<f32>, AlignedVec<f32>, AlignedVec<f32>) {
     let size: usize = BLOCK_SIZE * n;
 
-    let x: Vec<f32> = (0..size).enumerate().map(|(i, _)| i as f32).collect();
-    let y: Vec<f32> = (0..size).enumerate().map(|(i, _)| i as f32).collect();
-    let z = vec![0f32; size];
+    let x: AlignedVec<f32> = (0..size).enumerate().map(|(i, _)| i as f32).collect();
+    let y: AlignedVec<f32> = (0..size).enumerate().map(|(i, _)| i as f32).collect();
+    let z = AlignedVec::from_elem(0f32, size);
 
     (x, y, z)
 }
 
 // Dummy data that mirrors that of the FFT of Green's fct evaluations
-pub fn kernel_like_data(expansion_order: usize) -> Vec<Complex64> {
+pub fn kernel_like_data(expansion_order: usize, seed: u64) -> AlignedVec<Complex64> {
     let n = 2 * expansion_order - 1;
     let &(m, n, o) = &(n, n, n);
 
@@ -46,13 +62,12 @@ pub fn kernel_like_data(expansion_order: usize) -> Vec<Complex64> {
     let r = o + 1;
     let size_real = p * q * (r / 2 + 1);
 
-    let data = rlst_rand_mat![Complex64, (16, size_real)];
-
-    data.data().to_vec()
+    // Stored column major, (16, size_real)
+    random_complex(16 * size_real, seed).into()
 }
 
 // Dummy data that mirrors that of the FFT of Green's fct evaluations
-pub fn kernel_like_data_transpose(expansion_order: usize) -> Vec<Complex64> {
+pub fn kernel_like_data_transpose(expansion_order: usize, seed: u64) -> AlignedVec<Complex64> {
     let n = 2 * expansion_order - 1;
     let &(m, n, o) = &(n, n, n);
 
@@ -61,12 +76,206 @@ pub fn kernel_like_data_transpose(expansion_order: usize) -> Vec<Complex64> {
     let r = o + 1;
     let size_real = p * q * (r / 2 + 1);
 
-    let data = rlst_rand_mat![Complex64, (size_real, 16)];
+    // Stored column major, (size_real, 16)
+    random_complex(size_real * 16, seed).into()
+}
+
+// All transfer vectors, in units of the box width, between a box and the members of its
+// interaction list, i.e. the integer vectors in [-3, 3]^3 that aren't adjacent. There are 316.
+pub fn transfer_vectors() -> Vec<[i64; 3]> {
+    let mut result = Vec::new();
+
+    for i in -3i64..=3 {
+        for j in -3i64..=3 {
+            for k in -3i64..=3 {
+                if i.abs() > 1 || j.abs() > 1 || k.abs() > 1 {
+                    result.push([i, j, k]);
+                }
+            }
+        }
+    }
+
+    result
+}
+
+// Points on the surface of a cube of side `alpha * width` about `centre`, with `expansion_order`
+// points per side. There are 6 * (expansion_order - 1)^2 + 2 points, the same as the number of
+// coefficients in `m2l_like_data`.
+pub fn surface_grid(
+    expansion_order: usize,
+    centre: &[f64; 3],
+    width: f64,
+    alpha: f64,
+) -> Vec<[f64; 3]> {
+    let n = expansion_order;
+    let side = alpha * width;
+    let mut points = Vec::with_capacity(6 * (n - 1).pow(2) + 2);
+
+    let coord = |i: usize, c: f64| c + side * ((i as f64) / ((n - 1) as f64) - 0.5);
+
+    for i in 0..n {
+        for j in 0..n {
+            for k in 0..n {
+                let on_surface = [i, j, k].iter().any(|&l| l == 0 || l == n - 1);
+                if on_surface {
+                    points.push([
+                        coord(i, centre[0]),
+                        coord(j, centre[1]),
+                        coord(k, centre[2]),
+                    ]);
+                }
+            }
+        }
+    }
+
+    points
+}
+
+// One dimensional DFT of `n` strided values starting at `offset`, writing the first `nout`
+// frequencies into `out`. Naive O(n^2), only intended for operator precomputation.
+fn dft_strided(
+    data: &[Complex64],
+    offset: usize,
+    stride: usize,
+    n: usize,
+    nout: usize,
+    twiddles: &[Complex64],
+    out: &mut [Complex64],
+) {
+    for (f, o) in out.iter_mut().enumerate().take(nout) {
+        let mut sum = Complex64::zero();
+        for t in 0..n {
+            sum += data[offset + t * stride] * twiddles[(f * t) % n];
+        }
+        *o = sum;
+    }
+}
+
+fn twiddles(n: usize) -> Vec<Complex64> {
+    (0..n)
+        .map(|i| Complex64::from_polar(1.0, -2.0 * std::f64::consts::PI * (i as f64) / (n as f64)))
+        .collect()
+}
+
+// Three dimensional forward DFT of a row-major (p, q, r) grid, keeping only the first
+// r / 2 + 1 frequencies along the last axis, i.e. the layout of a real-to-complex FFT.
+pub fn rfft3(data: &[Complex64], shape: [usize; 3]) -> Vec<Complex64> {
+    let [p, q, r] = shape;
+    let r_out = r / 2 + 1;
+    assert_eq!(data.len(), p * q * r);
+
+    // Transform along the last axis
+    let tw = twiddles(r);
+    let mut tmp = vec![Complex64::zero(); p * q * r_out];
+    for i in 0..p * q {
+        dft_strided(data, i * r, 1, r, r_out, &tw, &mut tmp[i * r_out..(i + 1) * r_out]);
+    }
+
+    // Transform along the middle axis
+    let tw = twiddles(q);
+    let mut tmp2 = vec![Complex64::zero(); p * q * r_out];
+    let mut line = vec![Complex64::zero(); q];
+    for i in 0..p {
+        for k in 0..r_out {
+            dft_strided(&tmp, i * q * r_out + k, r_out, q, q, &tw, &mut line);
+            for (j, v) in line.iter().enumerate() {
+                tmp2[i * q * r_out + j * r_out + k] = *v;
+            }
+        }
+    }
+
+    // Transform along the first axis
+    let tw = twiddles(p);
+    let mut line = vec![Complex64::zero(); p];
+    for j in 0..q {
+        for k in 0..r_out {
+            dft_strided(&tmp2, j * r_out + k, q * r_out, p, p, &tw, &mut line);
+            for (i, v) in line.iter().enumerate() {
+                tmp[i * q * r_out + j * r_out + k] = *v;
+            }
+        }
+    }
+
+    tmp
+}
+
+// Spectra of the kernel evaluated on the convolution grid of each transfer vector, stored
+// contiguously per transfer vector with the same layout as `kernel_like_data_transpose`.
+// Only the first r / 2 + 1 frequencies along the last axis are kept, which determine the spectrum
+// of a real signal. The spectrum of a complex kernel (Helmholtz) has no such symmetry, so it's held
+// as the spectra of the kernel's real part for every transfer vector, followed by those of its
+// imaginary part, each of which does. The full spectrum is then re[f] + i im[f] at a kept
+// frequency f, and conj(re[f]) + i conj(im[f]) at -f.
+pub fn kernel_spectrum_data<K: Kernel>(
+    kernel: &K,
+    expansion_order: usize,
+    transfer_vectors: &[[i64; 3]],
+    box_width: f64,
+) -> AlignedVec<Complex64> {
+    let n = 2 * expansion_order - 1;
+    let &(m, n, o) = &(n, n, n);
+
+    let p = m + 1;
+    let q = n + 1;
+    let r = o + 1;
+    let size = p * q * r;
+    let size_real = p * q * (r / 2 + 1);
+
+    // Spacing of the equivalent surface grid
+    let spacing = box_width / ((expansion_order - 1) as f64);
+
+    let mut real = Vec::with_capacity(size_real * transfer_vectors.len());
+    let mut imag = Vec::with_capacity(if K::COMPLEX { real.capacity() } else { 0 });
+    let mut grid = vec![K::T::zero(); size];
+
+    for t in transfer_vectors.iter() {
+        let centre = [
+            t[0] as f64 * box_width,
+            t[1] as f64 * box_width,
+            t[2] as f64 * box_width,
+        ];
+
+        kernel.evaluate_grid(&centre, spacing, [p, q, r], &mut grid);
+        let values: Vec<Complex64> = grid.iter().map(|&g| g.into()).collect();
+
+        let signal: Vec<Complex64> = values.iter().map(|g| Complex64::from(g.re)).collect();
+        real.extend(rfft3(&signal, [p, q, r]));
+
+        if K::COMPLEX {
+            let signal: Vec<Complex64> = values.iter().map(|g| Complex64::from(g.im)).collect();
+            imag.extend(rfft3(&signal, [p, q, r]));
+        }
+    }
+
+    real.extend(imag);
+    real.into()
+}
+
+// Spectra of `kernel` in place of the 16 random kernels of `kernel_like_data_transpose`, for the
+// first 16 transfer vectors of the interaction list between boxes of width `box_width`. There is a
+// buffer of 16 kernels for each part of the spectrum, one for a real kernel and two, the real then
+// imaginary part, for a complex kernel. Convolving a real signal with each and combining the
+// results as re + i im gives the convolution with the complex kernel.
+pub fn m2l_kernel_data<K: Kernel>(
+    kernel: &K,
+    expansion_order: usize,
+    box_width: f64,
+) -> Vec<AlignedVec<Complex64>> {
+    let n = 2 * expansion_order - 1;
+    let &(m, n, o) = &(n, n, n);
+
+    let p = m + 1;
+    let q = n + 1;
+    let r = o + 1;
+    let size_real = p * q * (r / 2 + 1);
 
-    data.data().to_vec()
+    kernel_spectrum_data(kernel, expansion_order, &transfer_vectors()[..16], box_width)
+        .chunks_exact(16 * size_real)
+        .map(AlignedVec::from_slice)
+        .collect()
 }
 
-pub fn transpose<T: Clone>(data: &Vec<Arc<Mutex<Vec<T>>>>) -> Vec<T> {
+pub fn transpose<T: Copy>(data: &Vec<Arc<Mutex<AlignedVec<T>>>>) -> Vec<T> {
     let outer_len = data.len();
     if outer_len == 0 {
         return Vec::new();
@@ -77,7 +286,7 @@ pub fn transpose<T: Clone>(data: &Vec<Arc<Mutex<Vec<T>>>>) -> Vec<T> {
 
     for i in 0..inner_len {
         for j in 0..outer_len {
-            let value = data[j].lock().unwrap()[i].clone();
+            let value = data[j].lock().unwrap()[i];
             transposed.push(value);
         }
     }
@@ -85,16 +294,77 @@ pub fn transpose<T: Clone>(data: &Vec<Arc<Mutex<Vec<T>>>>) -> Vec<T> {
     transposed
 }
 
+// Domain of the synthetic octrees
+pub fn unit_domain() -> Domain {
+    Domain {
+        origin: [0., 0., 0.],
+        diameter: [1., 1., 1.],
+    }
+}
+
+// All keys of a complete octree over the unit cube at a given depth, without building a tree from
+// points. There are 8^depth keys.
+pub fn full_octree_keys(depth: u64) -> HashSet<MortonKey> {
+    let domain = unit_domain();
+    let n = 1u64 << depth;
+    let width = 1. / n as f64;
+
+    (0..n)
+        .flat_map(|i| (0..n).flat_map(move |j| (0..n).map(move |k| [i, j, k])))
+        .map(|idx| {
+            let centre = idx.map(|x| (x as f64 + 0.5) * width);
+            MortonKey::from_point(&centre, &domain, depth)
+        })
+        .collect()
+}
+
+// Random subset of a complete octree, each sibling set is kept with probability `occupancy` so
+// that the keys still group into complete sibling sets as the parent level drivers expect. Panics
+// for a depth of 0, as the root has no siblings.
+pub fn sparse_octree_keys(depth: u64, occupancy: f64, seed: u64) -> HashSet<MortonKey> {
+    assert!(depth > 0, "a sparse octree needs a depth of at least 1");
+
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut parents: Vec<MortonKey> = full_octree_keys(depth - 1).into_iter().collect();
+    parents.sort();
+
+    parents
+        .iter()
+        .filter(|_| rng.gen::<f64>() < occupancy)
+        .flat_map(|p| p.children())
+        .collect()
+}
+
+// FFT coefficients of a sibling set, as input to the Hadamard product
+pub fn sibling_set_data(
+    expansion_order: usize,
+    seed: u64,
+) -> Vec<Arc<Mutex<AlignedVec<Complex64>>>> {
+    let n = 2 * expansion_order - 1;
+    let &(m, n, o) = &(n, n, n);
+
+    let p = m + 1;
+    let q = n + 1;
+    let r = o + 1;
+    let size_real = p * q * (r / 2 + 1);
+
+    random_complex(8 * size_real, seed)
+        .chunks_exact(size_real)
+        .map(|c| Arc::new(Mutex::new(AlignedVec::from_slice(c))))
+        .collect()
+}
+
 // Generate random multipole coefficients attached to a set of keys for testing M2L data access
 pub fn m2l_like_data(
     expansion_order: usize,
-    tree: &SingleNodeTree,
+    keys: &HashSet<MortonKey>,
 ) -> HashMap<MortonKey, Vec<f64>> {
     let mut data = HashMap::new();
 
     let ncoeffs = 6 * (expansion_order - 1).pow(2) + 2;
 
-    for key in tree.get_all_leaves_set().iter() {
+    for key in keys.iter() {
         let tmp = vec![0.; ncoeffs];
         data.insert(*key, tmp);
     }
@@ -105,12 +375,12 @@ pub fn m2l_like_data(
 // Generate random multipole coefficients attached to a set of keys for testing M2L data access
 pub fn m2l_like_data_arc(
     expansion_order: usize,
-    tree: &SingleNodeTree,
+    keys: &HashSet<MortonKey>,
 ) -> HashMap<MortonKey, Arc<Mutex<Vec<f64>>>> {
     let ncoeffs = 6 * (expansion_order - 1).pow(2) + 2;
     let mut data = HashMap::new();
 
-    for key in tree.get_all_leaves_set().iter() {
+    for key in keys.iter() {
         let tmp = Arc::new(Mutex::new(vec![0.; ncoeffs]));
         data.insert(*key, tmp);
     }
@@ -118,12 +388,40 @@ pub fn m2l_like_data_arc(
     data
 }
 
+// Random multipole expansions, i.e. equivalent densities, of a set of keys, reproducible for a
+// given seed. They're shifted to sum to zero, as the Laplace lattice sum needs a domain with no
+// net charge.
+pub fn random_multipoles(
+    expansion_order: usize,
+    keys: &HashSet<MortonKey>,
+    seed: u64,
+) -> HashMap<MortonKey, Vec<f64>> {
+    let ncoeffs = 6 * (expansion_order - 1).pow(2) + 2;
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    // Morton order, so that each key gets the same values on every run
+    let mut keys: Vec<MortonKey> = keys.iter().cloned().collect();
+    keys.sort();
+
+    let mut values: Vec<f64> = (0..ncoeffs * keys.len()).map(|_| rng.gen()).collect();
+    let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    values.iter_mut().for_each(|v| *v -= mean);
+
+    keys.into_iter()
+        .zip(values.chunks_exact(ncoeffs))
+        .map(|(key, v)| (key, v.to_vec()))
+        .collect()
+}
+
 
-// Generate random coefficients attached to a set of keys for testing M2L data access
+// Generate random coefficients attached to a set of keys for testing M2L data access, allocated
+// according to `alloc`. Keys are taken in Morton order, so that with first touch each sibling set
+// is initialised by a single worker, as in the parent level drivers.
 pub fn fft_like_data_arc(
     expansion_order: usize,
-    tree: &SingleNodeTree,
-) -> HashMap<MortonKey, Arc<Mutex<Vec<Complex64>>>> {
+    keys: &HashSet<MortonKey>,
+    alloc: AllocPolicy,
+) -> HashMap<MortonKey, Arc<Mutex<AlignedVec<Complex64>>>> {
     let n = 2 * expansion_order - 1;
     let &(m, n, o) = &(n, n, n);
 
@@ -132,17 +430,18 @@ pub fn fft_like_data_arc(
     let r = o + 1;
     let size_real = p * q * (r / 2 + 1);
 
-    let mut data: HashMap<MortonKey, Arc<Mutex<Vec<Complex64>>>> = HashMap::new();
+    let mut keys: Vec<MortonKey> = keys.iter().cloned().collect();
+    keys.sort();
 
-    for key in tree.get_all_leaves_set().iter() {
-        let tmp = Arc::new(Mutex::new(vec![Complex64::one(); size_real]));
-        data.insert(*key, tmp);
-    }
+    let buffers = alloc_buffers(Complex64::one(), size_real, keys.len(), 8, alloc);
 
-    data
+    keys.into_iter()
+        .zip(buffers)
+        .map(|(key, buffer)| (key, Arc::new(Mutex::new(buffer))))
+        .collect()
 }
 
-pub fn fft_like_data_transposed(expansion_order: usize, tree: &SingleNodeTree) -> Vec<Complex<f64>> {
+pub fn fft_like_data_transposed(expansion_order: usize, keys: &HashSet<MortonKey>) -> AlignedVec<Complex<f64>> {
     let n = 2 * expansion_order - 1;
     let &(m, n, o) = &(n, n, n);
 
@@ -151,15 +450,15 @@ pub fn fft_like_data_transposed(expansion_order: usize, tree: &SingleNodeTree) -
     let r = o + 1;
     let size_real = p * q * (r / 2 + 1);
 
-    let data = vec![Complex64::one(); size_real*tree.get_all_leaves_set().len()];
+    let data = AlignedVec::from_elem(Complex64::one(), size_real*keys.len());
 
     data
 }
 
 pub fn fft_like_data_arc_vec(
     expansion_order: usize,
-    tree: &SingleNodeTree
-) -> Vec<Arc<Mutex<Vec<Complex64>>>>{
+    keys: &HashSet<MortonKey>
+) -> Vec<Arc<Mutex<AlignedVec<Complex64>>>>{
 
     let n = 2 * expansion_order - 1;
     let &(m, n, o) = &(n, n, n);
@@ -171,10 +470,10 @@ pub fn fft_like_data_arc_vec(
 
     let mut data = Vec::new();
 
-    let nkeys = tree.get_all_leaves_set().len();
+    let nkeys = keys.len();
 
     for _ in  0..nkeys {
-        let tmp = Arc::new(Mutex::new(vec![Complex64::zero(); size_real]));
+        let tmp = Arc::new(Mutex::new(AlignedVec::from_elem(Complex64::zero(), size_real)));
         data.push(tmp);
     }
 
@@ -198,3 +497,106 @@ pub fn fft_like_data_arc_vec(
 
 //     data
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::kernel::{Helmholtz, Laplace};
+
+    // Full three dimensional DFT of a row-major grid at frequency f, summed directly
+    fn dft3(data: &[Complex64], shape: [usize; 3], f: [usize; 3]) -> Complex64 {
+        let [p, q, r] = shape;
+        let mut sum = Complex64::zero();
+
+        for i in 0..p {
+            for j in 0..q {
+                for k in 0..r {
+                    let phase = (f[0] * i) as f64 / p as f64
+                        + (f[1] * j) as f64 / q as f64
+                        + (f[2] * k) as f64 / r as f64;
+                    sum += data[i * q * r + j * r + k]
+                        * Complex64::from_polar(1.0, -2.0 * std::f64::consts::PI * phase);
+                }
+            }
+        }
+
+        sum
+    }
+
+    #[test]
+    fn complex_spectrum_holds_the_full_spectrum() {
+        let expansion_order = 3;
+        let box_width = 0.5;
+        let t = [2i64, -1, 3];
+        let shape = [6, 6, 6];
+        let [p, q, r] = shape;
+        let size_real = p * q * (r / 2 + 1);
+
+        let kernel = Helmholtz::new(2.5);
+        let data = kernel_spectrum_data(&kernel, expansion_order, &[t], box_width);
+        assert_eq!(data.len(), 2 * size_real);
+        let (re, im) = data.split_at(size_real);
+
+        let mut grid = vec![Complex64::zero(); p * q * r];
+        let centre = t.map(|x| x as f64 * box_width);
+        let spacing = box_width / (expansion_order - 1) as f64;
+        kernel.evaluate_grid(&centre, spacing, shape, &mut grid);
+
+        let scale = grid.iter().fold(0f64, |a, g| a + g.norm());
+
+        for a in 0..p {
+            for b in 0..q {
+                for c in 0..r {
+                    let found = if c <= r / 2 {
+                        let idx = a * q * (r / 2 + 1) + b * (r / 2 + 1) + c;
+                        re[idx] + Complex64::i() * im[idx]
+                    } else {
+                        let idx = ((p - a) % p) * q * (r / 2 + 1) + ((q - b) % q) * (r / 2 + 1)
+                            + (r - c);
+                        re[idx].conj() + Complex64::i() * im[idx].conj()
+                    };
+                    let expected = dft3(&grid, shape, [a, b, c]);
+                    assert!(
+                        (expected - found).norm() <= 1e-12 * scale,
+                        "frequency {:?}: expected {}, found {}",
+                        [a, b, c],
+                        expected,
+                        found
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn m2l_kernel_data_has_a_buffer_per_part() {
+        let size_real = 6 * 6 * 4;
+
+        let real = m2l_kernel_data(&Laplace, 3, 0.5);
+        assert_eq!(real.len(), 1);
+        assert_eq!(real[0].len(), 16 * size_real);
+
+        let complex = m2l_kernel_data(&Helmholtz::new(2.5), 3, 0.5);
+        assert_eq!(complex.len(), 2);
+        assert!(complex.iter().all(|k| k.len() == 16 * size_real));
+    }
+
+    #[test]
+    fn sparse_octree_keys_are_whole_sibling_sets() {
+        for depth in 1..=3 {
+            let keys = sparse_octree_keys(depth, 0.5, 0);
+
+            assert!(keys.iter().all(|k| k.level() == depth));
+            assert!(keys
+                .iter()
+                .all(|k| k.siblings().iter().all(|s| keys.contains(s))));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn sparse_octree_of_depth_zero_is_rejected() {
+        sparse_octree_keys(0, 0.5, 0);
+    }
+}
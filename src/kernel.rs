@@ -0,0 +1,342 @@
+//! Green's function kernels used to generate non-trivial M2L operator data.
+use std::f64::consts::PI;
+use std::simd::{prelude::*, StdFloat};
+
+use num::{complex::Complex64, Zero};
+
+// Interface for a translation invariant Green's function, G(x, y) = G(x - y).
+pub trait Kernel: Send + Sync {
+    // Type of the kernel values, real for Laplace/Yukawa, complex for Helmholtz.
+    type T: Copy + Zero + Into<Complex64> + Send + Sync;
+
+    // Whether the kernel values have an imaginary part, so their spectra lack the symmetry of
+    // those of a real kernel.
+    const COMPLEX: bool = false;
+
+    // Evaluate the kernel between a single source and target, the singularity is set to zero.
+    fn evaluate(&self, source: &[f64; 3], target: &[f64; 3]) -> Self::T;
+
+    // Evaluate the kernel between all sources and targets, result is stored row-major with
+    // one row per target, i.e. result[i * sources.len() + j] = G(targets[i], sources[j]).
+    fn evaluate_batch(&self, sources: &[[f64; 3]], targets: &[[f64; 3]], result: &mut [Self::T]);
+
+    // Evaluate the kernel at the displacements `centre + d * spacing`, where d is an integer
+    // offset laid out in circulant order on a grid of `shape`, as required by FFT based
+    // convolution. Along an axis of length n, index i < n/2 holds offset i, index i > n/2 holds
+    // offset i - n, and for even n the index n/2 is zero padding.
+    fn evaluate_grid(
+        &self,
+        centre: &[f64; 3],
+        spacing: f64,
+        shape: [usize; 3],
+        result: &mut [Self::T],
+    ) {
+        let [p, q, r] = shape;
+        assert_eq!(result.len(), p * q * r);
+
+        let offset = |i: usize, n: usize| -> Option<f64> {
+            if n % 2 == 0 && i == n / 2 {
+                None
+            } else if i < n / 2 || (n % 2 == 1 && i == n / 2) {
+                Some(i as f64)
+            } else {
+                Some(i as f64 - n as f64)
+            }
+        };
+
+        let origin = [0f64; 3];
+        for i in 0..p {
+            for j in 0..q {
+                for k in 0..r {
+                    let idx = i * q * r + j * r + k;
+                    result[idx] = match (offset(i, p), offset(j, q), offset(k, r)) {
+                        (Some(di), Some(dj), Some(dk)) => {
+                            let displacement = [
+                                centre[0] + di * spacing,
+                                centre[1] + dj * spacing,
+                                centre[2] + dk * spacing,
+                            ];
+                            self.evaluate(&origin, &displacement)
+                        }
+                        _ => Self::T::zero(),
+                    };
+                }
+            }
+        }
+    }
+}
+
+const LANES: usize = 4;
+
+// Gather the coordinates of (up to) four sources into SIMD registers, padding with the target
+// itself so that padded lanes hit the singularity and evaluate to zero.
+fn gather_sources(sources: &[[f64; 3]], target: &[f64; 3]) -> (f64x4, f64x4, f64x4) {
+    let mut x = [target[0]; LANES];
+    let mut y = [target[1]; LANES];
+    let mut z = [target[2]; LANES];
+
+    for (l, s) in sources.iter().enumerate() {
+        x[l] = s[0];
+        y[l] = s[1];
+        z[l] = s[2];
+    }
+
+    (
+        f64x4::from_array(x),
+        f64x4::from_array(y),
+        f64x4::from_array(z),
+    )
+}
+
+// Distance from a target to four sources held in SIMD registers.
+fn distance_simd(target: &[f64; 3], sources: (f64x4, f64x4, f64x4)) -> f64x4 {
+    let dx = f64x4::splat(target[0]) - sources.0;
+    let dy = f64x4::splat(target[1]) - sources.1;
+    let dz = f64x4::splat(target[2]) - sources.2;
+
+    dx.mul_add(dx, dy.mul_add(dy, dz * dz)).sqrt()
+}
+
+fn distance(source: &[f64; 3], target: &[f64; 3]) -> f64 {
+    let dx = target[0] - source[0];
+    let dy = target[1] - source[1];
+    let dz = target[2] - source[2];
+
+    (dx * dx + dy * dy + dz * dz).sqrt()
+}
+
+// Laplace kernel, 1 / (4 pi |x - y|)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Laplace;
+
+impl Kernel for Laplace {
+    type T = f64;
+
+    fn evaluate(&self, source: &[f64; 3], target: &[f64; 3]) -> f64 {
+        let r = distance(source, target);
+        if r == 0. {
+            0.
+        } else {
+            1. / (4. * PI * r)
+        }
+    }
+
+    fn evaluate_batch(&self, sources: &[[f64; 3]], targets: &[[f64; 3]], result: &mut [f64]) {
+        let nsources = sources.len();
+        assert_eq!(result.len(), nsources * targets.len());
+
+        let scale = f64x4::splat(1. / (4. * PI));
+        let zero = f64x4::splat(0.);
+
+        for (target, row) in targets.iter().zip(result.chunks_exact_mut(nsources)) {
+            for (chunk, res) in sources.chunks(LANES).zip(row.chunks_mut(LANES)) {
+                let r = distance_simd(target, gather_sources(chunk, target));
+                let inv_r = r.simd_eq(zero).select(zero, scale / r);
+                res.copy_from_slice(&inv_r.to_array()[..res.len()]);
+            }
+        }
+    }
+}
+
+// Modified Helmholtz (Yukawa) kernel, exp(-k |x - y|) / (4 pi |x - y|)
+#[derive(Clone, Copy, Debug)]
+pub struct ModifiedHelmholtz {
+    pub wavenumber: f64,
+}
+
+impl ModifiedHelmholtz {
+    pub fn new(wavenumber: f64) -> Self {
+        Self { wavenumber }
+    }
+}
+
+impl Kernel for ModifiedHelmholtz {
+    type T = f64;
+
+    fn evaluate(&self, source: &[f64; 3], target: &[f64; 3]) -> f64 {
+        let r = distance(source, target);
+        if r == 0. {
+            0.
+        } else {
+            (-self.wavenumber * r).exp() / (4. * PI * r)
+        }
+    }
+
+    fn evaluate_batch(&self, sources: &[[f64; 3]], targets: &[[f64; 3]], result: &mut [f64]) {
+        let nsources = sources.len();
+        assert_eq!(result.len(), nsources * targets.len());
+
+        let scale = f64x4::splat(1. / (4. * PI));
+        let k = f64x4::splat(-self.wavenumber);
+        let zero = f64x4::splat(0.);
+
+        for (target, row) in targets.iter().zip(result.chunks_exact_mut(nsources)) {
+            for (chunk, res) in sources.chunks(LANES).zip(row.chunks_mut(LANES)) {
+                let r = distance_simd(target, gather_sources(chunk, target));
+                let value = (k * r).exp() * scale / r;
+                let value = r.simd_eq(zero).select(zero, value);
+                res.copy_from_slice(&value.to_array()[..res.len()]);
+            }
+        }
+    }
+}
+
+// Helmholtz kernel, exp(i k |x - y|) / (4 pi |x - y|)
+#[derive(Clone, Copy, Debug)]
+pub struct Helmholtz {
+    pub wavenumber: f64,
+}
+
+impl Helmholtz {
+    pub fn new(wavenumber: f64) -> Self {
+        Self { wavenumber }
+    }
+}
+
+impl Kernel for Helmholtz {
+    type T = Complex64;
+
+    const COMPLEX: bool = true;
+
+    fn evaluate(&self, source: &[f64; 3], target: &[f64; 3]) -> Complex64 {
+        let r = distance(source, target);
+        if r == 0. {
+            Complex64::zero()
+        } else {
+            let kr = self.wavenumber * r;
+            Complex64::new(kr.cos(), kr.sin()) / (4. * PI * r)
+        }
+    }
+
+    fn evaluate_batch(
+        &self,
+        sources: &[[f64; 3]],
+        targets: &[[f64; 3]],
+        result: &mut [Complex64],
+    ) {
+        let nsources = sources.len();
+        assert_eq!(result.len(), nsources * targets.len());
+
+        let scale = f64x4::splat(1. / (4. * PI));
+        let k = f64x4::splat(self.wavenumber);
+        let zero = f64x4::splat(0.);
+
+        for (target, row) in targets.iter().zip(result.chunks_exact_mut(nsources)) {
+            for (chunk, res) in sources.chunks(LANES).zip(row.chunks_mut(LANES)) {
+                let r = distance_simd(target, gather_sources(chunk, target));
+                let kr = k * r;
+                let inv_r = r.simd_eq(zero).select(zero, scale / r);
+
+                let re = (kr.cos() * inv_r).to_array();
+                let im = (kr.sin() * inv_r).to_array();
+
+                for (l, c) in res.iter_mut().enumerate() {
+                    *c = Complex64::new(re[l], im[l]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close<T: Into<Complex64>>(expected: T, found: T) {
+        let (expected, found) = (expected.into(), found.into());
+        assert!(
+            (expected - found).norm() <= 1e-14 * expected.norm().max(1.),
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+
+    fn points(n: usize, shift: f64) -> Vec<[f64; 3]> {
+        (0..n)
+            .map(|i| {
+                let i = i as f64;
+                [
+                    0.1 * i + shift,
+                    0.3 * (i * 0.7).sin(),
+                    0.2 * (i * 1.3).cos(),
+                ]
+            })
+            .collect()
+    }
+
+    // Batches of every length around the lane width, with targets that coincide with some sources
+    fn check_batch<K: Kernel>(kernel: &K) {
+        for nsources in 1..=2 * LANES + 1 {
+            let sources = points(nsources, 0.);
+            let mut targets = points(3, 0.05);
+            targets.push(sources[nsources - 1]);
+
+            let mut result = vec![K::T::zero(); nsources * targets.len()];
+            kernel.evaluate_batch(&sources, &targets, &mut result);
+
+            for (i, t) in targets.iter().enumerate() {
+                for (j, s) in sources.iter().enumerate() {
+                    assert_close(kernel.evaluate(s, t), result[i * nsources + j]);
+                }
+            }
+            assert_close(K::T::zero(), result[result.len() - 1]);
+        }
+    }
+
+    #[test]
+    fn evaluate_batch_matches_evaluate() {
+        check_batch(&Laplace);
+        check_batch(&ModifiedHelmholtz::new(1.5));
+        check_batch(&Helmholtz::new(2.5));
+    }
+
+    // Offset of index i along an axis of length n in circulant order, None for the padding
+    fn circulant(i: usize, n: usize) -> Option<f64> {
+        if 2 * i == n {
+            None
+        } else if 2 * i < n {
+            Some(i as f64)
+        } else {
+            Some(i as f64 - n as f64)
+        }
+    }
+
+    fn check_grid<K: Kernel>(kernel: &K, centre: [f64; 3]) {
+        let spacing = 0.25;
+        let shape = [4, 5, 3];
+        let [p, q, r] = shape;
+
+        let mut result = vec![K::T::zero(); p * q * r];
+        kernel.evaluate_grid(&centre, spacing, shape, &mut result);
+
+        for i in 0..p {
+            for j in 0..q {
+                for k in 0..r {
+                    let found = result[i * q * r + j * r + k];
+                    let expected = match (circulant(i, p), circulant(j, q), circulant(k, r)) {
+                        (Some(di), Some(dj), Some(dk)) => kernel.evaluate(
+                            &[0.; 3],
+                            &[
+                                centre[0] + di * spacing,
+                                centre[1] + dj * spacing,
+                                centre[2] + dk * spacing,
+                            ],
+                        ),
+                        _ => K::T::zero(),
+                    };
+                    assert_close(expected, found);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn evaluate_grid_matches_evaluate() {
+        for centre in [[0.; 3], [2., -1., 3.]] {
+            check_grid(&Laplace, centre);
+            check_grid(&ModifiedHelmholtz::new(1.5), centre);
+            check_grid(&Helmholtz::new(2.5), centre);
+        }
+    }
+}
@@ -3,7 +3,23 @@
 #![feature(array_chunks)]
 #![feature(slice_as_chunks)]
 #![feature(portable_simd)]
+pub mod aligned;
+pub mod blas1;
+pub mod cachesim;
+pub mod distributions;
 pub mod dotp;
 pub mod hadamard;
 pub mod helpers;
+pub mod kernel;
 pub mod m2l;
+pub mod operators;
+pub mod p2p;
+pub mod perf;
+pub mod periodic;
+pub mod prefetch;
+pub mod results;
+pub mod roofline;
+pub mod stream;
+pub mod svd;
+pub mod timing;
+pub mod tune;
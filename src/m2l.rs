@@ -1,6 +1,6 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
     time::Instant,
 };
 
@@ -8,21 +8,28 @@ use itertools::*;
 use num::{Float, Complex, complex::Complex64, One, Zero};
 use rayon::prelude::*;
 
-use bempp_tree::types::{domain::Domain, morton::MortonKey, single_node::SingleNodeTree};
+use bempp_tree::types::{domain::Domain, morton::MortonKey};
 
 use crate::{
-    hadamard::hadamard_product_naive,
-    helpers::{fft_like_data_arc, kernel_like_data_transpose, m2l_like_data, m2l_like_data_arc, fft_like_data_arc_vec, kernel_like_data, transpose, fft_like_data_transposed},
+    aligned::{alloc_buffers, AlignedVec, AllocPolicy},
+    hadamard::{hadamard_product_naive, HadamardFn},
+    helpers::{fft_like_data_arc, m2l_like_data, m2l_like_data_arc, fft_like_data_arc_vec, kernel_like_data, transpose, fft_like_data_transposed},
+    periodic::{periodic_interaction_list, periodic_neighbors, Boundary},
+    prefetch::{prefetch_ptr, PrefetchPolicy},
+    timing::{Phase, PhaseTimes},
 };
 
+// Expansion buffer of a key, locked individually by the workers
+type Expansion = Arc<Mutex<AlignedVec<Complex64>>>;
+type Expansions = HashMap<MortonKey, Expansion>;
 
-pub fn m2l_naive(expansion_order: usize, tree: &SingleNodeTree) {
-    let mut data = m2l_like_data(expansion_order, tree);
+
+pub fn m2l_naive(expansion_order: usize, leaves: &HashSet<MortonKey>) {
+    let mut data = m2l_like_data(expansion_order, leaves);
 
     // Iterate through all keys, pull up their interaction lists and save some random data to them
     let keys: Vec<MortonKey> = data.keys().cloned().collect();
 
-    let s = Instant::now();
     for key in keys.iter() {
         let interaction_list: Vec<MortonKey> = key
             .parent()
@@ -38,18 +45,16 @@ pub fn m2l_naive(expansion_order: usize, tree: &SingleNodeTree) {
             entry.and_modify(|e| e.iter_mut().for_each(|x| *x += 0.));
         }
     }
-    println!("M2L naive {:?}", s.elapsed().as_millis());
 }
 
-pub fn m2l_naive_par(expansion_order: usize, tree: &SingleNodeTree) {
-    let data = m2l_like_data_arc(expansion_order, tree);
-    let fft_data = fft_like_data_arc(expansion_order, tree);
-    let ifft_data = fft_like_data_arc(expansion_order, tree);
+pub fn m2l_naive_par(expansion_order: usize, leaves: &HashSet<MortonKey>, alloc: AllocPolicy) {
+    let data = m2l_like_data_arc(expansion_order, leaves);
+    let fft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+    let ifft_data = fft_like_data_arc(expansion_order, leaves, alloc);
 
     // Iterate through all keys, pull up their interaction lists and save some random data to them
     let keys: Vec<MortonKey> = data.keys().cloned().collect();
 
-    let s = Instant::now();
     keys.into_par_iter().for_each(|key| {
         let interaction_list: Vec<MortonKey> = key
             .parent()
@@ -62,32 +67,38 @@ pub fn m2l_naive_par(expansion_order: usize, tree: &SingleNodeTree) {
         // Scatter data to all sources in interaction list
         interaction_list.into_iter().for_each(|source| {
             // Get data and scatter
-            let entry_arc: &Arc<std::sync::Mutex<Vec<num::Complex<f64>>>> = ifft_data.get(&source).unwrap();
-            let mut entry = entry_arc.lock().unwrap();
-            entry.iter_mut().for_each(|x| *x += Complex64::new(0.0, 0.0));
+            // Sources may be missing from sparse key sets
+            if let Some(entry_arc) = ifft_data.get(&source) {
+                let mut entry = entry_arc.lock().unwrap();
+                entry.iter_mut().for_each(|x| *x += Complex64::new(0.0, 0.0));
+            }
         });
     });
-    println!("M2L naive par {:?}", s.elapsed());
 }
 
-pub fn m2l_parent_par_naive(expansion_order: usize, tree: &SingleNodeTree) {
-    let data = m2l_like_data_arc(expansion_order, tree);
+pub fn m2l_parent_par_naive(
+    expansion_order: usize,
+    leaves: &HashSet<MortonKey>,
+    kernel_data: &RwLock<AlignedVec<Complex64>>,
+    alloc: AllocPolicy,
+) -> PhaseTimes {
+    let data = m2l_like_data_arc(expansion_order, leaves);
     let mut keys: Vec<MortonKey> = data.keys().cloned().collect();
     keys.sort();
 
     // Iterate over parents now
-    let scatter_idxs = Arc::new(RwLock::new(scatter_displacements()));
-
-    // let kernel_data = kernel_like_data::<f64>();
-    let kernel_data = RwLock::new(kernel_like_data_transpose(expansion_order));
+    let scatter_idxs = Arc::new(RwLock::new(scatter_displacements(Boundary::Free)));
 
+    let times = PhaseTimes::new();
     let s = Instant::now();
-    let fft_data = fft_like_data_arc(expansion_order, tree);
-    let ifft_data = fft_like_data_arc(expansion_order, tree);
+    let fft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+    let ifft_data = fft_like_data_arc(expansion_order, leaves, alloc);
 
     // For non-uniform trees simply have to iterate over each key in a level, computing for keys below to ensure
     // existence.
     keys.par_chunks_exact(8).for_each(|children| {
+        let t = Instant::now();
+
         let mut halo_data = Vec::new();
 
         let parent = children[0].parent();
@@ -106,22 +117,29 @@ pub fn m2l_parent_par_naive(expansion_order: usize, tree: &SingleNodeTree) {
             })
             .collect_vec();
 
-        // Get all halo data
+        // Get all halo data, which may be missing from sparse key sets
         for &pnc in parent_neigbors_children.iter() {
             if pnc != sentinel {
-                halo_data.push(Some(Arc::clone(ifft_data.get(&pnc).unwrap())))
+                halo_data.push(ifft_data.get(&pnc).map(Arc::clone))
             } else {
                 halo_data.push(None)
             }
         }
 
+        times.add(Phase::HaloGather, t.elapsed());
+        let t = Instant::now();
+
         let mut sibling_set = Vec::new();
 
         for c in children.iter() {
             sibling_set.push(Arc::clone(fft_data.get(&c).unwrap()))
         }
 
-        let hadamard_products = hadamard_product_naive(expansion_order, &sibling_set, &kernel_data);
+        times.add(Phase::SiblingGather, t.elapsed());
+        let t = Instant::now();
+        let hadamard_products = hadamard_product_naive(expansion_order, &sibling_set, kernel_data);
+        times.add(Phase::Hadamard, t.elapsed());
+        let t = Instant::now();
 
         for (i, dat) in halo_data.iter().enumerate() {
             if let Some(dat) = dat {
@@ -137,14 +155,74 @@ pub fn m2l_parent_par_naive(expansion_order: usize, tree: &SingleNodeTree) {
                 }
             }
         }
+
+        times.add(Phase::Scatter, t.elapsed());
     });
-    println!("M2L parent par naive {:?}", s.elapsed());
+    times.set_total(s.elapsed());
+
+    times
+}
+
+
+
+
+// Address and length of an expansion buffer, taken once so that it can be prefetched without
+// locking the buffer. A prefetch reads nothing, so racing the worker holding the lock is harmless.
+#[derive(Clone, Copy)]
+struct BufferPtr {
+    ptr: *const Complex64,
+    len: usize,
+}
+
+unsafe impl Send for BufferPtr {}
+unsafe impl Sync for BufferPtr {}
+
+// The buffers stay in place for as long as `data` is alive.
+fn buffer_ptrs(data: &Expansions) -> HashMap<MortonKey, BufferPtr> {
+    data.iter()
+        .map(|(key, buffer)| {
+            let buffer = buffer.lock().unwrap();
+            let ptr = BufferPtr {
+                ptr: buffer.as_ptr(),
+                len: buffer.len(),
+            };
+            (*key, ptr)
+        })
+        .collect()
 }
 
+// Prefetch the data of sibling set `k` of a block of keys, if the block has one: the start of each
+// of its signal buffers, which the Hadamard product reads, and of each of its halo buffers, which
+// the scatter updates.
+fn prefetch_sibling_set(
+    key_block: &[MortonKey],
+    k: usize,
+    lines: usize,
+    fft_ptrs: &HashMap<MortonKey, BufferPtr>,
+    ifft_ptrs: &HashMap<MortonKey, BufferPtr>,
+) {
+    let Some(children) = key_block.get(8 * k..8 * (k + 1)) else {
+        return;
+    };
 
+    for c in children.iter() {
+        if let Some(signal) = fft_ptrs.get(c) {
+            prefetch_ptr(signal.ptr, signal.len, lines, false);
+        }
+    }
 
+    let parent = children[0].parent();
+    for pnc in parent.all_neighbors().iter().flatten().flat_map(|p| p.children()) {
+        if let Some(halo) = ifft_ptrs.get(&pnc) {
+            prefetch_ptr(halo.ptr, halo.len, lines, true);
+        }
+    }
+}
 
-pub fn scatter_displacements() -> Vec<Vec<usize>> {
+// For each child of the parent halo, the indices of the siblings whose interaction lists contain it.
+// The halo is ordered as returned by `neighbors()` in free mode, and as `periodic_neighbors()` in
+// periodic mode.
+pub fn scatter_displacements(boundary: Boundary) -> Vec<Vec<usize>> {
     let domain = Domain {
         diameter: [1.0, 1.0, 1.0],
         origin: [0., 0., 0.],
@@ -158,7 +236,10 @@ pub fn scatter_displacements() -> Vec<Vec<usize>> {
 
     // Find its parent siblings (halo)
     let parent = key.parent();
-    let halo = parent.neighbors();
+    let halo = match boundary {
+        Boundary::Free => parent.neighbors(),
+        Boundary::Periodic => periodic_neighbors(&parent, &domain),
+    };
 
     let halo_children = halo.iter().flat_map(|h| h.children()).collect_vec();
 
@@ -172,13 +253,16 @@ pub fn scatter_displacements() -> Vec<Vec<usize>> {
     // Need to find indices of each sibling's interaction list inside the halo children.
 
     for (i, sibling) in siblings.iter().enumerate() {
-        let interaction_list: Vec<MortonKey> = sibling
-            .parent()
-            .neighbors()
-            .iter()
-            .flat_map(|pn| pn.children())
-            .filter(|pnc| !sibling.is_adjacent_same_level(pnc))
-            .collect();
+        let interaction_list: Vec<MortonKey> = match boundary {
+            Boundary::Free => sibling
+                .parent()
+                .neighbors()
+                .iter()
+                .flat_map(|pn| pn.children())
+                .filter(|pnc| !sibling.is_adjacent_same_level(pnc))
+                .collect(),
+            Boundary::Periodic => periodic_interaction_list(sibling, &domain),
+        };
 
         for source in interaction_list.iter() {
             let idx = halo_children_idxs.get(source).unwrap();
@@ -190,14 +274,277 @@ pub fn scatter_displacements() -> Vec<Vec<usize>> {
     scatter_idxs
 }
 
+// Parent level M2L with periodic boundary conditions, every parent has a full 26 neighbour halo and
+// the scatter writes to the periodic images of boxes at the edge of the domain. The parents must be
+// at `MIN_PERIODIC_LEVEL` or deeper.
+pub fn m2l_parent_par_periodic(
+    expansion_order: usize,
+    leaves: &HashSet<MortonKey>,
+    domain: &Domain,
+    kernel_data: &RwLock<AlignedVec<Complex64>>,
+    alloc: AllocPolicy,
+) -> PhaseTimes {
+    let data = m2l_like_data_arc(expansion_order, leaves);
+    let mut keys: Vec<MortonKey> = data.keys().cloned().collect();
+    keys.sort();
+
+    let scatter_idxs = Arc::new(RwLock::new(scatter_displacements(Boundary::Periodic)));
+
+    let times = PhaseTimes::new();
+    let s = Instant::now();
+    let fft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+    let ifft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+
+    keys.par_chunks_exact(8).for_each(|children| {
+        let t = Instant::now();
+
+        let parent = children[0].parent();
+
+        // Get all halo data, in a non-uniform tree periodic images may still be missing
+        let halo_data = periodic_neighbors(&parent, domain)
+            .iter()
+            .flat_map(|p| p.children())
+            .map(|pnc| ifft_data.get(&pnc).map(Arc::clone))
+            .collect_vec();
+
+        times.add(Phase::HaloGather, t.elapsed());
+        let t = Instant::now();
+
+        let mut sibling_set = Vec::new();
+
+        for c in children.iter() {
+            sibling_set.push(Arc::clone(fft_data.get(c).unwrap()))
+        }
+
+        times.add(Phase::SiblingGather, t.elapsed());
+        let t = Instant::now();
+        let hadamard_products = hadamard_product_naive(expansion_order, &sibling_set, kernel_data);
+        times.add(Phase::Hadamard, t.elapsed());
+        let t = Instant::now();
+
+        for (i, dat) in halo_data.iter().enumerate() {
+            if let Some(dat) = dat {
+                // The indices of potentials from sibling set to save from
+                let save_idxs = &scatter_idxs.read().unwrap()[i];
+
+                // In real life would also have to lookup appropriate convolution that is being saved
+                for _ in save_idxs.iter() {
+                    dat.lock()
+                        .unwrap()
+                        .iter_mut()
+                        .for_each(|x| *x += Complex64{re: 1.0, im: 1.0} );
+                }
+            }
+        }
+
+        times.add(Phase::Scatter, t.elapsed());
+    });
+    times.set_total(s.elapsed());
+
+    times
+}
+
+
+// Inputs of one block of sibling sets, packed ahead of the compute stage.
+struct Stage {
+    // Copies of the signals, 8 per sibling set, laid out contiguously in processing order
+    signals: Vec<Expansion>,
+    // Halo buffers of each sibling set, ordered as `scatter_displacements`
+    halos: Vec<Vec<Option<Expansion>>>,
+}
+
+impl Stage {
+    // Room for a block of `block_size` keys, allocated with the same policy as the expansions so
+    // that the drivers can be compared under each policy.
+    fn new(expansion_order: usize, block_size: usize, alloc: AllocPolicy) -> Self {
+        let n = 2 * expansion_order - 1;
+        let &(m, n, o) = &(n, n, n);
+
+        let p = m + 1;
+        let q = n + 1;
+        let r = o + 1;
+        let size_real = p * q * (r / 2 + 1);
+
+        let signals = alloc_buffers(Complex64::zero(), size_real, block_size, 8, alloc)
+            .into_iter()
+            .map(|s| Arc::new(Mutex::new(s)))
+            .collect();
+
+        Self {
+            signals,
+            halos: Vec::new(),
+        }
+    }
+
+    // Copy the signals of a block of keys into the stage and look up their halos. The block must
+    // hold whole sibling sets.
+    fn pack(
+        &mut self,
+        key_block: &[MortonKey],
+        fft_data: &Expansions,
+        ifft_data: &Expansions,
+        times: &PhaseTimes,
+    ) {
+        assert!(
+            key_block.len() % 8 == 0,
+            "a block of {} keys doesn't hold whole sibling sets",
+            key_block.len()
+        );
+
+        self.halos = key_block
+            .par_chunks_exact(8)
+            .zip(self.signals.par_chunks_exact(8))
+            .map(|(children, signals)| {
+                let t = Instant::now();
+
+                for (c, staged) in children.iter().zip(signals.iter()) {
+                    let signal = fft_data.get(c).unwrap().lock().unwrap();
+                    staged.lock().unwrap().copy_from_slice(&signal);
+                }
+
+                times.add(Phase::SiblingGather, t.elapsed());
+                let t = Instant::now();
+
+                // Halo data may be missing from sparse key sets
+                let halo = children[0]
+                    .parent()
+                    .all_neighbors()
+                    .iter()
+                    .flat_map(|p| {
+                        if let Some(p) = p {
+                            p.children()
+                                .iter()
+                                .map(|pnc| ifft_data.get(pnc).map(Arc::clone))
+                                .collect_vec()
+                        } else {
+                            vec![None; 8]
+                        }
+                    })
+                    .collect_vec();
+
+                times.add(Phase::HaloGather, t.elapsed());
+
+                halo
+            })
+            .collect();
+    }
+}
+
+// Keys per block of the pipelined driver when the block size isn't tuned
+pub const DEFAULT_PIPELINED_BLOCK_SIZE: usize = 4096;
+
+// Parent level M2L over blocks of `block_size` keys, double buffered so that the signals and halos
+// of the next block are packed into a staging buffer while the current block is computed. The
+// Hadamard products then stream through contiguous signals rather than gathering them from
+// scattered buffers. Both stages are parallel and share the rayon pool, which balances the workers
+// between them. The leaves must form whole sibling sets.
+pub fn m2l_parent_par_pipelined(
+    expansion_order: usize,
+    leaves: &HashSet<MortonKey>,
+    kernel_data: &RwLock<AlignedVec<Complex64>>,
+    alloc: AllocPolicy,
+    hadamard: HadamardFn,
+    block_size: usize,
+) -> PhaseTimes {
+    assert!(
+        block_size > 0 && block_size % 8 == 0,
+        "block size must be a positive multiple of 8 keys"
+    );
+
+    let data = m2l_like_data_arc(expansion_order, leaves);
+    let mut keys: Vec<MortonKey> = data.keys().cloned().collect();
+    keys.sort();
+
+    let scatter_idxs = scatter_displacements(Boundary::Free);
+
+    let times = PhaseTimes::new();
+    let s = Instant::now();
+    let fft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+    let ifft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+
+    let block_size = block_size.min(keys.len().max(8));
+    let mut current = Stage::new(expansion_order, block_size, alloc);
+    let mut next = Stage::new(expansion_order, block_size, alloc);
+
+    let blocks = keys.chunks(block_size).collect_vec();
+
+    if let Some(first) = blocks.first() {
+        current.pack(first, &fft_data, &ifft_data, &times);
+    }
+
+    // Hadamard products and scatter of a packed block
+    let compute = |stage: &Stage| {
+        stage
+            .signals
+            .par_chunks_exact(8)
+            .zip(stage.halos.par_iter())
+            .for_each(|(sibling_set, halo_data)| {
+                let t = Instant::now();
+                let sibling_set = sibling_set.to_vec();
+                let _hadamard_products = hadamard(expansion_order, &sibling_set, kernel_data);
+                times.add(Phase::Hadamard, t.elapsed());
+                let t = Instant::now();
+
+                for (i, dat) in halo_data.iter().enumerate() {
+                    if let Some(dat) = dat {
+                        // The indices of potentials from sibling set to save from
+                        let save_idxs = &scatter_idxs[i];
+                        let mut dat_mut_ref = dat.lock().unwrap();
+
+                        // In real life would also have to lookup the convolution being saved
+                        for _ in save_idxs.iter() {
+                            dat_mut_ref
+                                .iter_mut()
+                                .for_each(|x| *x += Complex64{re: 1.0, im: 1.0} );
+                        }
+                    }
+                }
+
+                times.add(Phase::Scatter, t.elapsed());
+            })
+    };
+
+    for b in 0..blocks.len() {
+        rayon::join(
+            || compute(&current),
+            || {
+                if let Some(key_block) = blocks.get(b + 1) {
+                    next.pack(key_block, &fft_data, &ifft_data, &times);
+                }
+            },
+        );
+
+        std::mem::swap(&mut current, &mut next);
+    }
+
+    times.set_total(s.elapsed());
+
+    times
+}
+
 
 #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
 pub mod x86 {   
     use super::*;
     use std::arch::x86_64::*;
-    use crate::hadamard::hadamard_product_simd;
+    use crate::hadamard::x86::hadamard_product_simd;
+
+    // Keys per block when the block size isn't tuned
+    pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+    pub fn m2l_parent_par_simd(
+        expansion_order: usize,
+        leaves: &HashSet<MortonKey>,
+        kernel_data: &RwLock<AlignedVec<Complex64>>,
+        alloc: AllocPolicy,
+        prefetch: PrefetchPolicy,
+        block_size: usize,
+    ) -> PhaseTimes {
+        assert!(
+            block_size > 0 && block_size % 8 == 0,
+            "block size must be a positive multiple of 8 keys"
+        );
 
-    pub fn m2l_parent_par_simd(expansion_order: usize, tree: &SingleNodeTree) {
         let n = 2 * expansion_order - 1;
         let &(m, n, o) = &(n, n, n);
 
@@ -206,32 +553,50 @@ pub mod x86 {
         let r = o + 1;
         let size_real = p * q * (r / 2 + 1);
 
-        let data = m2l_like_data_arc(expansion_order, tree);
+        let data = m2l_like_data_arc(expansion_order, leaves);
         let mut keys: Vec<MortonKey> = data.keys().cloned().collect();
         keys.sort();
 
         // Iterate over parents now
-        let scatter_idxs = Arc::new(RwLock::new(scatter_displacements()));
-
-        let kernel_data = RwLock::new(kernel_like_data_transpose(expansion_order));
+        let scatter_idxs = Arc::new(RwLock::new(scatter_displacements(Boundary::Free)));
 
+        let times = PhaseTimes::new();
         let s = Instant::now();
-        let fft_data = fft_like_data_arc(expansion_order, tree);
-        let ifft_data = fft_like_data_arc(expansion_order, tree);
+        let fft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+        let ifft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+
+        let ptrs = (prefetch.distance > 0).then(|| {
+            let t = Instant::now();
+            let ptrs = (buffer_ptrs(&fft_data), buffer_ptrs(&ifft_data));
+            times.add(Phase::Prefetch, t.elapsed());
+            ptrs
+        });
+
+        // The last block may be partial
+        keys.chunks(block_size).for_each(|key_block| {
+            key_block.par_chunks_exact(8).enumerate().for_each(|(k, children)| {
+                // Rayon hands each worker a contiguous run of sibling sets, so set k + d is
+                // usually processed by the same worker d sets later
+                if let Some((fft_ptrs, ifft_ptrs)) = &ptrs {
+                    let t = Instant::now();
+                    let k = k + prefetch.distance;
+                    prefetch_sibling_set(key_block, k, prefetch.lines, fft_ptrs, ifft_ptrs);
+                    times.add(Phase::Prefetch, t.elapsed());
+                }
+
+                let t = Instant::now();
 
-        keys.chunks_exact(4096).for_each(|key_block| {
-            key_block.par_chunks_exact(8).for_each(|children| {
-                let mut halo_data = vec::new();
+                let mut halo_data = Vec::new();
 
                 let parent = children[0].parent();
 
-                let sentinel = mortonkey::default();
+                let sentinel = MortonKey::default();
 
                 let parent_neigbors_children = parent
                     .all_neighbors()
                     .iter()
                     .flat_map(|p| {
-                        if let some(p) = p {
+                        if let Some(p) = p {
                             p.children()
                         } else {
                             vec![sentinel; 8]
@@ -239,24 +604,31 @@ pub mod x86 {
                     })
                     .collect_vec();
 
-                // get all halo data
+                // get all halo data, which may be missing from sparse key sets
                 for &pnc in parent_neigbors_children.iter() {
                     if pnc != sentinel {
-                        halo_data.push(some(arc::clone(ifft_data.get(&pnc).unwrap())))
+                        halo_data.push(ifft_data.get(&pnc).map(Arc::clone))
                     } else {
-                        halo_data.push(none)
+                        halo_data.push(None)
                     }
                 }
 
-                let mut sibling_set = vec::new();
+                times.add(Phase::HaloGather, t.elapsed());
+                let t = Instant::now();
+
+                let mut sibling_set = Vec::new();
 
                 for c in children.iter() {
-                    sibling_set.push(arc::clone(fft_data.get(&c).unwrap()))
+                    sibling_set.push(Arc::clone(fft_data.get(&c).unwrap()))
                 }
+                times.add(Phase::SiblingGather, t.elapsed());
                 // until this point runtime is negligible (order 0.5s in total, including initial data instantiation)
 
                 // takes a little over 1 second
-                let hadamard_products = hadamard_product_simd(expansion_order, &sibling_set, &kernel_data);
+                let t = Instant::now();
+                let hadamard_products = hadamard_product_simd(expansion_order, &sibling_set, kernel_data);
+                times.add(Phase::Hadamard, t.elapsed());
+                let t = Instant::now();
 
                 // this takes more than 3 seconds for demo problem, same obv for naive code
                 // is there any way to use simd for the saves, mimicking what dhairya manages to do?
@@ -264,7 +636,7 @@ pub mod x86 {
                 // that wouldn't even reduce the number of saves, just move this loop elsewhere???
 
                 for (i, dat) in halo_data.iter().enumerate() {
-                    if let some(dat) = dat {
+                    if let Some(dat) = dat {
                         // the indices of potentials from sibling set to save from
                         let save_idxs = &scatter_idxs.read().unwrap()[i];
                         let mut dat_mut_ref = dat.lock().unwrap();
@@ -283,6 +655,8 @@ pub mod x86 {
                         }
                     }
                 }
+
+                times.add(Phase::Scatter, t.elapsed());
             });
 
         });
@@ -346,7 +720,9 @@ pub mod x86 {
         //         }
         //     }
         // });
-        println!("M2L parent par SIMD {:?}", s.elapsed());
+        times.set_total(s.elapsed());
+
+        times
     }
 
 
@@ -373,7 +749,22 @@ pub mod aarch64 {
     use std::arch::aarch64::*;
     use crate::hadamard::aarch64::hadamard_product_simd_neon;
 
-    pub fn m2l_parent_par_simd(expansion_order: usize, tree: &SingleNodeTree) {
+    // Keys per block when the block size isn't tuned
+    pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+    pub fn m2l_parent_par_simd(
+        expansion_order: usize,
+        leaves: &HashSet<MortonKey>,
+        kernel_data: &RwLock<AlignedVec<Complex64>>,
+        alloc: AllocPolicy,
+        prefetch: PrefetchPolicy,
+        block_size: usize,
+    ) -> PhaseTimes {
+        assert!(
+            block_size > 0 && block_size % 8 == 0,
+            "block size must be a positive multiple of 8 keys"
+        );
+
 
         let n = 2 * expansion_order - 1;
         let &(m, n, o) = &(n, n, n);
@@ -383,21 +774,39 @@ pub mod aarch64 {
         let r = o + 1;
         let size_real = p * q * (r / 2 + 1);
 
-        let data = m2l_like_data_arc(expansion_order, tree);
+        let data = m2l_like_data_arc(expansion_order, leaves);
         let mut keys: Vec<MortonKey> = data.keys().cloned().collect();
         keys.sort();
 
         // Iterate over parents now
-        let scatter_idxs = Arc::new(RwLock::new(scatter_displacements()));
-
-        let kernel_data = RwLock::new(kernel_like_data_transpose(expansion_order));
+        let scatter_idxs = Arc::new(RwLock::new(scatter_displacements(Boundary::Free)));
 
+        let times = PhaseTimes::new();
         let s = Instant::now();
-        let fft_data = fft_like_data_arc(expansion_order, tree);
-        let ifft_data = fft_like_data_arc(expansion_order, tree);
+        let fft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+        let ifft_data = fft_like_data_arc(expansion_order, leaves, alloc);
+
+        let ptrs = (prefetch.distance > 0).then(|| {
+            let t = Instant::now();
+            let ptrs = (buffer_ptrs(&fft_data), buffer_ptrs(&ifft_data));
+            times.add(Phase::Prefetch, t.elapsed());
+            ptrs
+        });
+
+        // The last block may be partial
+        keys.chunks(block_size).for_each(|key_block| {
+            key_block.par_chunks_exact(8).enumerate().for_each(|(k, children)| {
+                // Rayon hands each worker a contiguous run of sibling sets, so set k + d is
+                // usually processed by the same worker d sets later
+                if let Some((fft_ptrs, ifft_ptrs)) = &ptrs {
+                    let t = Instant::now();
+                    let k = k + prefetch.distance;
+                    prefetch_sibling_set(key_block, k, prefetch.lines, fft_ptrs, ifft_ptrs);
+                    times.add(Phase::Prefetch, t.elapsed());
+                }
+
+                let t = Instant::now();
 
-        keys.chunks_exact(512).for_each(|key_block| {
-            key_block.par_chunks_exact(8).for_each(|children| {
                 let mut halo_data = Vec::new();
 
                 let parent = children[0].parent();
@@ -416,24 +825,31 @@ pub mod aarch64 {
                     })
                     .collect_vec();
 
-                // get all halo data
+                // get all halo data, which may be missing from sparse key sets
                 for &pnc in parent_neigbors_children.iter() {
                     if pnc != sentinel {
-                        halo_data.push(Some(Arc::clone(ifft_data.get(&pnc).unwrap())))
+                        halo_data.push(ifft_data.get(&pnc).map(Arc::clone))
                     } else {
                         halo_data.push(None)
                     }
                 }
 
+                times.add(Phase::HaloGather, t.elapsed());
+                let t = Instant::now();
+
                 let mut sibling_set = Vec::new();
 
                 for c in children.iter() {
                     sibling_set.push(Arc::clone(fft_data.get(&c).unwrap()))
                 }
+                times.add(Phase::SiblingGather, t.elapsed());
                 // until this point runtime is negligible (order 0.5s in total, including initial data instantiation)
 
                 // takes a little over 1 second
-                let hadamard_products = hadamard_product_simd_neon(expansion_order, &sibling_set, &kernel_data);
+                let t = Instant::now();
+                let hadamard_products = hadamard_product_simd_neon(expansion_order, &sibling_set, kernel_data);
+                times.add(Phase::Hadamard, t.elapsed());
+                let t = Instant::now();
 
                 // this takes more than 3 seconds for demo problem, same obv for naive code
                 // is there any way to use simd for the saves, mimicking what dhairya manages to do?
@@ -454,11 +870,15 @@ pub mod aarch64 {
                         }
                     }
                 }
+
+                times.add(Phase::Scatter, t.elapsed());
             });
 
         });
 
-        println!("M2L parent par SIMD {:?}", s.elapsed());
+        times.set_total(s.elapsed());
+
+        times
     }
 
 }
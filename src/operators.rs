@@ -0,0 +1,193 @@
+//! Check to equivalent surface operators for KIFMM, from pseudo-inverses of surface kernel matrices.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use rlst::{
+    algorithms::{linalg::LinAlg, traits::pseudo_inverse::Pinv},
+    common::traits::Eval,
+    dense::{rlst_mat, Dot, Shape},
+};
+
+use crate::{
+    helpers::surface_grid,
+    kernel::Kernel,
+    svd::{kernel_matrix, Mat, ALPHA_INNER, ALPHA_OUTER},
+};
+
+// Relative threshold below which singular values are discarded when forming pseudo-inverses.
+pub const PINV_THRESHOLD: f64 = 1e-12;
+
+// Upward and downward check to equivalent operators for boxes of a given width.
+pub struct C2EOperators {
+    // Upward check surface to upward equivalent surface, (ncoeffs, ncoeffs)
+    pub uc2e: Mat,
+    // Downward check surface to downward equivalent surface, (ncoeffs, ncoeffs)
+    pub dc2e: Mat,
+}
+
+// Regularised Moore-Penrose pseudo-inverse, V S^+ U^T, discarding singular values smaller than
+// `threshold` times the largest.
+pub fn pseudo_inverse(mat: Mat, threshold: f64) -> Mat {
+    let (s, ut, v) = mat.linalg().pinv(Some(threshold)).unwrap();
+    let s = s.unwrap();
+    let ut = ut.unwrap();
+    let v = v.unwrap();
+
+    // rlst returns the already inverted singular values
+    let k = s.shape().0;
+    let mut mat_s = rlst_mat![f64, (k, k)];
+    for i in 0..k {
+        mat_s[[i, i]] = s[[i, 0]];
+    }
+
+    v.dot(&mat_s).eval().dot(&ut).eval()
+}
+
+// Compute the check to equivalent operators for a box of the given width centred at the origin.
+// For the upward pass the equivalent surface lies inside the check surface, for the downward pass
+// the roles of the two surfaces are swapped.
+pub fn c2e_operators<K: Kernel<T = f64>>(
+    kernel: &K,
+    expansion_order: usize,
+    box_width: f64,
+) -> C2EOperators {
+    let centre = [0., 0., 0.];
+
+    let upward_equivalent = surface_grid(expansion_order, &centre, box_width, ALPHA_INNER);
+    let upward_check = surface_grid(expansion_order, &centre, box_width, ALPHA_OUTER);
+    let downward_equivalent = surface_grid(expansion_order, &centre, box_width, ALPHA_OUTER);
+    let downward_check = surface_grid(expansion_order, &centre, box_width, ALPHA_INNER);
+
+    let uc2e = kernel_matrix(kernel, &upward_equivalent, &upward_check);
+    let dc2e = kernel_matrix(kernel, &downward_equivalent, &downward_check);
+
+    C2EOperators {
+        uc2e: pseudo_inverse(uc2e, PINV_THRESHOLD),
+        dc2e: pseudo_inverse(dc2e, PINV_THRESHOLD),
+    }
+}
+
+// Per level cache of the check to equivalent operators, operators are computed the first time a
+// level is requested. Kernels that aren't scale invariant need a separate operator per level.
+pub struct C2ECache<K: Kernel<T = f64>> {
+    pub kernel: K,
+    pub expansion_order: usize,
+    // Diameter of the root box
+    pub diameter: f64,
+    cache: Mutex<HashMap<u64, Arc<C2EOperators>>>,
+}
+
+impl<K: Kernel<T = f64>> C2ECache<K> {
+    pub fn new(kernel: K, expansion_order: usize, diameter: f64) -> Self {
+        Self {
+            kernel,
+            expansion_order,
+            diameter,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, level: u64) -> Arc<C2EOperators> {
+        if let Some(operators) = self.cache.lock().unwrap().get(&level) {
+            return Arc::clone(operators);
+        }
+
+        // Compute outside of the lock, as the pseudo-inverses are expensive
+        let box_width = self.diameter / 2f64.powi(level as i32);
+        let operators = Arc::new(c2e_operators(&self.kernel, self.expansion_order, box_width));
+
+        let mut cache = self.cache.lock().unwrap();
+        Arc::clone(cache.entry(level).or_insert(operators))
+    }
+
+    // Convert downward check potentials, one column per box, as output by the M2L, into
+    // downward equivalent densities.
+    pub fn downward_equivalent(&self, level: u64, check_potentials: &Mat) -> Mat {
+        self.get(level).dc2e.dot(check_potentials).eval()
+    }
+
+    // Convert upward check potentials, one column per box, into multipole expansions.
+    pub fn upward_equivalent(&self, level: u64, check_potentials: &Mat) -> Mat {
+        self.get(level).uc2e.dot(check_potentials).eval()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::Laplace;
+
+    const EXPANSION_ORDER: usize = 4;
+
+    fn assert_close(expected: &Mat, found: &Mat) {
+        let (m, n) = expected.shape();
+        assert_eq!(found.shape(), (m, n));
+
+        let mut scale = 0f64;
+        for i in 0..m {
+            for j in 0..n {
+                scale = scale.max(expected[[i, j]].abs());
+            }
+        }
+
+        for i in 0..m {
+            for j in 0..n {
+                let (e, f) = (expected[[i, j]], found[[i, j]]);
+                assert!((e - f).abs() <= 1e-8 * scale, "expected {}, found {}", e, f);
+            }
+        }
+    }
+
+    fn surface_matrix(width: f64, inner_to_outer: bool) -> Mat {
+        let inner = surface_grid(EXPANSION_ORDER, &[0., 0., 0.], width, ALPHA_INNER);
+        let outer = surface_grid(EXPANSION_ORDER, &[0., 0., 0.], width, ALPHA_OUTER);
+
+        if inner_to_outer {
+            kernel_matrix(&Laplace, &inner, &outer)
+        } else {
+            kernel_matrix(&Laplace, &outer, &inner)
+        }
+    }
+
+    // A pinv(A) A = A holds only if rlst returns the singular values already inverted
+    #[test]
+    fn pseudo_inverse_is_generalised_inverse() {
+        for inner_to_outer in [true, false] {
+            let a = surface_matrix(1., inner_to_outer);
+            let pinv = pseudo_inverse(surface_matrix(1., inner_to_outer), PINV_THRESHOLD);
+
+            let found = a.dot(&pinv).eval().dot(&a).eval();
+            assert_close(&a, &found);
+        }
+    }
+
+    // Equivalent densities found from check potentials reproduce those potentials
+    #[test]
+    fn c2e_cache_reproduces_check_potentials() {
+        let cache = C2ECache::new(Laplace, EXPANSION_ORDER, 1.);
+        let level = 2;
+        let width = 1. / 4.;
+
+        assert!(Arc::ptr_eq(&cache.get(level), &cache.get(level)));
+        assert!(!Arc::ptr_eq(&cache.get(level), &cache.get(level + 1)));
+
+        let ncoeffs = 6 * (EXPANSION_ORDER - 1).pow(2) + 2;
+        let mut density = rlst_mat![f64, (ncoeffs, 2)];
+        for i in 0..ncoeffs {
+            density[[i, 0]] = 1.;
+            density[[i, 1]] = (i as f64).sin();
+        }
+
+        let upward = surface_matrix(width, true);
+        let check = upward.dot(&density).eval();
+        let equivalent = cache.upward_equivalent(level, &check);
+        assert_close(&check, &upward.dot(&equivalent).eval());
+
+        let downward = surface_matrix(width, false);
+        let check = downward.dot(&density).eval();
+        let equivalent = cache.downward_equivalent(level, &check);
+        assert_close(&check, &downward.dot(&equivalent).eval());
+    }
+}
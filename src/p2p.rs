@@ -0,0 +1,472 @@
+//! Near field (particle to particle) evaluation of Laplace potentials and gradients.
+use std::f64::consts::PI;
+use std::simd::{prelude::*, StdFloat};
+
+use rayon::prelude::*;
+
+use bempp_traits::tree::Tree;
+use bempp_tree::types::single_node::SingleNodeTree;
+
+// Signature shared by all P2P kernels, potentials and gradients at the targets are accumulated
+// into the output buffers.
+pub type P2PFn = fn(&[[f64; 3]], &[f64], &[[f64; 3]], &mut [f64], &mut [[f64; 3]]);
+
+const INV_4PI: f64 = 1. / (4. * PI);
+
+// Scalar reference implementation, uses a full precision square root.
+pub fn p2p_laplace_naive(
+    sources: &[[f64; 3]],
+    charges: &[f64],
+    targets: &[[f64; 3]],
+    potentials: &mut [f64],
+    gradients: &mut [[f64; 3]],
+) {
+    assert_eq!(sources.len(), charges.len());
+    assert_eq!(targets.len(), potentials.len());
+    assert_eq!(targets.len(), gradients.len());
+
+    for (i, t) in targets.iter().enumerate() {
+        let mut pot = 0.;
+        let mut grad = [0f64; 3];
+
+        for (s, q) in sources.iter().zip(charges.iter()) {
+            let dx = t[0] - s[0];
+            let dy = t[1] - s[1];
+            let dz = t[2] - s[2];
+            let r2 = dx * dx + dy * dy + dz * dz;
+
+            if r2 > 0. {
+                let inv_r = 1. / r2.sqrt();
+                let qinv_r = q * inv_r;
+                let qinv_r3 = qinv_r * inv_r * inv_r;
+
+                pot += qinv_r;
+                grad[0] -= dx * qinv_r3;
+                grad[1] -= dy * qinv_r3;
+                grad[2] -= dz * qinv_r3;
+            }
+        }
+
+        potentials[i] += pot * INV_4PI;
+        gradients[i][0] += grad[0] * INV_4PI;
+        gradients[i][1] += grad[1] * INV_4PI;
+        gradients[i][2] += grad[2] * INV_4PI;
+    }
+}
+
+// Portable SIMD implementation. There is no portable reciprocal square root, so the initial
+// estimate is found with the usual integer shift trick and refined with Newton iterations.
+pub fn p2p_laplace_portable(
+    sources: &[[f64; 3]],
+    charges: &[f64],
+    targets: &[[f64; 3]],
+    potentials: &mut [f64],
+    gradients: &mut [[f64; 3]],
+) {
+    assert_eq!(sources.len(), charges.len());
+    assert_eq!(targets.len(), potentials.len());
+    assert_eq!(targets.len(), gradients.len());
+
+    let nchunks = sources.len() / 4;
+    let half = f64x4::splat(0.5);
+    let three_halves = f64x4::splat(1.5);
+    let zero = f64x4::splat(0.);
+    let magic = u64x4::splat(0x5fe6eb50c7b537a9);
+
+    for (i, t) in targets.iter().enumerate() {
+        let tx = f64x4::splat(t[0]);
+        let ty = f64x4::splat(t[1]);
+        let tz = f64x4::splat(t[2]);
+
+        let mut pot = zero;
+        let mut gx = zero;
+        let mut gy = zero;
+        let mut gz = zero;
+
+        for c in 0..nchunks {
+            let s = &sources[c * 4..(c + 1) * 4];
+            let q = f64x4::from_slice(&charges[c * 4..(c + 1) * 4]);
+
+            let dx = tx - f64x4::from_array([s[0][0], s[1][0], s[2][0], s[3][0]]);
+            let dy = ty - f64x4::from_array([s[0][1], s[1][1], s[2][1], s[3][1]]);
+            let dz = tz - f64x4::from_array([s[0][2], s[1][2], s[2][2], s[3][2]]);
+            let r2 = dx.mul_add(dx, dy.mul_add(dy, dz * dz));
+
+            // Initial estimate, followed by Newton iterations y = y(3/2 - r2/2 y^2)
+            let mut inv_r = f64x4::from_bits(magic - (r2.to_bits() >> 1));
+            let half_r2 = half * r2;
+            for _ in 0..4 {
+                inv_r *= three_halves - half_r2 * inv_r * inv_r;
+            }
+            let inv_r = r2.simd_gt(zero).select(inv_r, zero);
+
+            let qinv_r = q * inv_r;
+            let qinv_r3 = qinv_r * inv_r * inv_r;
+
+            pot += qinv_r;
+            gx -= dx * qinv_r3;
+            gy -= dy * qinv_r3;
+            gz -= dz * qinv_r3;
+        }
+
+        potentials[i] += pot.reduce_sum() * INV_4PI;
+        gradients[i][0] += gx.reduce_sum() * INV_4PI;
+        gradients[i][1] += gy.reduce_sum() * INV_4PI;
+        gradients[i][2] += gz.reduce_sum() * INV_4PI;
+    }
+
+    // Handle remainder
+    let start_remainder = nchunks * 4;
+    p2p_laplace_naive(
+        &sources[start_remainder..],
+        &charges[start_remainder..],
+        targets,
+        potentials,
+        gradients,
+    );
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+pub mod x86 {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    // Horizontal sum of the four lanes of an AVX register.
+    unsafe fn reduce_sum(a: __m256d) -> f64 {
+        let lo = _mm256_castpd256_pd128(a);
+        let hi = _mm256_extractf128_pd(a, 1);
+        let sum = _mm_add_pd(lo, hi);
+        let sum = _mm_add_sd(sum, _mm_unpackhi_pd(sum, sum));
+        _mm_cvtsd_f64(sum)
+    }
+
+    // AVX2 implementation, AVX only provides a single precision reciprocal square root so the
+    // 12 bit estimate is computed in f32 and refined with three Newton iterations in f64. Squared
+    // distances outside the range of a normal f32 have no estimate, chunks with any take a full
+    // precision square root instead.
+    pub fn p2p_laplace_avx2(
+        sources: &[[f64; 3]],
+        charges: &[f64],
+        targets: &[[f64; 3]],
+        potentials: &mut [f64],
+        gradients: &mut [[f64; 3]],
+    ) {
+        assert_eq!(sources.len(), charges.len());
+        assert_eq!(targets.len(), potentials.len());
+        assert_eq!(targets.len(), gradients.len());
+
+        let nchunks = sources.len() / 4;
+
+        for (i, t) in targets.iter().enumerate() {
+            unsafe {
+                let tx = _mm256_set1_pd(t[0]);
+                let ty = _mm256_set1_pd(t[1]);
+                let tz = _mm256_set1_pd(t[2]);
+                let half = _mm256_set1_pd(0.5);
+                let three_halves = _mm256_set1_pd(1.5);
+                let zero = _mm256_setzero_pd();
+                let one = _mm256_set1_pd(1.);
+                let min_f32 = _mm256_set1_pd(f32::MIN_POSITIVE as f64);
+                let max_f32 = _mm256_set1_pd(f32::MAX as f64);
+
+                let mut pot = zero;
+                let mut gx = zero;
+                let mut gy = zero;
+                let mut gz = zero;
+
+                for c in 0..nchunks {
+                    let s = &sources[c * 4..(c + 1) * 4];
+                    let q = _mm256_loadu_pd(charges[c * 4..].as_ptr());
+
+                    let dx = _mm256_sub_pd(tx, _mm256_set_pd(s[3][0], s[2][0], s[1][0], s[0][0]));
+                    let dy = _mm256_sub_pd(ty, _mm256_set_pd(s[3][1], s[2][1], s[1][1], s[0][1]));
+                    let dz = _mm256_sub_pd(tz, _mm256_set_pd(s[3][2], s[2][2], s[1][2], s[0][2]));
+
+                    let r2 = _mm256_fmadd_pd(
+                        dx,
+                        dx,
+                        _mm256_fmadd_pd(dy, dy, _mm256_mul_pd(dz, dz)),
+                    );
+
+                    // Converting to f32 flushes tiny distances to zero and overflows huge ones
+                    let tiny = _mm256_and_pd(
+                        _mm256_cmp_pd(r2, zero, _CMP_GT_OQ),
+                        _mm256_cmp_pd(r2, min_f32, _CMP_LT_OQ),
+                    );
+                    let huge = _mm256_cmp_pd(r2, max_f32, _CMP_GT_OQ);
+
+                    let inv_r = if _mm256_movemask_pd(_mm256_or_pd(tiny, huge)) == 0 {
+                        let mut inv_r = _mm256_cvtps_pd(_mm_rsqrt_ps(_mm256_cvtpd_ps(r2)));
+                        let half_r2 = _mm256_mul_pd(half, r2);
+                        for _ in 0..3 {
+                            let y2 = _mm256_mul_pd(inv_r, inv_r);
+                            inv_r =
+                                _mm256_mul_pd(inv_r, _mm256_fnmadd_pd(half_r2, y2, three_halves));
+                        }
+                        inv_r
+                    } else {
+                        _mm256_div_pd(one, _mm256_sqrt_pd(r2))
+                    };
+
+                    // Zero out contributions from coincident points
+                    let mask = _mm256_cmp_pd(r2, zero, _CMP_GT_OQ);
+                    let inv_r = _mm256_and_pd(inv_r, mask);
+
+                    let qinv_r = _mm256_mul_pd(q, inv_r);
+                    let qinv_r3 = _mm256_mul_pd(qinv_r, _mm256_mul_pd(inv_r, inv_r));
+
+                    pot = _mm256_add_pd(pot, qinv_r);
+                    gx = _mm256_fnmadd_pd(dx, qinv_r3, gx);
+                    gy = _mm256_fnmadd_pd(dy, qinv_r3, gy);
+                    gz = _mm256_fnmadd_pd(dz, qinv_r3, gz);
+                }
+
+                potentials[i] += reduce_sum(pot) * INV_4PI;
+                gradients[i][0] += reduce_sum(gx) * INV_4PI;
+                gradients[i][1] += reduce_sum(gy) * INV_4PI;
+                gradients[i][2] += reduce_sum(gz) * INV_4PI;
+            }
+        }
+
+        // Handle remainder
+        let start_remainder = nchunks * 4;
+        p2p_laplace_naive(
+            &sources[start_remainder..],
+            &charges[start_remainder..],
+            targets,
+            potentials,
+            gradients,
+        );
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", feature = "neon"))]
+pub mod aarch64 {
+    use super::*;
+    use std::arch::aarch64::*;
+
+    // NEON implementation, the 8 bit reciprocal square root estimate is refined with three
+    // Newton iterations using the fused step instruction.
+    pub fn p2p_laplace_neon(
+        sources: &[[f64; 3]],
+        charges: &[f64],
+        targets: &[[f64; 3]],
+        potentials: &mut [f64],
+        gradients: &mut [[f64; 3]],
+    ) {
+        assert_eq!(sources.len(), charges.len());
+        assert_eq!(targets.len(), potentials.len());
+        assert_eq!(targets.len(), gradients.len());
+
+        let nchunks = sources.len() / 2;
+
+        for (i, t) in targets.iter().enumerate() {
+            unsafe {
+                let tx = vdupq_n_f64(t[0]);
+                let ty = vdupq_n_f64(t[1]);
+                let tz = vdupq_n_f64(t[2]);
+                let zero = vdupq_n_f64(0.);
+
+                let mut pot = zero;
+                let mut gx = zero;
+                let mut gy = zero;
+                let mut gz = zero;
+
+                for c in 0..nchunks {
+                    let s = &sources[c * 2..(c + 1) * 2];
+                    let q = vld1q_f64(charges[c * 2..].as_ptr());
+
+                    let dx = vsubq_f64(tx, vld1q_f64([s[0][0], s[1][0]].as_ptr()));
+                    let dy = vsubq_f64(ty, vld1q_f64([s[0][1], s[1][1]].as_ptr()));
+                    let dz = vsubq_f64(tz, vld1q_f64([s[0][2], s[1][2]].as_ptr()));
+
+                    let r2 = vfmaq_f64(vfmaq_f64(vmulq_f64(dz, dz), dy, dy), dx, dx);
+
+                    // y_{n+1} = y_n (3 - r2 y_n^2) / 2
+                    let mut inv_r = vrsqrteq_f64(r2);
+                    for _ in 0..3 {
+                        inv_r = vmulq_f64(inv_r, vrsqrtsq_f64(vmulq_f64(r2, inv_r), inv_r));
+                    }
+
+                    // Zero out contributions from coincident points
+                    let mask = vcgtq_f64(r2, zero);
+                    let inv_r = vreinterpretq_f64_u64(vandq_u64(
+                        vreinterpretq_u64_f64(inv_r),
+                        mask,
+                    ));
+
+                    let qinv_r = vmulq_f64(q, inv_r);
+                    let qinv_r3 = vmulq_f64(qinv_r, vmulq_f64(inv_r, inv_r));
+
+                    pot = vaddq_f64(pot, qinv_r);
+                    gx = vfmsq_f64(gx, dx, qinv_r3);
+                    gy = vfmsq_f64(gy, dy, qinv_r3);
+                    gz = vfmsq_f64(gz, dz, qinv_r3);
+                }
+
+                potentials[i] += vaddvq_f64(pot) * INV_4PI;
+                gradients[i][0] += vaddvq_f64(gx) * INV_4PI;
+                gradients[i][1] += vaddvq_f64(gy) * INV_4PI;
+                gradients[i][2] += vaddvq_f64(gz) * INV_4PI;
+            }
+        }
+
+        // Handle remainder
+        let start_remainder = nchunks * 2;
+        p2p_laplace_naive(
+            &sources[start_remainder..],
+            &charges[start_remainder..],
+            targets,
+            potentials,
+            gradients,
+        );
+    }
+}
+
+// Evaluate the near field of every leaf in the tree, i.e. the interactions with the points in the
+// leaf itself and its neighbours. Charges are indexed by the global index of each point, and the
+// returned potentials and gradients are too.
+pub fn p2p_tree(tree: &SingleNodeTree, charges: &[f64], p2p: P2PFn) -> (Vec<f64>, Vec<[f64; 3]>) {
+    let leaves: Vec<_> = tree.get_all_leaves_set().iter().cloned().collect();
+
+    let results: Vec<(Vec<usize>, Vec<f64>, Vec<[f64; 3]>)> = leaves
+        .par_iter()
+        .filter_map(|leaf| {
+            let target_points = tree.get_points(leaf)?;
+
+            let targets: Vec<[f64; 3]> = target_points.iter().map(|p| p.coordinate).collect();
+            let global_idxs: Vec<usize> = target_points.iter().map(|p| p.global_idx).collect();
+
+            let mut sources = Vec::new();
+            let mut source_charges = Vec::new();
+
+            let mut near_field = leaf.neighbors();
+            near_field.push(*leaf);
+
+            for key in near_field.iter() {
+                if let Some(points) = tree.get_points(key) {
+                    for p in points.iter() {
+                        sources.push(p.coordinate);
+                        source_charges.push(charges[p.global_idx]);
+                    }
+                }
+            }
+
+            let mut potentials = vec![0f64; targets.len()];
+            let mut gradients = vec![[0f64; 3]; targets.len()];
+            p2p(
+                &sources,
+                &source_charges,
+                &targets,
+                &mut potentials,
+                &mut gradients,
+            );
+
+            Some((global_idxs, potentials, gradients))
+        })
+        .collect();
+
+    let npoints = charges.len();
+    let mut potentials = vec![0f64; npoints];
+    let mut gradients = vec![[0f64; 3]; npoints];
+
+    for (global_idxs, pot, grad) in results.iter() {
+        for (j, &idx) in global_idxs.iter().enumerate() {
+            potentials[idx] = pot[j];
+            gradients[idx] = grad[j];
+        }
+    }
+
+    (potentials, gradients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sources in the unit cube ending with one at the origin, so that it falls in a SIMD chunk or
+    // in the remainder depending on the length. Targets include the origin itself and points
+    // whose squared distance to it is below the smallest normal f32.
+    fn problem(nsources: usize) -> (Vec<[f64; 3]>, Vec<f64>, Vec<[f64; 3]>) {
+        let mut sources: Vec<[f64; 3]> = (1..nsources)
+            .map(|i| {
+                let i = i as f64;
+                [(0.37 * i).fract(), (0.61 * i).fract(), (0.83 * i).fract()]
+            })
+            .collect();
+        if nsources > 0 {
+            sources.push([0.; 3]);
+        }
+
+        let charges = (0..nsources).map(|i| 1. + 0.1 * i as f64).collect();
+
+        let targets = vec![
+            [0.; 3],
+            [1e-20, 0., 0.],
+            [0., 3e-25, 4e-25],
+            [0., 0., 1e-19],
+            [0.5, 0.25, 0.75],
+            [1.5, -0.5, 0.1],
+        ];
+
+        (sources, charges, targets)
+    }
+
+    fn check(kernel: P2PFn) {
+        for nsources in 0..=9 {
+            let (sources, charges, targets) = problem(nsources);
+            let ntargets = targets.len();
+
+            let mut expected = (vec![0f64; ntargets], vec![[0f64; 3]; ntargets]);
+            let mut found = (vec![0f64; ntargets], vec![[0f64; 3]; ntargets]);
+
+            p2p_laplace_naive(
+                &sources,
+                &charges,
+                &targets,
+                &mut expected.0,
+                &mut expected.1,
+            );
+            kernel(&sources, &charges, &targets, &mut found.0, &mut found.1);
+
+            for i in 0..ntargets {
+                let (e, f) = (expected.0[i], found.0[i]);
+                assert!(f.is_finite(), "{} sources, target {}: {}", nsources, i, f);
+                assert!(
+                    (e - f).abs() <= 1e-12 * e.abs(),
+                    "{} sources, target {}",
+                    nsources,
+                    i
+                );
+
+                let scale = expected.1[i].iter().fold(0f64, |a, g| a.max(g.abs()));
+                for (e, f) in expected.1[i].iter().zip(found.1[i].iter()) {
+                    assert!(f.is_finite(), "{} sources, target {}: {}", nsources, i, f);
+                    assert!(
+                        (e - f).abs() <= 1e-12 * scale,
+                        "{} sources, target {}",
+                        nsources,
+                        i
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn portable_matches_naive() {
+        check(p2p_laplace_portable);
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    #[test]
+    fn avx2_matches_naive() {
+        check(x86::p2p_laplace_avx2);
+    }
+
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    #[test]
+    fn neon_matches_naive() {
+        check(aarch64::p2p_laplace_neon);
+    }
+}
@@ -0,0 +1,300 @@
+//! Hardware performance counters read through the Linux `perf_event_open` syscall. Counters that
+//! can't be opened, e.g. due to `perf_event_paranoid` or a container's seccomp profile, are
+//! reported as unavailable rather than failing the benchmark.
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    Cycles,
+    Instructions,
+    L1dMisses,
+    LlcMisses,
+    DtlbMisses,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 5] = [
+        Counter::Cycles,
+        Counter::Instructions,
+        Counter::L1dMisses,
+        Counter::LlcMisses,
+        Counter::DtlbMisses,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::Cycles => "cycles",
+            Counter::Instructions => "instructions",
+            Counter::L1dMisses => "L1d misses",
+            Counter::LlcMisses => "LLC misses",
+            Counter::DtlbMisses => "dTLB misses",
+        }
+    }
+}
+
+// Counter values, None where the counter is unavailable.
+#[derive(Clone, Debug, Default)]
+pub struct CounterValues {
+    pub values: Vec<(Counter, Option<u64>)>,
+}
+
+impl CounterValues {
+    pub fn get(&self, counter: Counter) -> Option<u64> {
+        self.values
+            .iter()
+            .find(|(c, _)| *c == counter)
+            .and_then(|(_, v)| *v)
+    }
+
+    // (name, value per repetition) for each counter
+    pub fn per_repetition(&self, repetitions: usize) -> Vec<(String, Option<f64>)> {
+        self.values
+            .iter()
+            .map(|(c, v)| {
+                (
+                    c.name().to_string(),
+                    v.map(|v| v as f64 / repetitions.max(1) as f64),
+                )
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for CounterValues {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (c, v) in self.values.iter() {
+            match v {
+                Some(v) => writeln!(f, "  {:<15} {:>16}", c.name(), v)?,
+                None => writeln!(f, "  {:<15} {:>16}", c.name(), "unavailable")?,
+            }
+        }
+
+        if let (Some(cycles), Some(instructions)) =
+            (self.get(Counter::Cycles), self.get(Counter::Instructions))
+        {
+            if cycles > 0 {
+                writeln!(f, "  {:<15} {:>16.3}", "IPC", instructions as f64 / cycles as f64)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::Counter;
+
+    const PERF_TYPE_HARDWARE: u32 = 0;
+    const PERF_TYPE_HW_CACHE: u32 = 3;
+
+    const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+    const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+
+    const PERF_COUNT_HW_CACHE_L1D: u64 = 0;
+    const PERF_COUNT_HW_CACHE_LL: u64 = 2;
+    const PERF_COUNT_HW_CACHE_DTLB: u64 = 3;
+    const PERF_COUNT_HW_CACHE_OP_READ: u64 = 0;
+    const PERF_COUNT_HW_CACHE_RESULT_MISS: u64 = 1;
+
+    const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+    const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+
+    // Bit flags of perf_event_attr
+    const DISABLED: u64 = 1 << 0;
+    const INHERIT: u64 = 1 << 1;
+    const EXCLUDE_KERNEL: u64 = 1 << 5;
+    const EXCLUDE_HV: u64 = 1 << 6;
+
+    const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+    const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+    const PERF_EVENT_IOC_RESET: libc::c_ulong = 0x2403;
+
+    // perf_event_attr up to PERF_ATTR_SIZE_VER5
+    #[repr(C)]
+    #[derive(Default)]
+    struct PerfEventAttr {
+        kind: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        config1: u64,
+        config2: u64,
+        branch_sample_type: u64,
+        sample_regs_user: u64,
+        sample_stack_user: u32,
+        clockid: i32,
+        sample_regs_intr: u64,
+        aux_watermark: u32,
+        sample_max_stack: u16,
+        reserved: u16,
+    }
+
+    fn event(counter: Counter) -> (u32, u64) {
+        let cache = |id: u64| {
+            (
+                PERF_TYPE_HW_CACHE,
+                id | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16),
+            )
+        };
+
+        match counter {
+            Counter::Cycles => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES),
+            Counter::Instructions => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS),
+            Counter::L1dMisses => cache(PERF_COUNT_HW_CACHE_L1D),
+            Counter::LlcMisses => cache(PERF_COUNT_HW_CACHE_LL),
+            Counter::DtlbMisses => cache(PERF_COUNT_HW_CACHE_DTLB),
+        }
+    }
+
+    // Open a disabled counter for this process, inherited by threads created after it is opened.
+    pub fn open(counter: Counter) -> Option<i32> {
+        let (kind, config) = event(counter);
+        let attr = PerfEventAttr {
+            kind,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+            flags: DISABLED | INHERIT | EXCLUDE_KERNEL | EXCLUDE_HV,
+            ..Default::default()
+        };
+
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                0,
+                -1,
+                -1,
+                0,
+            )
+        };
+
+        if fd < 0 {
+            None
+        } else {
+            Some(fd as i32)
+        }
+    }
+
+    pub fn enable(fd: i32) {
+        unsafe {
+            libc::ioctl(fd, PERF_EVENT_IOC_ENABLE, 0);
+        }
+    }
+
+    pub fn disable(fd: i32) {
+        unsafe {
+            libc::ioctl(fd, PERF_EVENT_IOC_DISABLE, 0);
+        }
+    }
+
+    pub fn reset(fd: i32) {
+        unsafe {
+            libc::ioctl(fd, PERF_EVENT_IOC_RESET, 0);
+        }
+    }
+
+    // Read a counter, scaling for the time it was multiplexed off the PMU. A counter that never
+    // ran, e.g. because the PMU has too few slots, is unavailable.
+    pub fn read(fd: i32) -> Option<u64> {
+        let mut buf = [0u64; 3];
+        let n = unsafe {
+            libc::read(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                std::mem::size_of_val(&buf),
+            )
+        };
+
+        let [value, enabled, running] = buf;
+        if n != std::mem::size_of_val(&buf) as isize || running == 0 {
+            return None;
+        }
+
+        Some((value as f64 * enabled as f64 / running as f64) as u64)
+    }
+
+    pub fn close(fd: i32) {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::Counter;
+
+    pub fn open(_counter: Counter) -> Option<i32> {
+        None
+    }
+    pub fn enable(_fd: i32) {}
+    pub fn disable(_fd: i32) {}
+    pub fn reset(_fd: i32) {}
+    pub fn read(_fd: i32) -> Option<u64> {
+        None
+    }
+    pub fn close(_fd: i32) {}
+}
+
+// A set of counters for this process. Counters accumulate only while enabled, so wrapping each
+// timed region with `enable` and `disable` counts exactly those regions. Threads are only counted
+// if they are spawned after the counters are opened, so open them before building the rayon pool.
+pub struct PerfCounters {
+    fds: Vec<(Counter, Option<i32>)>,
+}
+
+impl PerfCounters {
+    pub fn open() -> Self {
+        Self {
+            fds: Counter::ALL.iter().map(|&c| (c, sys::open(c))).collect(),
+        }
+    }
+
+    // Whether any counter could be opened
+    pub fn available(&self) -> bool {
+        self.fds.iter().any(|(_, fd)| fd.is_some())
+    }
+
+    pub fn enable(&self) {
+        self.fds.iter().flat_map(|(_, fd)| fd).for_each(|&fd| sys::enable(fd));
+    }
+
+    pub fn disable(&self) {
+        self.fds.iter().flat_map(|(_, fd)| fd).for_each(|&fd| sys::disable(fd));
+    }
+
+    pub fn reset(&self) {
+        self.fds.iter().flat_map(|(_, fd)| fd).for_each(|&fd| sys::reset(fd));
+    }
+
+    pub fn read(&self) -> CounterValues {
+        CounterValues {
+            values: self
+                .fds
+                .iter()
+                .map(|(c, fd)| (*c, fd.and_then(sys::read)))
+                .collect(),
+        }
+    }
+
+    // Run `f` with the counters enabled.
+    pub fn measure<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        self.enable();
+        let result = f();
+        self.disable();
+        result
+    }
+}
+
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        self.fds.iter().flat_map(|(_, fd)| fd).for_each(|&fd| sys::close(fd));
+    }
+}
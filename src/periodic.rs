@@ -0,0 +1,390 @@
+//! Periodic boundary conditions for interaction lists, halos and the far field above the root.
+use std::collections::HashMap;
+
+use bempp_tree::types::{domain::Domain, morton::MortonKey};
+
+use rlst::{
+    common::traits::Eval,
+    dense::{rlst_mat, Dot},
+};
+
+use crate::{
+    helpers::surface_grid,
+    kernel::Kernel,
+    operators::C2ECache,
+    svd::{kernel_matrix, m2l_operator, Mat, ALPHA_INNER, ALPHA_OUTER},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boundary {
+    // Keys at the edge of the domain have fewer than 26 neighbours
+    Free,
+    // Neighbours wrap around the domain, so every key has a full 26 neighbour halo
+    Periodic,
+}
+
+// The 26 directions to the neighbours of a box, in lexicographic order. This fixes the order of
+// the halo in periodic mode.
+pub fn halo_directions() -> Vec<[i64; 3]> {
+    let mut directions = Vec::new();
+
+    for i in -1i64..=1 {
+        for j in -1i64..=1 {
+            for k in -1i64..=1 {
+                if i != 0 || j != 0 || k != 0 {
+                    directions.push([i, j, k]);
+                }
+            }
+        }
+    }
+
+    directions
+}
+
+// Key at the same level as `key` displaced by `direction` box widths, wrapped periodically
+// around the domain.
+pub fn periodic_translate(key: &MortonKey, direction: &[i64; 3], domain: &Domain) -> MortonKey {
+    let level = key.level();
+    let anchor = key.to_coordinates(domain);
+
+    let mut centre = [0f64; 3];
+    for d in 0..3 {
+        let box_width = domain.diameter[d] / 2f64.powi(level as i32);
+
+        // Centre of the displaced box, relative to the domain origin
+        let c = anchor[d] - domain.origin[d] + (direction[d] as f64 + 0.5) * box_width;
+        centre[d] = domain.origin[d] + c.rem_euclid(domain.diameter[d]);
+    }
+
+    MortonKey::from_point(&centre, domain, level)
+}
+
+// Coarsest level at which the periodic neighbours of a key are 26 distinct boxes, other than the
+// key itself. Above it, with fewer than 3 boxes per side, neighbours wrap onto each other.
+pub const MIN_PERIODIC_LEVEL: u64 = 2;
+
+// All 26 neighbours of a key with periodic wrapping, ordered as `halo_directions`. Panics for
+// keys above `MIN_PERIODIC_LEVEL`.
+pub fn periodic_neighbors(key: &MortonKey, domain: &Domain) -> Vec<MortonKey> {
+    assert!(
+        key.level() >= MIN_PERIODIC_LEVEL,
+        "periodic neighbours repeat boxes above level {}",
+        MIN_PERIODIC_LEVEL
+    );
+
+    halo_directions()
+        .iter()
+        .map(|d| periodic_translate(key, d, domain))
+        .collect()
+}
+
+// Interaction list of a key with periodic wrapping, the children of the parent's periodic
+// neighbours that aren't periodic neighbours of the key itself. The key must be at least one level
+// below `MIN_PERIODIC_LEVEL`.
+pub fn periodic_interaction_list(key: &MortonKey, domain: &Domain) -> Vec<MortonKey> {
+    let mut near = periodic_neighbors(key, domain);
+    near.push(*key);
+
+    periodic_neighbors(&key.parent(), domain)
+        .iter()
+        .flat_map(|pn| pn.children())
+        .filter(|pnc| !near.contains(pnc))
+        .collect()
+}
+
+// Whether the domain is a cube. The equivalent and check surfaces are cubes, so the lattice sum
+// and the translations up to the root are only defined for cubic domains.
+pub fn is_cubic(domain: &Domain) -> bool {
+    domain.diameter.iter().all(|&d| d == domain.diameter[0])
+}
+
+// Lattice sum for the far field above the root in periodic mode. Returns the operator taking the
+// root's equivalent density to the downward check potential of the root due to all periodic
+// images of the domain outside of its 26 neighbour shell, summed directly over the images in
+// [-nimages, nimages]^3. The Laplace lattice sum is only conditionally convergent, so the total
+// charge in the domain must vanish for this to be meaningful. Panics if the domain isn't cubic.
+pub fn lattice_sum_operator<K: Kernel<T = f64>>(
+    kernel: &K,
+    expansion_order: usize,
+    domain: &Domain,
+    nimages: i64,
+) -> Mat {
+    assert!(is_cubic(domain), "the domain {:?} isn't cubic", domain.diameter);
+
+    let ncoeffs = 6 * (expansion_order - 1).pow(2) + 2;
+    let mut result = rlst_mat![f64, (ncoeffs, ncoeffs)];
+
+    for i in -nimages..=nimages {
+        for j in -nimages..=nimages {
+            for k in -nimages..=nimages {
+                if i.abs() <= 1 && j.abs() <= 1 && k.abs() <= 1 {
+                    continue;
+                }
+
+                let image = m2l_operator(kernel, expansion_order, &[i, j, k], domain.diameter[0]);
+
+                for r in 0..ncoeffs {
+                    for c in 0..ncoeffs {
+                        result[[r, c]] += image[[r, c]];
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+// Octant of a key within its parent, bit d is set if the key is in the upper half along axis d.
+fn octant(key: &MortonKey, domain: &Domain) -> usize {
+    let anchor = key.to_coordinates(domain);
+    let parent = key.parent().to_coordinates(domain);
+
+    (0..3).filter(|&d| anchor[d] > parent[d]).map(|d| 1 << d).sum()
+}
+
+// Multipole expansion of the root, i.e. its upward equivalent density, translated up the tree
+// from the expansions of `leaves`. At each level the equivalent density of a box gives a potential
+// on its parent's upward check surface, which the parent's check to equivalent operator turns into
+// the parent's density. Leaves may be at different levels, as in an adaptive tree. Panics if the
+// domain isn't cubic.
+pub fn root_multipole<K: Kernel<T = f64>>(
+    c2e: &C2ECache<K>,
+    domain: &Domain,
+    leaves: &HashMap<MortonKey, Vec<f64>>,
+) -> Vec<f64> {
+    assert!(is_cubic(domain), "the domain {:?} isn't cubic", domain.diameter);
+
+    let expansion_order = c2e.expansion_order;
+    let ncoeffs = 6 * (expansion_order - 1).pow(2) + 2;
+
+    let mut expansions = leaves.clone();
+    let depth = leaves.keys().map(|k| k.level()).max().unwrap_or(0);
+
+    for level in (1..=depth).rev() {
+        let parent_width = domain.diameter[0] / 2f64.powi(level as i32 - 1);
+        let check = surface_grid(expansion_order, &[0., 0., 0.], parent_width, ALPHA_OUTER);
+
+        // From the equivalent surface of the child in each octant to the parent's check surface
+        let m2m: Vec<Mat> = (0..8)
+            .map(|octant| {
+                let centre = [0, 1, 2].map(|d| {
+                    let sign = if (octant >> d) & 1 == 1 { 1. } else { -1. };
+                    sign * 0.25 * parent_width
+                });
+                let equivalent =
+                    surface_grid(expansion_order, &centre, parent_width / 2., ALPHA_INNER);
+                kernel_matrix(&c2e.kernel, &equivalent, &check)
+            })
+            .collect();
+
+        let children: Vec<MortonKey> = expansions
+            .keys()
+            .filter(|k| k.level() == level)
+            .cloned()
+            .collect();
+
+        let mut check_potentials: HashMap<MortonKey, Vec<f64>> = HashMap::new();
+        for child in children.iter() {
+            let density = expansions.remove(child).unwrap();
+            let m2m = &m2m[octant(child, domain)];
+
+            let potential = check_potentials
+                .entry(child.parent())
+                .or_insert_with(|| vec![0.; ncoeffs]);
+            for (r, p) in potential.iter_mut().enumerate() {
+                for (c, d) in density.iter().enumerate() {
+                    *p += m2m[[r, c]] * d;
+                }
+            }
+        }
+
+        // One column per parent
+        let parents: Vec<MortonKey> = check_potentials.keys().cloned().collect();
+        let mut potentials = rlst_mat![f64, (ncoeffs, parents.len())];
+        for (j, parent) in parents.iter().enumerate() {
+            for (i, p) in check_potentials[parent].iter().enumerate() {
+                potentials[[i, j]] = *p;
+            }
+        }
+
+        let densities = c2e.upward_equivalent(level - 1, &potentials);
+        for (j, parent) in parents.into_iter().enumerate() {
+            expansions.insert(parent, (0..ncoeffs).map(|i| densities[[i, j]]).collect());
+        }
+    }
+
+    assert!(expansions.len() <= 1, "the leaves don't form a single tree");
+    expansions
+        .into_values()
+        .next()
+        .unwrap_or_else(|| vec![0.; ncoeffs])
+}
+
+// Apply a lattice sum operator to the root's equivalent density, giving the root's downward check
+// potential due to the far periodic images.
+pub fn apply_lattice_sum(operator: &Mat, density: &[f64]) -> Vec<f64> {
+    let n = density.len();
+
+    let mut d = rlst_mat![f64, (n, 1)];
+    for (i, x) in density.iter().enumerate() {
+        d[[i, 0]] = *x;
+    }
+
+    let potential = operator.dot(&d).eval();
+    (0..n).map(|i| potential[[i, 0]]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        helpers::{full_octree_keys, random_multipoles, unit_domain},
+        kernel::Laplace,
+    };
+
+    #[test]
+    fn periodic_neighbors_are_distinct() {
+        let domain = unit_domain();
+
+        for level in MIN_PERIODIC_LEVEL..=4 {
+            // A corner box, all of whose neighbours in some direction wrap around
+            let key = MortonKey::from_point(&[0.01, 0.01, 0.99], &domain, level);
+            let neighbors = periodic_neighbors(&key, &domain);
+
+            let distinct: HashSet<MortonKey> = neighbors.iter().cloned().collect();
+            assert_eq!(distinct.len(), 26);
+            assert!(!distinct.contains(&key));
+            assert!(neighbors.iter().all(|n| n.level() == level));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn periodic_neighbors_of_root_are_rejected() {
+        let domain = unit_domain();
+        let root = MortonKey::from_point(&[0.5, 0.5, 0.5], &domain, 0);
+        periodic_neighbors(&root, &domain);
+    }
+
+    #[test]
+    #[should_panic]
+    fn periodic_neighbors_above_min_level_are_rejected() {
+        let domain = unit_domain();
+        let key = MortonKey::from_point(&[0.5, 0.5, 0.5], &domain, MIN_PERIODIC_LEVEL - 1);
+        periodic_neighbors(&key, &domain);
+    }
+
+    #[test]
+    fn periodic_interaction_list_is_full() {
+        let domain = unit_domain();
+        let key = MortonKey::from_point(&[0.99, 0.01, 0.5], &domain, MIN_PERIODIC_LEVEL + 1);
+
+        let interaction_list = periodic_interaction_list(&key, &domain);
+        let distinct: HashSet<MortonKey> = interaction_list.iter().cloned().collect();
+
+        // 6^3 children of the parent's halo, less the key and its 26 neighbours
+        assert_eq!(interaction_list.len(), 189);
+        assert_eq!(distinct.len(), 189);
+    }
+
+    #[test]
+    #[should_panic]
+    fn lattice_sum_of_non_cubic_domain_is_rejected() {
+        let domain = Domain {
+            origin: [0., 0., 0.],
+            diameter: [1., 2., 1.],
+        };
+        lattice_sum_operator(&Laplace, 3, &domain, 2);
+    }
+
+    // The lattice sum matches the potential of the images of the root's equivalent surface
+    // summed point by point
+    #[test]
+    fn lattice_sum_matches_direct_sum() {
+        let expansion_order = 3;
+        let domain = unit_domain();
+        let width = domain.diameter[0];
+
+        let surface = surface_grid(expansion_order, &[0., 0., 0.], width, ALPHA_INNER);
+        let density: Vec<f64> = (0..surface.len()).map(|i| 1. + (i as f64).cos()).collect();
+
+        // Only the neighbour shell, which is excluded
+        let operator = lattice_sum_operator(&Laplace, expansion_order, &domain, 1);
+        assert!(apply_lattice_sum(&operator, &density)
+            .iter()
+            .all(|&p| p == 0.));
+
+        let nimages = 2;
+        let operator = lattice_sum_operator(&Laplace, expansion_order, &domain, nimages);
+        let found = apply_lattice_sum(&operator, &density);
+
+        for (target, found) in surface.iter().zip(found.iter()) {
+            let mut expected = 0.;
+
+            for i in -nimages..=nimages {
+                for j in -nimages..=nimages {
+                    for k in -nimages..=nimages {
+                        if i.abs() <= 1 && j.abs() <= 1 && k.abs() <= 1 {
+                            continue;
+                        }
+
+                        for (source, q) in surface.iter().zip(density.iter()) {
+                            let image = [
+                                source[0] + i as f64 * width,
+                                source[1] + j as f64 * width,
+                                source[2] + k as f64 * width,
+                            ];
+                            expected += q * Laplace.evaluate(&image, target);
+                        }
+                    }
+                }
+            }
+
+            assert!((expected - found).abs() <= 1e-12 * expected.abs());
+        }
+    }
+
+    // The root's expansion reproduces the far field of the leaves' expansions
+    #[test]
+    fn root_multipole_matches_far_field_of_leaves() {
+        let expansion_order = 6;
+        let domain = unit_domain();
+        let depth = 2;
+        let width = domain.diameter[0] / 2f64.powi(depth as i32);
+
+        let leaves = random_multipoles(expansion_order, &full_octree_keys(depth), 0);
+        let c2e = C2ECache::new(Laplace, expansion_order, domain.diameter[0]);
+        let root = root_multipole(&c2e, &domain, &leaves);
+
+        let target = [4.5, 0.7, -2.3];
+        let potential = |centre: &[f64; 3], width: f64, density: &[f64]| -> f64 {
+            surface_grid(expansion_order, centre, width, ALPHA_INNER)
+                .iter()
+                .zip(density.iter())
+                .map(|(s, q)| q * Laplace.evaluate(s, &target))
+                .sum()
+        };
+
+        let mut expected = 0.;
+        let mut scale = 0.;
+        for (key, density) in leaves.iter() {
+            let anchor = key.to_coordinates(&domain);
+            let centre = [0, 1, 2].map(|d| anchor[d] + 0.5 * width);
+            let magnitude: Vec<f64> = density.iter().map(|q| q.abs()).collect();
+
+            expected += potential(&centre, width, density);
+            scale += potential(&centre, width, &magnitude);
+        }
+
+        let found = potential(&[0.5, 0.5, 0.5], domain.diameter[0], &root);
+        assert!(
+            (expected - found).abs() <= 1e-8 * scale,
+            "expected {}, found {}",
+            expected,
+            found
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Software prefetch hints. Prefetches never fault, so any address may be passed, and they compile
+//! to nothing on targets without a prefetch instruction.
+use crate::aligned::CACHE_LINE;
+
+// Hint that the cache line holding `ptr` will soon be read.
+#[inline(always)]
+pub fn prefetch_read<T>(ptr: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch::<_MM_HINT_T0>(ptr as *const i8);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!(
+            "prfm pldl1keep, [{0}]",
+            in(reg) ptr,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let _ = ptr;
+}
+
+// Hint that the cache line holding `ptr` will soon be written, fetching it in an exclusive state
+// where supported so the store doesn't need a second trip to invalidate other copies.
+#[inline(always)]
+pub fn prefetch_write<T>(ptr: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_ET0};
+        _mm_prefetch::<_MM_HINT_ET0>(ptr as *const i8);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!(
+            "prfm pstl1keep, [{0}]",
+            in(reg) ptr,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let _ = ptr;
+}
+
+// How far ahead, and how much of each buffer, the blocked M2L drivers prefetch. Sibling sets are
+// prefetched `distance` sets ahead of the one being processed, 0 disables prefetching, and only
+// the first `lines` cache lines of each buffer are prefetched, the hardware prefetcher picking up
+// the rest of the sequential access once it has started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefetchPolicy {
+    pub distance: usize,
+    pub lines: usize,
+}
+
+// Prefetch the first `lines` cache lines of `data`, or all of it if it's shorter.
+pub fn prefetch_slice<T>(data: &[T], lines: usize, write: bool) {
+    prefetch_ptr(data.as_ptr(), data.len(), lines, write);
+}
+
+// As `prefetch_slice` for the `len` elements at `ptr`. Nothing is read, so the buffer may be
+// locked or being written by another thread.
+pub fn prefetch_ptr<T>(ptr: *const T, len: usize, lines: usize, write: bool) {
+    let bytes = (len * std::mem::size_of::<T>()).min(lines.saturating_mul(CACHE_LINE));
+    let ptr = ptr as *const u8;
+
+    for offset in (0..bytes).step_by(CACHE_LINE) {
+        let line = ptr.wrapping_add(offset);
+        if write {
+            prefetch_write(line);
+        } else {
+            prefetch_read(line);
+        }
+    }
+}
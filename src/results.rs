@@ -0,0 +1,257 @@
+//! Structured benchmark results with host metadata, written as JSON Lines or CSV.
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+// Description of the machine a benchmark was run on.
+#[derive(Clone, Debug, Serialize)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub cpu_model: String,
+    // SIMD extensions detected at runtime
+    pub simd_features: Vec<String>,
+    // SIMD backends compiled in via cargo features
+    pub compiled_features: Vec<String>,
+    pub rustc_version: String,
+    // Transparent huge page mode of the kernel, huge page advice has no effect if it's "never"
+    pub transparent_hugepages: String,
+}
+
+impl HostInfo {
+    pub fn detect() -> Self {
+        let cpu_model = fs::read_to_string("/proc/cpuinfo")
+            .ok()
+            .and_then(|info| {
+                info.lines()
+                    .find(|l| l.starts_with("model name") || l.starts_with("CPU part"))
+                    .and_then(|l| l.split(':').nth(1))
+                    .map(|m| m.trim().to_string())
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+
+        let mut compiled_features = Vec::new();
+        if cfg!(feature = "avx2") {
+            compiled_features.push("avx2".to_string());
+        }
+        if cfg!(feature = "neon") {
+            compiled_features.push("neon".to_string());
+        }
+
+        // The active mode is bracketed, e.g. "always [madvise] never"
+        let transparent_hugepages =
+            fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+                .ok()
+                .and_then(|modes| {
+                    modes
+                        .split_whitespace()
+                        .find(|m| m.starts_with('['))
+                        .map(|m| m.trim_matches(|c| c == '[' || c == ']').to_string())
+                })
+                .unwrap_or_else(|| "unknown".to_string());
+
+        Self {
+            hostname,
+            cpu_model,
+            simd_features: simd_features(),
+            compiled_features,
+            rustc_version: env!("RUSTC_VERSION").to_string(),
+            transparent_hugepages,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn simd_features() -> Vec<String> {
+    let mut features = Vec::new();
+    macro_rules! detect {
+        ($($f:tt),*) => {
+            $(if is_x86_feature_detected!($f) {
+                features.push($f.to_string());
+            })*
+        };
+    }
+    detect!("sse4.2", "avx", "avx2", "fma", "avx512f");
+    features
+}
+
+#[cfg(target_arch = "aarch64")]
+fn simd_features() -> Vec<String> {
+    let mut features = Vec::new();
+    macro_rules! detect {
+        ($($f:tt),*) => {
+            $(if std::arch::is_aarch64_feature_detected!($f) {
+                features.push($f.to_string());
+            })*
+        };
+    }
+    detect!("neon", "sve", "sve2");
+    features
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn simd_features() -> Vec<String> {
+    Vec::new()
+}
+
+// A single benchmark run, timings are in seconds over all repetitions.
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchResult {
+    pub kernel: String,
+    // Kernel specific options, e.g. precision or truncation rank
+    pub variant: String,
+    pub backend: String,
+    pub npoints: usize,
+    pub distribution: String,
+    // Source of the M2L keys, a tree over the points or a synthetic octree
+    pub octree: String,
+    pub seed: u64,
+    pub ncrit: u64,
+    pub depth: u64,
+    pub expansion_order: usize,
+    pub threads: usize,
+    // Allocation policy of the expansion buffers, see `AllocPolicy`
+    pub alloc: String,
+    pub repetitions: usize,
+    pub min: f64,
+    pub median: f64,
+    pub max: f64,
+    // Mean time per phase, summed over workers, for the instrumented drivers
+    pub phases: Vec<(String, f64)>,
+    // Hardware counters per repetition, None where unavailable
+    pub counters: Vec<(String, Option<f64>)>,
+    // Arithmetic intensity, FLOPs per byte, from the kernel's analytic cost model
+    pub intensity: Option<f64>,
+    // Achieved GFLOP/s and GB/s at the median time
+    pub gflops: Option<f64>,
+    pub gbytes: Option<f64>,
+    // Measured memory bandwidth ceiling in GB/s
+    pub bandwidth: Option<f64>,
+    // Achieved fraction of the bandwidth bound roofline
+    pub roofline_fraction: Option<f64>,
+    pub host: HostInfo,
+}
+
+// Minimum, median and maximum of a set of timings, in seconds.
+pub fn summarise(times: &[Duration]) -> (f64, f64, f64) {
+    let mut secs: Vec<f64> = times.iter().map(|t| t.as_secs_f64()).collect();
+    secs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let n = secs.len();
+    let median = if n % 2 == 0 {
+        0.5 * (secs[n / 2 - 1] + secs[n / 2])
+    } else {
+        secs[n / 2]
+    };
+
+    (secs[0], median, secs[n - 1])
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    JsonLines,
+    Csv,
+}
+
+const CSV_HEADER: &str = "kernel,variant,backend,npoints,distribution,octree,seed,ncrit,depth,expansion_order,\
+threads,alloc,repetitions,min,median,max,phases,counters,intensity,gflops,gbytes,bandwidth,roofline_fraction,hostname,cpu_model,simd_features,compiled_features,rustc_version,\
+transparent_hugepages";
+
+fn csv_option(x: Option<f64>) -> String {
+    x.map(|x| x.to_string()).unwrap_or_default()
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl BenchResult {
+    pub fn to_csv(&self) -> String {
+        [
+            csv_field(&self.kernel),
+            csv_field(&self.variant),
+            csv_field(&self.backend),
+            self.npoints.to_string(),
+            csv_field(&self.distribution),
+            csv_field(&self.octree),
+            self.seed.to_string(),
+            self.ncrit.to_string(),
+            self.depth.to_string(),
+            self.expansion_order.to_string(),
+            self.threads.to_string(),
+            csv_field(&self.alloc),
+            self.repetitions.to_string(),
+            self.min.to_string(),
+            self.median.to_string(),
+            self.max.to_string(),
+            csv_field(
+                &self
+                    .phases
+                    .iter()
+                    .map(|(name, t)| format!("{}={}", name, t))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            csv_field(
+                &self
+                    .counters
+                    .iter()
+                    .map(|(name, v)| match v {
+                        Some(v) => format!("{}={}", name, v),
+                        None => format!("{}=unavailable", name),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            csv_option(self.intensity),
+            csv_option(self.gflops),
+            csv_option(self.gbytes),
+            csv_option(self.bandwidth),
+            csv_option(self.roofline_fraction),
+            csv_field(&self.host.hostname),
+            csv_field(&self.host.cpu_model),
+            csv_field(&self.host.simd_features.join(" ")),
+            csv_field(&self.host.compiled_features.join(" ")),
+            csv_field(&self.host.rustc_version),
+            csv_field(&self.host.transparent_hugepages),
+        ]
+        .join(",")
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// Append results to a file, so that runs across machines can be collected in one place. A CSV
+// header is written if the file is new or empty.
+pub fn write_results(path: &Path, format: Format, results: &[BenchResult]) -> io::Result<()> {
+    let is_empty = fs::metadata(path).map(|m| m.len() == 0).unwrap_or(true);
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    if format == Format::Csv && is_empty {
+        writeln!(file, "{}", CSV_HEADER)?;
+    }
+
+    for result in results.iter() {
+        match format {
+            Format::JsonLines => writeln!(file, "{}", result.to_json())?,
+            Format::Csv => writeln!(file, "{}", result.to_csv())?,
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! Analytic FLOP and byte counts for each kernel, for placing measured timings on a roofline.
+use std::ops::{Add, Mul};
+
+use crate::{blas1::Blas1Op, stream::StreamKernel};
+
+// Work done and data moved by a kernel. Bytes count every load and store to the kernel's
+// operands, ignoring caching, i.e. they are the traffic of a kernel that streams from memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cost {
+    pub flops: f64,
+    pub bytes: f64,
+}
+
+impl Cost {
+    pub fn new(flops: f64, bytes: f64) -> Self {
+        Self { flops, bytes }
+    }
+
+    // FLOPs per byte
+    pub fn intensity(&self) -> f64 {
+        self.flops / self.bytes
+    }
+
+    pub fn gflops(&self, seconds: f64) -> f64 {
+        self.flops / seconds * 1e-9
+    }
+
+    pub fn gbytes(&self, seconds: f64) -> f64 {
+        self.bytes / seconds * 1e-9
+    }
+
+    // Attainable GFLOP/s for a purely bandwidth bound kernel, given a bandwidth ceiling in GB/s.
+    pub fn attainable_gflops(&self, bandwidth: f64) -> f64 {
+        self.intensity() * bandwidth
+    }
+}
+
+impl Add for Cost {
+    type Output = Cost;
+
+    fn add(self, other: Cost) -> Cost {
+        Cost::new(self.flops + other.flops, self.bytes + other.bytes)
+    }
+}
+
+impl Mul<f64> for Cost {
+    type Output = Cost;
+
+    fn mul(self, n: f64) -> Cost {
+        Cost::new(self.flops * n, self.bytes * n)
+    }
+}
+
+// Complex multiply-add, c += a * b, is 4 multiplications and 4 additions, loading a, b and c
+// and storing c.
+pub const COMPLEX_MUL_ADD: Cost = Cost {
+    flops: 8.,
+    bytes: 64.,
+};
+
+// Complex addition to a buffer in place, c += a, with a held in registers.
+pub const COMPLEX_ADD_IN_PLACE: Cost = Cost {
+    flops: 2.,
+    bytes: 32.,
+};
+
+fn size_real(expansion_order: usize) -> usize {
+    let n = 2 * expansion_order - 1;
+    let &(m, n, o) = &(n, n, n);
+
+    let p = m + 1;
+    let q = n + 1;
+    let r = o + 1;
+    p * q * (r / 2 + 1)
+}
+
+// Elementwise z = x * y over n elements of `elem_size` bytes, as in `dotp_naive_*`.
+pub fn dotp_mul_cost(n: usize, elem_size: usize) -> Cost {
+    Cost::new(n as f64, (3 * n * elem_size) as f64)
+}
+
+// Elementwise z = x * y + z over n elements of `elem_size` bytes, as in the FMA `dotp_simd_*`.
+pub fn dotp_fma_cost(n: usize, elem_size: usize) -> Cost {
+    Cost::new((2 * n) as f64, (4 * n * elem_size) as f64)
+}
+
+// Hadamard product of one sibling set with the 16 kernels, as in `hadamard_product_*`.
+pub fn hadamard_cost(expansion_order: usize) -> Cost {
+    COMPLEX_MUL_ADD * (16 * 8 * size_real(expansion_order)) as f64
+}
+
+// Parent level M2L, a Hadamard product per sibling set followed by a scatter that adds to a halo
+// child's buffer once for each sibling whose interaction list contains it.
+pub fn m2l_parent_cost(expansion_order: usize, nparents: usize, nsaves_per_parent: usize) -> Cost {
+    let scatter = COMPLEX_ADD_IN_PLACE * (nsaves_per_parent * size_real(expansion_order)) as f64;
+    (hadamard_cost(expansion_order) + scatter) * nparents as f64
+}
+
+// Naive M2L over all keys, adding to the buffer of each member of every interaction list.
+pub fn m2l_naive_cost(expansion_order: usize, ninteractions: usize) -> Cost {
+    COMPLEX_ADD_IN_PLACE * (ninteractions * size_real(expansion_order)) as f64
+}
+
+// Laplace P2P, 20 FLOPs per pairwise potential and gradient evaluation. Each target streams the
+// coordinates and charges of all its sources (32 bytes each), and loads and stores its own
+// potential and gradient.
+pub fn p2p_cost(ninteractions: usize, ntargets: usize) -> Cost {
+    Cost::new(
+        (20 * ninteractions) as f64,
+        (32 * ninteractions + 2 * 32 * ntargets) as f64,
+    )
+}
+
+// STREAM kernel over n doubles, scale and add do one FLOP per element and triad two.
+pub fn stream_cost(kernel: StreamKernel, n: usize) -> Cost {
+    let flops = match kernel {
+        StreamKernel::Copy => 0,
+        StreamKernel::Scale | StreamKernel::Add => n,
+        StreamKernel::Triad => 2 * n,
+    };
+    Cost::new(flops as f64, (8 * kernel.arrays() * n) as f64)
+}
+
+// BLAS level-1 kernel over n doubles, or n complex doubles. The norm reads x once.
+pub fn blas1_cost(op: Blas1Op, complex: bool, n: usize) -> Cost {
+    let (flops, arrays) = match (op, complex) {
+        (Blas1Op::Dot, false) => (2, 2),
+        (Blas1Op::Dot, true) => (8, 2),
+        (Blas1Op::Axpy, false) => (2, 3),
+        (Blas1Op::Axpy, true) => (8, 3),
+        (Blas1Op::Scal, false) => (1, 2),
+        (Blas1Op::Scal, true) => (6, 2),
+        (Blas1Op::Nrm2, false) => (2, 1),
+        (Blas1Op::Nrm2, true) => (4, 1),
+    };
+    let elem_size = if complex { 16 } else { 8 };
+    Cost::new((flops * n) as f64, (arrays * elem_size * n) as f64)
+}
+
+// Compressed M2L with rank k, compression and decompression GEMMs over all keys plus a (k, k)
+// GEMM per interaction, followed by the check to equivalent GEMM over all keys.
+pub fn m2l_svd_cost(ncoeffs: usize, k: usize, nkeys: usize, ninteractions: usize) -> Cost {
+    let gemm = |m: usize, n: usize, l: usize| {
+        Cost::new((2 * m * n * l) as f64, (8 * (m * l + l * n + 2 * m * n)) as f64)
+    };
+
+    gemm(k, nkeys, ncoeffs)
+        + gemm(ncoeffs, nkeys, k)
+        + gemm(k, ninteractions, k)
+        + gemm(ncoeffs, nkeys, ncoeffs)
+}
@@ -0,0 +1,317 @@
+//! STREAM-style memory bandwidth microbenchmarks, sweeping the working set through the cache
+//! hierarchy to find the attainable bandwidth of each level.
+use std::{fs, simd::prelude::*, time::Instant};
+
+use rayon::prelude::*;
+
+use crate::helpers::{data_f64, BLOCK_SIZE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKernel {
+    // z = x
+    Copy,
+    // z = s * x
+    Scale,
+    // z = x + y
+    Add,
+    // z = x + s * y
+    Triad,
+}
+
+impl StreamKernel {
+    pub const ALL: [StreamKernel; 4] = [
+        StreamKernel::Copy,
+        StreamKernel::Scale,
+        StreamKernel::Add,
+        StreamKernel::Triad,
+    ];
+
+    // Number of arrays read or written per element
+    pub fn arrays(&self) -> usize {
+        match self {
+            StreamKernel::Copy | StreamKernel::Scale => 2,
+            StreamKernel::Add | StreamKernel::Triad => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamKernel::Copy => "copy",
+            StreamKernel::Scale => "scale",
+            StreamKernel::Add => "add",
+            StreamKernel::Triad => "triad",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamBackend {
+    Scalar,
+    Portable,
+    #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+    Avx2,
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    Neon,
+}
+
+const SCALAR: f64 = 3.0;
+
+pub fn stream_scalar(kernel: StreamKernel, x: &[f64], y: &[f64], z: &mut [f64]) {
+    let it = z.iter_mut().zip(x.iter()).zip(y.iter());
+    match kernel {
+        StreamKernel::Copy => it.for_each(|((z, x), _)| *z = *x),
+        StreamKernel::Scale => it.for_each(|((z, x), _)| *z = SCALAR * x),
+        StreamKernel::Add => it.for_each(|((z, x), y)| *z = x + y),
+        StreamKernel::Triad => it.for_each(|((z, x), y)| *z = x + SCALAR * y),
+    }
+}
+
+pub fn stream_portable(kernel: StreamKernel, x: &[f64], y: &[f64], z: &mut [f64]) {
+    let s = f64x4::splat(SCALAR);
+
+    x.array_chunks::<4>()
+        .map(|&a| f64x4::from_array(a))
+        .zip(y.array_chunks::<4>().map(|&b| f64x4::from_array(b)))
+        .zip(z.array_chunks_mut::<4>())
+        .for_each(|((a, b), c)| {
+            let r = match kernel {
+                StreamKernel::Copy => a,
+                StreamKernel::Scale => s * a,
+                StreamKernel::Add => a + b,
+                StreamKernel::Triad => a + s * b,
+            };
+            *c = r.to_array();
+        });
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+pub mod x86 {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    pub fn stream_avx2(kernel: StreamKernel, x: &[f64], y: &[f64], z: &mut [f64]) {
+        let chunk_size = 4;
+        for ((a, b), c) in x
+            .chunks_exact(chunk_size)
+            .zip(y.chunks_exact(chunk_size))
+            .zip(z.chunks_exact_mut(chunk_size))
+        {
+            unsafe {
+                let s = _mm256_set1_pd(SCALAR);
+                let x_a = _mm256_loadu_pd(a.as_ptr());
+
+                let r = match kernel {
+                    StreamKernel::Copy => x_a,
+                    StreamKernel::Scale => _mm256_mul_pd(s, x_a),
+                    StreamKernel::Add => _mm256_add_pd(x_a, _mm256_loadu_pd(b.as_ptr())),
+                    StreamKernel::Triad => _mm256_fmadd_pd(s, _mm256_loadu_pd(b.as_ptr()), x_a),
+                };
+
+                _mm256_storeu_pd(c.as_mut_ptr(), r);
+            }
+        }
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", feature = "neon"))]
+pub mod aarch64 {
+    use super::*;
+    use std::arch::aarch64::*;
+
+    pub fn stream_neon(kernel: StreamKernel, x: &[f64], y: &[f64], z: &mut [f64]) {
+        let chunk_size = 2;
+        for ((a, b), c) in x
+            .chunks_exact(chunk_size)
+            .zip(y.chunks_exact(chunk_size))
+            .zip(z.chunks_exact_mut(chunk_size))
+        {
+            unsafe {
+                let s = vdupq_n_f64(SCALAR);
+                let x_a = vld1q_f64(a.as_ptr());
+
+                let r = match kernel {
+                    StreamKernel::Copy => x_a,
+                    StreamKernel::Scale => vmulq_f64(s, x_a),
+                    StreamKernel::Add => vaddq_f64(x_a, vld1q_f64(b.as_ptr())),
+                    StreamKernel::Triad => vfmaq_f64(x_a, s, vld1q_f64(b.as_ptr())),
+                };
+
+                vst1q_f64(c.as_mut_ptr(), r);
+            }
+        }
+    }
+}
+
+fn stream_fn(backend: StreamBackend) -> fn(StreamKernel, &[f64], &[f64], &mut [f64]) {
+    match backend {
+        StreamBackend::Scalar => stream_scalar,
+        StreamBackend::Portable => stream_portable,
+        #[cfg(all(target_arch = "x86_64", feature = "avx2"))]
+        StreamBackend::Avx2 => x86::stream_avx2,
+        #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+        StreamBackend::Neon => aarch64::stream_neon,
+    }
+}
+
+// Run a kernel once, in parallel the arrays are split into `BLOCK_SIZE` blocks for rayon.
+pub fn stream(
+    kernel: StreamKernel,
+    backend: StreamBackend,
+    parallel: bool,
+    x: &[f64],
+    y: &[f64],
+    z: &mut [f64],
+) {
+    let f = stream_fn(backend);
+
+    if parallel {
+        x.par_chunks(BLOCK_SIZE)
+            .zip(y.par_chunks(BLOCK_SIZE))
+            .zip(z.par_chunks_mut(BLOCK_SIZE))
+            .for_each(|((a, b), c)| f(kernel, a, b, c));
+    } else {
+        f(kernel, x, y, z)
+    }
+}
+
+// Bandwidth of a single working set size.
+#[derive(Clone, Debug)]
+pub struct StreamResult {
+    pub kernel: StreamKernel,
+    // Bytes touched by one pass of the kernel
+    pub working_set: usize,
+    // Best bandwidth over all repetitions, in GB/s
+    pub bandwidth: f64,
+    // Smallest cache level the working set fits in, None if it only fits in main memory
+    pub cache_level: Option<usize>,
+}
+
+// Data cache sizes in bytes by level, from sysfs. Empty if unavailable.
+pub fn cache_sizes() -> Vec<(usize, usize)> {
+    let mut sizes = Vec::new();
+
+    for index in 0.. {
+        let dir = format!("/sys/devices/system/cpu/cpu0/cache/index{}", index);
+        let read = |f: &str| fs::read_to_string(format!("{}/{}", dir, f)).ok();
+
+        let (Some(level), Some(size), Some(kind)) = (read("level"), read("size"), read("type"))
+        else {
+            break;
+        };
+
+        if kind.trim() == "Instruction" {
+            continue;
+        }
+
+        let size = size.trim();
+        let bytes = if let Some(k) = size.strip_suffix('K') {
+            k.parse::<usize>().ok().map(|k| k << 10)
+        } else if let Some(m) = size.strip_suffix('M') {
+            m.parse::<usize>().ok().map(|m| m << 20)
+        } else {
+            size.parse::<usize>().ok()
+        };
+
+        if let (Ok(level), Some(bytes)) = (level.trim().parse::<usize>(), bytes) {
+            sizes.push((level, bytes));
+        }
+    }
+
+    sizes.sort();
+    sizes
+}
+
+// Time a kernel on a working set of (at least) `working_set` bytes, repeating until at least
+// `min_seconds` have elapsed, and return the best bandwidth in GB/s.
+pub fn stream_bandwidth(
+    kernel: StreamKernel,
+    backend: StreamBackend,
+    parallel: bool,
+    working_set: usize,
+    min_seconds: f64,
+) -> f64 {
+    let bytes_per_block = kernel.arrays() * BLOCK_SIZE * std::mem::size_of::<f64>();
+    let nblocks = working_set.div_ceil(bytes_per_block).max(1);
+    let (x, y, mut z) = data_f64(nblocks);
+
+    let bytes = (kernel.arrays() * x.len() * std::mem::size_of::<f64>()) as f64;
+
+    // Warm up, so that the first pass doesn't pay for page faults
+    stream(kernel, backend, parallel, &x, &y, &mut z);
+
+    let mut best = f64::MAX;
+    let start = Instant::now();
+    while start.elapsed().as_secs_f64() < min_seconds {
+        let s = Instant::now();
+        stream(kernel, backend, parallel, &x, &y, &mut z);
+        best = best.min(s.elapsed().as_secs_f64());
+    }
+
+    bytes / best * 1e-9
+}
+
+// Sweep the working set from 16 KiB to `max_working_set` in powers of two for each kernel.
+pub fn stream_sweep(
+    backend: StreamBackend,
+    parallel: bool,
+    max_working_set: usize,
+    min_seconds: f64,
+) -> Vec<StreamResult> {
+    let caches = cache_sizes();
+    let mut results = Vec::new();
+
+    for kernel in StreamKernel::ALL.iter() {
+        let mut working_set = 16 << 10;
+        while working_set <= max_working_set {
+            let bandwidth = stream_bandwidth(*kernel, backend, parallel, working_set, min_seconds);
+
+            let cache_level = caches
+                .iter()
+                .find(|(_, size)| working_set <= *size)
+                .map(|(level, _)| *level);
+
+            results.push(StreamResult {
+                kernel: *kernel,
+                working_set,
+                bandwidth,
+                cache_level,
+            });
+
+            working_set *= 2;
+        }
+    }
+
+    results
+}
+
+// Best bandwidth of each kernel per cache level, with None for main memory.
+pub fn bandwidth_per_level(results: &[StreamResult]) -> Vec<(StreamKernel, Option<usize>, f64)> {
+    let mut summary: Vec<(StreamKernel, Option<usize>, f64)> = Vec::new();
+
+    for r in results.iter() {
+        match summary
+            .iter_mut()
+            .find(|(k, l, _)| *k == r.kernel && *l == r.cache_level)
+        {
+            Some(entry) => entry.2 = entry.2.max(r.bandwidth),
+            None => summary.push((r.kernel, r.cache_level, r.bandwidth)),
+        }
+    }
+
+    summary
+}
+
+// Main memory bandwidth ceiling in GB/s for the roofline, the parallel triad over a working set
+// well beyond the last level cache.
+pub fn bandwidth_ceiling(parallel: bool) -> f64 {
+    let llc = cache_sizes().last().map(|(_, size)| *size).unwrap_or(32 << 20);
+    let working_set = (8 * llc).max(256 << 20);
+
+    stream_bandwidth(
+        StreamKernel::Triad,
+        StreamBackend::Portable,
+        parallel,
+        working_set,
+        1.0,
+    )
+}
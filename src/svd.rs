@@ -0,0 +1,403 @@
+//! M2L via SVD compressed dense operators applied with BLAS, as an alternative to the FFT path.
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+};
+
+use rayon::prelude::*;
+
+use bempp_tree::types::{domain::Domain, morton::MortonKey};
+
+use rlst::{
+    algorithms::{
+        linalg::LinAlg,
+        traits::svd::{Mode, Svd},
+    },
+    common::traits::Eval,
+    dense::{
+        base_matrix::BaseMatrix, rlst_mat, Dot, Dynamic, Matrix, Shape, VectorContainer,
+    },
+};
+
+use crate::{
+    helpers::{surface_grid, transfer_vectors},
+    kernel::Kernel,
+};
+
+pub type Mat = Matrix<f64, BaseMatrix<f64, VectorContainer<f64>, Dynamic>, Dynamic>;
+
+// Scaling of the equivalent and check surfaces relative to the box width.
+pub const ALPHA_INNER: f64 = 1.05;
+pub const ALPHA_OUTER: f64 = 2.95;
+
+// Inputs the SVD compressed M2L can't be applied to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SvdM2lError {
+    // The surfaces need at least two points per side, and the expansions the same number of
+    // coefficients as the operator
+    ExpansionOrder(usize),
+    // A source and target pair whose transfer vector has no operator, e.g. as the domain isn't a
+    // cube
+    TransferVector([i64; 3]),
+}
+
+impl fmt::Display for SvdM2lError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SvdM2lError::ExpansionOrder(order) => {
+                write!(f, "expansion order {} is invalid for the operator", order)
+            }
+            SvdM2lError::TransferVector(t) => write!(f, "no operator for transfer vector {:?}", t),
+        }
+    }
+}
+
+impl Error for SvdM2lError {}
+
+// Compressed M2L operators, each dense operator K_t from the source equivalent surface to the
+// target check surface for transfer vector t is approximated as U C_t Vt.
+pub struct SvdM2lOperator {
+    // Truncation rank
+    pub k: usize,
+    // Left singular vectors, (ncoeffs, k)
+    pub u: Mat,
+    // Right singular vectors, (k, ncoeffs)
+    pub vt: Mat,
+    // Compressed operators, (k, k), one per transfer vector
+    pub c: Vec<Mat>,
+    pub transfer_vectors: Vec<[i64; 3]>,
+}
+
+// Dense kernel matrix between a set of sources and targets, (ntargets, nsources).
+pub fn kernel_matrix<K: Kernel<T = f64>>(
+    kernel: &K,
+    sources: &[[f64; 3]],
+    targets: &[[f64; 3]],
+) -> Mat {
+    let mut buf = vec![0f64; sources.len() * targets.len()];
+    kernel.evaluate_batch(sources, targets, &mut buf);
+
+    let mut result = rlst_mat![f64, (targets.len(), sources.len())];
+    for i in 0..targets.len() {
+        for j in 0..sources.len() {
+            result[[i, j]] = buf[i * sources.len() + j];
+        }
+    }
+
+    result
+}
+
+// Dense M2L operator for a transfer vector, in units of the box width, from the upward equivalent
+// surface of a source box to the downward check surface of a target box centred at the origin.
+// Both surfaces lie just outside their boxes.
+pub fn m2l_operator<K: Kernel<T = f64>>(
+    kernel: &K,
+    expansion_order: usize,
+    transfer_vector: &[i64; 3],
+    box_width: f64,
+) -> Mat {
+    let source_centre = [
+        transfer_vector[0] as f64 * box_width,
+        transfer_vector[1] as f64 * box_width,
+        transfer_vector[2] as f64 * box_width,
+    ];
+
+    let sources = surface_grid(expansion_order, &source_centre, box_width, ALPHA_INNER);
+    let targets = surface_grid(expansion_order, &[0., 0., 0.], box_width, ALPHA_INNER);
+
+    kernel_matrix(kernel, &sources, &targets)
+}
+
+// Precompute the dense operators for all 316 transfer vectors and compress them with a truncated
+// SVD of rank k. The shared bases are found from the SVDs of the operators stacked side by side
+// (for U) and on top of each other (for Vt).
+pub fn svd_m2l_operator<K: Kernel<T = f64>>(
+    kernel: &K,
+    expansion_order: usize,
+    box_width: f64,
+    k: usize,
+) -> Result<SvdM2lOperator, SvdM2lError> {
+    if expansion_order < 2 {
+        return Err(SvdM2lError::ExpansionOrder(expansion_order));
+    }
+
+    let ncoeffs = 6 * (expansion_order - 1).pow(2) + 2;
+    let k = k.min(ncoeffs);
+
+    let transfer_vectors = transfer_vectors();
+    let ntransfer_vectors = transfer_vectors.len();
+
+    let operators: Vec<Mat> = transfer_vectors
+        .par_iter()
+        .map(|t| m2l_operator(kernel, expansion_order, t, box_width))
+        .collect();
+
+    let mut fat = rlst_mat![f64, (ncoeffs, ncoeffs * ntransfer_vectors)];
+    let mut thin = rlst_mat![f64, (ncoeffs * ntransfer_vectors, ncoeffs)];
+
+    for (t, op) in operators.iter().enumerate() {
+        for i in 0..ncoeffs {
+            for j in 0..ncoeffs {
+                fat[[i, t * ncoeffs + j]] = op[[i, j]];
+                thin[[t * ncoeffs + i, j]] = op[[i, j]];
+            }
+        }
+    }
+
+    let (_, u, _) = fat.linalg().svd(Mode::All, Mode::None).unwrap();
+    let (_, _, vt) = thin.linalg().svd(Mode::None, Mode::All).unwrap();
+    let u = u.unwrap();
+    let vt = vt.unwrap();
+
+    // Truncate the bases
+    let mut u_k = rlst_mat![f64, (ncoeffs, k)];
+    let mut vt_k = rlst_mat![f64, (k, ncoeffs)];
+    let mut ut_k = rlst_mat![f64, (k, ncoeffs)];
+    let mut v_k = rlst_mat![f64, (ncoeffs, k)];
+
+    for i in 0..ncoeffs {
+        for j in 0..k {
+            u_k[[i, j]] = u[[i, j]];
+            ut_k[[j, i]] = u[[i, j]];
+            vt_k[[j, i]] = vt[[j, i]];
+            v_k[[i, j]] = vt[[j, i]];
+        }
+    }
+
+    // Compressed operators C_t = U^T K_t V
+    let c: Vec<Mat> = operators
+        .par_iter()
+        .map(|op| ut_k.dot(op).eval().dot(&v_k).eval())
+        .collect();
+
+    Ok(SvdM2lOperator {
+        k,
+        u: u_k,
+        vt: vt_k,
+        c,
+        transfer_vectors,
+    })
+}
+
+// Transfer vector from a target to a source key at the same level, in units of the box width.
+pub fn find_transfer_vector(
+    target: &MortonKey,
+    source: &MortonKey,
+    domain: &Domain,
+) -> [i64; 3] {
+    let box_width = domain.diameter[0] / 2f64.powi(target.level() as i32);
+
+    let t = target.to_coordinates(domain);
+    let s = source.to_coordinates(domain);
+
+    [
+        ((s[0] - t[0]) / box_width).round() as i64,
+        ((s[1] - t[1]) / box_width).round() as i64,
+        ((s[2] - t[2]) / box_width).round() as i64,
+    ]
+}
+
+// Apply the compressed operators to the leaves of a uniform tree. Multipoles are first compressed
+// with Vt, then for each transfer vector the compressed multipoles of all sources are gathered into
+// a single matrix and multiplied by C_t in one GEMM, before the results are scattered back to the
+// targets and decompressed with U.
+pub fn m2l_svd(
+    operator: &SvdM2lOperator,
+    expansion_order: usize,
+    leaves: &HashSet<MortonKey>,
+    domain: &Domain,
+) -> Result<Mat, SvdM2lError> {
+    if expansion_order < 2 || operator.u.shape().0 != 6 * (expansion_order - 1).pow(2) + 2 {
+        return Err(SvdM2lError::ExpansionOrder(expansion_order));
+    }
+
+    let ncoeffs = 6 * (expansion_order - 1).pow(2) + 2;
+    let k = operator.k;
+
+    let mut keys: Vec<MortonKey> = leaves.iter().cloned().collect();
+    keys.sort();
+    let nkeys = keys.len();
+
+    let key_idxs: HashMap<MortonKey, usize> =
+        keys.iter().enumerate().map(|(i, k)| (*k, i)).collect();
+
+    let transfer_vector_idxs: HashMap<[i64; 3], usize> = operator
+        .transfer_vectors
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (*t, i))
+        .collect();
+
+    // Multipole expansions, one column per key
+    let mut multipoles = rlst_mat![f64, (ncoeffs, nkeys)];
+    for j in 0..nkeys {
+        for i in 0..ncoeffs {
+            multipoles[[i, j]] = 1.0;
+        }
+    }
+
+    // For each transfer vector, the (target, source) pairs that it connects
+    let mut pairs = vec![Vec::new(); operator.transfer_vectors.len()];
+    for (target_idx, target) in keys.iter().enumerate() {
+        let interaction_list = target
+            .parent()
+            .neighbors()
+            .iter()
+            .flat_map(|pn| pn.children())
+            .filter(|pnc| !target.is_adjacent_same_level(pnc));
+
+        for source in interaction_list {
+            if let Some(&source_idx) = key_idxs.get(&source) {
+                let t = find_transfer_vector(target, &source, domain);
+                let &t_idx = transfer_vector_idxs
+                    .get(&t)
+                    .ok_or(SvdM2lError::TransferVector(t))?;
+                pairs[t_idx].push((target_idx, source_idx));
+            }
+        }
+    }
+
+    let compressed_multipoles = operator.vt.dot(&multipoles).eval();
+
+    // Batched GEMM per transfer vector
+    let results: Vec<Mat> = pairs
+        .par_iter()
+        .enumerate()
+        .map(|(t, pairs)| {
+            let mut sources = rlst_mat![f64, (k, pairs.len())];
+            for (j, &(_, source_idx)) in pairs.iter().enumerate() {
+                for i in 0..k {
+                    sources[[i, j]] = compressed_multipoles[[i, source_idx]];
+                }
+            }
+            operator.c[t].dot(&sources).eval()
+        })
+        .collect();
+
+    let mut compressed_locals = rlst_mat![f64, (k, nkeys)];
+    for (result, pairs) in results.iter().zip(pairs.iter()) {
+        for (j, &(target_idx, _)) in pairs.iter().enumerate() {
+            for i in 0..k {
+                compressed_locals[[i, target_idx]] += result[[i, j]];
+            }
+        }
+    }
+
+    let locals = operator.u.dot(&compressed_locals).eval();
+
+    Ok(locals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        helpers::{full_octree_keys, unit_domain},
+        kernel::Laplace,
+    };
+
+    const EXPANSION_ORDER: usize = 3;
+    const NCOEFFS: usize = 6 * (EXPANSION_ORDER - 1).pow(2) + 2;
+
+    fn assert_close(expected: &[f64], found: &[f64]) {
+        let scale = expected.iter().fold(0f64, |a, e| a.max(e.abs()));
+        for (e, f) in expected.iter().zip(found.iter()) {
+            assert!(
+                (e - f).abs() <= 1e-10 * scale,
+                "expected {}, found {}",
+                e,
+                f
+            );
+        }
+    }
+
+    fn entries(mat: &Mat) -> Vec<f64> {
+        let (m, n) = mat.shape();
+        (0..m)
+            .flat_map(|i| (0..n).map(move |j| (i, j)))
+            .map(|(i, j)| mat[[i, j]])
+            .collect()
+    }
+
+    #[test]
+    fn full_rank_reconstructs_operators() {
+        let box_width = 0.25;
+        let operator = svd_m2l_operator(&Laplace, EXPANSION_ORDER, box_width, NCOEFFS).unwrap();
+        assert_eq!(operator.k, NCOEFFS);
+
+        for (t, c) in operator.transfer_vectors.iter().zip(operator.c.iter()) {
+            let expected = m2l_operator(&Laplace, EXPANSION_ORDER, t, box_width);
+            let found = operator.u.dot(c).eval().dot(&operator.vt).eval();
+            assert_close(&entries(&expected), &entries(&found));
+        }
+    }
+
+    #[test]
+    fn m2l_svd_matches_dense() {
+        let depth = 2;
+        let leaves = full_octree_keys(depth);
+        let domain = unit_domain();
+        let box_width = domain.diameter[0] / 2f64.powi(depth as i32);
+
+        let operator = svd_m2l_operator(&Laplace, EXPANSION_ORDER, box_width, NCOEFFS).unwrap();
+        let locals = m2l_svd(&operator, EXPANSION_ORDER, &leaves, &domain).unwrap();
+
+        let mut keys: Vec<MortonKey> = leaves.iter().cloned().collect();
+        keys.sort();
+
+        // Apply the dense operator of each interaction to the multipoles, which are all ones
+        for (j, target) in keys.iter().enumerate() {
+            let mut expected = vec![0f64; NCOEFFS];
+
+            let interaction_list = target
+                .parent()
+                .neighbors()
+                .iter()
+                .flat_map(|pn| pn.children())
+                .filter(|pnc| !target.is_adjacent_same_level(pnc) && leaves.contains(pnc))
+                .collect::<Vec<_>>();
+
+            for source in interaction_list.iter() {
+                let t = find_transfer_vector(target, source, &domain);
+                let op = m2l_operator(&Laplace, EXPANSION_ORDER, &t, box_width);
+                for (i, e) in expected.iter_mut().enumerate() {
+                    *e += (0..NCOEFFS).map(|l| op[[i, l]]).sum::<f64>();
+                }
+            }
+
+            let found = (0..NCOEFFS).map(|i| locals[[i, j]]).collect::<Vec<_>>();
+            assert_close(&expected, &found);
+        }
+    }
+
+    #[test]
+    fn invalid_inputs_are_rejected() {
+        assert_eq!(
+            svd_m2l_operator(&Laplace, 0, 1., 10).err(),
+            Some(SvdM2lError::ExpansionOrder(0))
+        );
+        assert_eq!(
+            svd_m2l_operator(&Laplace, 1, 1., 10).err(),
+            Some(SvdM2lError::ExpansionOrder(1))
+        );
+
+        let leaves = full_octree_keys(2);
+        let operator = svd_m2l_operator(&Laplace, EXPANSION_ORDER, 0.25, 10).unwrap();
+
+        assert_eq!(
+            m2l_svd(&operator, EXPANSION_ORDER + 1, &leaves, &unit_domain()).err(),
+            Some(SvdM2lError::ExpansionOrder(EXPANSION_ORDER + 1))
+        );
+
+        // Box widths along y are twice those along x, so some transfer vectors are out of range
+        let stretched = Domain {
+            origin: [0., 0., 0.],
+            diameter: [1., 2., 1.],
+        };
+        assert!(matches!(
+            m2l_svd(&operator, EXPANSION_ORDER, &leaves, &stretched),
+            Err(SvdM2lError::TransferVector(_))
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+//! Per-phase timing of the parent level M2L drivers, accumulated across rayon workers.
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    HaloGather,
+    SiblingGather,
+    Hadamard,
+    Scatter,
+    Prefetch,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [
+        Phase::HaloGather,
+        Phase::SiblingGather,
+        Phase::Hadamard,
+        Phase::Scatter,
+        Phase::Prefetch,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::HaloGather => "halo gather",
+            Phase::SiblingGather => "sibling gather",
+            Phase::Hadamard => "hadamard",
+            Phase::Scatter => "scatter",
+            Phase::Prefetch => "prefetch",
+        }
+    }
+}
+
+// Time spent in each phase summed over all workers, so the phases add up to the CPU time rather
+// than the wall time of a parallel driver.
+#[derive(Debug, Default)]
+pub struct PhaseTimes {
+    nanos: [AtomicU64; 5],
+    total: AtomicU64,
+}
+
+impl PhaseTimes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, phase: Phase, elapsed: Duration) {
+        self.nanos[phase as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // Run `f`, attributing its runtime to `phase`.
+    pub fn time<T, F: FnOnce() -> T>(&self, phase: Phase, f: F) -> T {
+        let s = Instant::now();
+        let result = f();
+        self.add(phase, s.elapsed());
+        result
+    }
+
+    pub fn get(&self, phase: Phase) -> Duration {
+        Duration::from_nanos(self.nanos[phase as usize].load(Ordering::Relaxed))
+    }
+
+    // Wall time of the whole driver
+    pub fn set_total(&self, total: Duration) {
+        self.total.store(total.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.total.load(Ordering::Relaxed))
+    }
+
+    // Add the phase and wall times of another run, e.g. of a driver with each part of the
+    // spectrum of a complex kernel.
+    pub fn accumulate(&self, other: &PhaseTimes) {
+        for p in Phase::ALL.iter() {
+            self.add(*p, other.get(*p));
+        }
+        self.total
+            .fetch_add(other.total().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // (name, seconds) for each phase
+    pub fn breakdown(&self) -> Vec<(String, f64)> {
+        Phase::ALL
+            .iter()
+            .map(|p| (p.name().to_string(), self.get(*p).as_secs_f64()))
+            .collect()
+    }
+}
+
+impl fmt::Display for PhaseTimes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpu: f64 = Phase::ALL.iter().map(|p| self.get(*p).as_secs_f64()).sum();
+
+        writeln!(f, "total (wall) {:?}", self.total())?;
+        for p in Phase::ALL.iter() {
+            let t = self.get(*p).as_secs_f64();
+            let fraction = if cpu > 0. { 100. * t / cpu } else { 0. };
+            writeln!(f, "  {:<15} {:>10.6}s cpu {:>5.1}%", p.name(), t, fraction)?;
+        }
+
+        Ok(())
+    }
+}
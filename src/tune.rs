@@ -0,0 +1,212 @@
+//! Auto-tuning of the number of keys per block in the blocked M2L drivers. Candidates are derived
+//! from the host's cache sizes and the footprint of the expansions, timed on the problem at hand,
+//! and the fastest is saved to a config file reused by later runs on the same machine.
+use std::{fs, io, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{results::summarise, stream::cache_sizes};
+
+pub const DEFAULT_TUNING_FILE: &str = "m2l-tuning.json";
+
+// Block size chosen for one driver and problem configuration.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TunedBlock {
+    // Driver and backend, e.g. "m2l-parent avx2"
+    pub driver: String,
+    pub expansion_order: usize,
+    pub threads: usize,
+    pub block_size: usize,
+    // Median time of a run of the driver with this block size
+    pub seconds: f64,
+    // Fastest and slowest of the runs, zero in files saved before they were recorded
+    #[serde(default)]
+    pub min_seconds: f64,
+    #[serde(default)]
+    pub max_seconds: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tuning {
+    // (level, bytes) of the data caches the blocks were tuned for
+    pub caches: Vec<(usize, usize)>,
+    pub blocks: Vec<TunedBlock>,
+}
+
+impl Tuning {
+    // Tuning saved at `path`. Blocks tuned on a machine with different caches, or an unreadable
+    // file, are discarded.
+    pub fn load(path: &Path) -> Self {
+        let caches = cache_sizes();
+
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<Tuning>(&s).ok())
+            .filter(|t| t.caches == caches)
+            .unwrap_or(Tuning {
+                caches,
+                blocks: Vec::new(),
+            })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap())
+    }
+
+    pub fn block_size(
+        &self,
+        driver: &str,
+        expansion_order: usize,
+        threads: usize,
+    ) -> Option<usize> {
+        self.blocks
+            .iter()
+            .find(|b| {
+                b.driver == driver && b.expansion_order == expansion_order && b.threads == threads
+            })
+            .map(|b| b.block_size)
+    }
+
+    // Add a block, replacing any tuned for the same configuration.
+    pub fn insert(&mut self, block: TunedBlock) {
+        self.blocks.retain(|b| {
+            !(b.driver == block.driver
+                && b.expansion_order == block.expansion_order
+                && b.threads == block.threads)
+        });
+        self.blocks.push(block);
+    }
+}
+
+// Candidate keys per block. For each data cache level, the largest block whose signals fit in the
+// cache alongside the 16 kernels, and half and twice that. The private levels are summed over the
+// `threads` workers sharing a block, while the last level is shared. Blocks are whole sibling sets,
+// and no larger than the `nkeys` keys of the problem. Falls back to the fixed block sizes of the
+// drivers if the cache sizes are unknown.
+pub fn candidate_block_sizes(
+    expansion_order: usize,
+    caches: &[(usize, usize)],
+    threads: usize,
+    nkeys: usize,
+) -> Vec<usize> {
+    let n = 2 * expansion_order - 1;
+    let &(m, n, o) = &(n, n, n);
+
+    let p = m + 1;
+    let q = n + 1;
+    let r = o + 1;
+    let size_real = p * q * (r / 2 + 1);
+
+    let signal_bytes = 16 * size_real;
+    let kernel_bytes = 16 * signal_bytes;
+    let max_block = nkeys.div_ceil(8).max(1) * 8;
+
+    let mut candidates: Vec<usize> = Vec::new();
+
+    for (i, &(_, size)) in caches.iter().enumerate() {
+        let capacity = if i + 1 < caches.len() {
+            size * threads
+        } else {
+            size
+        };
+
+        // Too small to hold even the kernels
+        if capacity <= kernel_bytes {
+            continue;
+        }
+
+        let nkeys = (capacity - kernel_bytes) / signal_bytes;
+        candidates.extend([nkeys / 2, nkeys, 2 * nkeys]);
+    }
+
+    if candidates.is_empty() {
+        candidates.extend([512, 4096]);
+    }
+
+    let mut candidates = candidates
+        .into_iter()
+        .map(|b| ((b / 8) * 8).clamp(8, max_block))
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+// Run the driver `repetitions` times with each candidate block size, after a warm up run,
+// returning the times of each and the block size with the fastest median time. `run` runs the
+// driver with a block size and returns how long it took, so the timing source can be replaced.
+// The median is less sensitive than a single run to a noisy repetition.
+pub fn tune<F: FnMut(usize) -> Duration>(
+    candidates: &[usize],
+    repetitions: usize,
+    mut run: F,
+) -> (Vec<(usize, Vec<Duration>)>, usize) {
+    assert!(!candidates.is_empty(), "no candidate block sizes");
+
+    run(candidates[0]);
+
+    let times = candidates
+        .iter()
+        .map(|&block_size| {
+            let times = (0..repetitions.max(1))
+                .map(|_| run(block_size))
+                .collect::<Vec<_>>();
+            (block_size, times)
+        })
+        .collect::<Vec<_>>();
+
+    let median = |times: &[Duration]| summarise(times).1;
+    let best = times
+        .iter()
+        .min_by(|(_, a), (_, b)| median(a).partial_cmp(&median(b)).unwrap())
+        .unwrap()
+        .0;
+
+    (times, best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tune_picks_the_fastest_median() {
+        let mut runs = Vec::new();
+
+        // The first timed run of the fastest candidate is an outlier, which would rule it out if
+        // each candidate were only run once
+        let (times, best) = tune(&[8, 16, 24], 3, |block_size| {
+            let previous = runs.iter().filter(|&&b| b == block_size).count();
+            runs.push(block_size);
+
+            let millis = if block_size == 8 && previous == 1 {
+                60
+            } else {
+                10 * block_size as u64 / 8
+            };
+            Duration::from_millis(millis)
+        });
+
+        // A warm up run, then the repetitions of each candidate in turn
+        assert_eq!(runs, [8, 8, 8, 8, 16, 16, 16, 24, 24, 24]);
+        let ms = Duration::from_millis;
+        assert_eq!(
+            times,
+            [
+                (8, vec![ms(60), ms(10), ms(10)]),
+                (16, vec![ms(20); 3]),
+                (24, vec![ms(30); 3]),
+            ]
+        );
+        assert_eq!(best, 8);
+    }
+
+    #[test]
+    fn blocks_saved_without_a_spread_still_load() {
+        let json = r#"{"driver": "m2l-parent avx2", "expansion_order": 5, "threads": 8,
+            "block_size": 512, "seconds": 0.5}"#;
+        let block: TunedBlock = serde_json::from_str(json).unwrap();
+        assert_eq!(block.block_size, 512);
+        assert_eq!((block.min_seconds, block.max_seconds), (0., 0.));
+    }
+}